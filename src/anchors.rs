@@ -0,0 +1,347 @@
+//!
+//! Anchors: positions in a `Rope` that follow its contents through edits.
+//!
+//! An anchor sits in a gap between two values (or at either end of the
+//! rope), and is stored in the leaf containing that gap, so like markers and
+//! spans it moves with its surroundings whenever the rope is sliced,
+//! concatenated or spliced. Each `Concat` node keeps the sorted ids of the
+//! anchors below it, so finding an anchor's current index (or removing it)
+//! only follows the one path down to its leaf, checking which side it's on
+//! at each level with a binary search. The price is paid when nodes are
+//! joined: a node over k anchors takes O(k) to build, so edits to ropes
+//! with many anchors cost more, though nothing at all without any.
+//!
+//! An anchor's `Bias` decides which side of an edit it ends up on when the
+//! edit happens exactly at the anchor:
+//!
+//! * Inserting at a `Left` anchor leaves it before the new values, and at a
+//!   `Right` anchor moves it after them.
+//! * Slicing exactly at an anchor keeps it only if its bias points into the
+//!   slice, e.g. `slice(2, 5)` keeps a `Right` anchor at 2 but not a `Left`
+//!   one.
+//! * Removing a range containing an anchor moves the anchor to the start of
+//!   the removed range (or, for `Right` anchors, the end of whatever replaced
+//!   it) rather than dropping it.
+//!
+
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Node, Shared, Rope};
+use super::Node::*;
+
+static NEXT_ANCHOR_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Identifies an anchor added by `Rope::add_anchor`, in that rope and every
+/// rope derived from it.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct AnchorId(usize);
+
+/// The ids of the anchors under a `Concat` node, sorted and without
+/// repeats (a rope concatenated with itself has each anchor twice).
+/// Shared, so a node over only one side's anchors can reuse that side's.
+#[derive(Clone, Default)]
+pub struct AnchorSet(Option<Shared<Vec<AnchorId>>>);
+
+impl AnchorSet {
+
+    fn ids(&self) -> &[AnchorId] {
+        match self.0 {
+            Some(ref ids) => ids,
+            None => &[],
+        }
+    }
+
+    fn contains(&self, id: AnchorId) -> bool {
+        self.ids().binary_search(&id).is_ok()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+/// Which value an anchor sticks to: the one before it, or the one after.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Bias {
+    Left,
+    Right,
+}
+
+/// An anchor as stored in a `Flat` node, at a gap relative to the node.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AnchorEntry {
+    pub at: usize,
+    pub id: AnchorId,
+    pub bias: Bias,
+}
+
+/// Which ends of a slice are cuts through the rope, rather than ends of the
+/// rope itself. Anchors exactly at a cut only stay in the slice if their
+/// bias points into it.
+#[derive(Clone, Copy)]
+pub struct Cuts {
+    pub start: bool,
+    pub end: bool,
+}
+
+impl Cuts {
+
+    /// Whether an anchor survives slicing `start..end`. An empty slice only
+    /// keeps anchors that both of its ends would.
+    pub fn keeps(&self, entry: &AnchorEntry, start: usize, end: usize) -> bool {
+        let kept_by_start = entry.at != start || !self.start || entry.bias == Bias::Right;
+        let kept_by_end = entry.at != end || !self.end || entry.bias == Bias::Left;
+
+        entry.at >= start && entry.at <= end && kept_by_start && kept_by_end
+    }
+}
+
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> Node<T, M, V> {
+
+    pub fn has_anchors(&self) -> bool {
+        match *self {
+            Concat { ref anchors, .. } => !anchors.is_empty(),
+            Flat { ref anchors, .. } => !anchors.is_empty(),
+        }
+    }
+
+    /// Whether the anchor `id` is somewhere under this node, without
+    /// looking below it.
+    fn has_anchor(&self, id: AnchorId) -> bool {
+        match *self {
+            Concat { ref anchors, .. } => anchors.contains(id),
+            Flat { ref anchors, .. } => anchors.iter().any(|entry| entry.id == id),
+        }
+    }
+
+    fn push_anchor_ids(&self, ids: &mut Vec<AnchorId>) {
+        match *self {
+            Concat { ref anchors, .. } => ids.extend_from_slice(anchors.ids()),
+            Flat { ref anchors, .. } => ids.extend(anchors.iter().map(|entry| entry.id)),
+        }
+    }
+
+    /// The set of the anchors under both `left` and `right`.
+    pub fn anchor_union(left: &Self, right: &Self) -> AnchorSet {
+        match (left, right) {
+            (&Concat { ref anchors, .. }, other) | (other, &Concat { ref anchors, .. })
+                if !other.has_anchors() => return anchors.clone(),
+            _ if !left.has_anchors() && !right.has_anchors() => return AnchorSet::default(),
+            _ => {},
+        }
+
+        let mut ids = Vec::new();
+        left.push_anchor_ids(&mut ids);
+        right.push_anchor_ids(&mut ids);
+
+        // a stable sort finds the sorted runs from each side and merges them
+        ids.sort();
+        ids.dedup();
+        AnchorSet(Some(Shared::new(ids)))
+    }
+
+    /// The anchors in the gap at the very start (or, if `at_end`, the very
+    /// end) of this node, which might be spread over several empty nodes.
+    pub fn anchors_at_edge(&self, at_end: bool) -> Vec<AnchorEntry> {
+        if !self.has_anchors() {
+            return Vec::new();
+        }
+
+        match *self {
+            Flat { ref anchors, ref data, .. } => {
+                let at = if at_end { data.len() } else { 0 };
+                anchors.iter().filter(|entry| entry.at == at).cloned().collect()
+            },
+
+            Concat { ref left, ref right, .. } => {
+                let (near, far) = if at_end { (right, left) } else { (left, right) };
+                let mut found = near.anchors_at_edge(at_end);
+
+                if near.len() == 0 {
+                    found.extend(far.anchors_at_edge(at_end));
+                }
+
+                found
+            },
+        }
+    }
+
+    /// Add anchors relative to this node. Anchors in the gap between the
+    /// two sides of a `Concat` go to the side they're biased towards.
+    pub fn add_anchors(node: &Shared<Self>, entries: Vec<AnchorEntry>) -> Shared<Self> {
+        if entries.is_empty() {
+            return node.clone();
+        }
+
+        match **node {
+            Flat { ref data, ref markers, ref spans, ref anchors } => {
+                let mut all = (**anchors).clone();
+                all.extend(entries);
+
+                Shared::new(Flat {
+                    data: data.clone(),
+                    markers: markers.clone(),
+                    spans: spans.clone(),
+                    anchors: Shared::new(all),
+                })
+            },
+
+            Concat { left_len, ref left, ref right, ref spans, .. } => {
+                let (to_left, to_right): (Vec<AnchorEntry>, Vec<AnchorEntry>) =
+                    entries.into_iter().partition(|entry| {
+                        entry.at < left_len || (entry.at == left_len && entry.bias == Bias::Left)
+                    });
+
+                let to_right = to_right.into_iter()
+                                       .map(|entry| AnchorEntry { at: entry.at - left_len, ..entry })
+                                       .collect();
+
+                Self::concat_spanning(&Self::add_anchors(left, to_left),
+                                      &Self::add_anchors(right, to_right),
+                                      spans.clone())
+            },
+        }
+    }
+
+    fn remove_anchor(node: &Shared<Self>, id: AnchorId) -> Shared<Self> {
+        match **node {
+            Flat { ref data, ref markers, ref spans, ref anchors } => {
+                Shared::new(Flat {
+                    data: data.clone(),
+                    markers: markers.clone(),
+                    spans: spans.clone(),
+                    anchors: Shared::new(anchors.iter().filter(|entry| entry.id != id).cloned().collect()),
+                })
+            },
+
+            Concat { ref left, ref right, ref spans, .. } => {
+                if left.has_anchor(id) {
+                    Self::concat_spanning(&Self::remove_anchor(left, id), right, spans.clone())
+                } else {
+                    Self::concat_spanning(left, &Self::remove_anchor(right, id), spans.clone())
+                }
+            },
+        }
+    }
+
+    fn resolve(&self, id: AnchorId) -> Option<usize> {
+        match *self {
+            Flat { ref anchors, .. } => {
+                anchors.iter().find(|entry| entry.id == id).map(|entry| entry.at)
+            },
+
+            Concat { left_len, ref left, ref right, .. } => {
+                if left.has_anchor(id) {
+                    left.resolve(id)
+                } else if right.has_anchor(id) {
+                    right.resolve(id).map(|at| left_len + at)
+                } else {
+                    None
+                }
+            },
+        }
+    }
+
+    /// Push every anchor in the gaps `start..=end` onto `found`, offset by
+    /// `offset`.
+    pub fn anchors_in(&self,
+                      start: usize,
+                      end: usize,
+                      offset: usize,
+                      found: &mut Vec<AnchorEntry>) {
+
+        if !self.has_anchors() {
+            return;
+        }
+
+        match *self {
+            Flat { ref anchors, .. } => {
+                found.extend(anchors.iter()
+                                    .filter(|entry| entry.at >= start && entry.at <= end)
+                                    .map(|entry| AnchorEntry { at: offset + entry.at, ..*entry }));
+            },
+
+            Concat { left_len, ref left, ref right, .. } => {
+                if start <= left_len {
+                    left.anchors_in(start, end.min(left_len), offset, found);
+                }
+
+                if end >= left_len {
+                    right.anchors_in(start.saturating_sub(left_len),
+                                     end - left_len,
+                                     offset + left_len,
+                                     found);
+                }
+            },
+        }
+    }
+}
+
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> Rope<T, M, V> {
+
+    /// Add an anchor in the gap before index `at` (so `at` may equal the
+    /// length of the rope), returning the new rope and the anchor's id.
+    pub fn add_anchor(&self, at: usize, bias: Bias) -> (Self, AnchorId) {
+        if at > self.len() {
+            panic!("anchor index exceeds bounds (length {:?}, index {:?})", self.len(), at);
+        }
+
+        let id = AnchorId(NEXT_ANCHOR_ID.fetch_add(1, Ordering::Relaxed));
+        let entry = AnchorEntry { at, id, bias };
+
+        (self.with_root(Node::add_anchors(&self.root, vec![entry])), id)
+    }
+
+    /// A copy of this rope without the anchor `id`, if it has one.
+    pub fn remove_anchor(&self, id: AnchorId) -> Self {
+        if self.root.has_anchor(id) {
+            self.with_root(Node::remove_anchor(&self.root, id))
+        } else {
+            self.clone()
+        }
+    }
+
+    /// The current index of the anchor `id`, or `None` if it isn't in this
+    /// rope, e.g. because it was sliced off.
+    pub fn resolve(&self, id: AnchorId) -> Option<usize> {
+        self.root.resolve(id)
+    }
+
+    /// Used by `splice`: put back the anchors of this rope that were in the
+    /// replaced range `start..end` but didn't survive into `spliced`.
+    pub(crate) fn splice_anchors(&self,
+                                 start: usize,
+                                 end: usize,
+                                 replacement_len: usize,
+                                 spliced: Self) -> Self {
+
+        if start == end || !self.root.has_anchors() {
+            return spliced;
+        }
+
+        let mut replaced = Vec::new();
+        self.root.anchors_in(start, end, 0, &mut replaced);
+
+        let kept_before = Cuts { start: false, end: true };
+        let kept_after = Cuts { start: true, end: false };
+
+        let moved: Vec<AnchorEntry> =
+            replaced.into_iter()
+                    .filter(|entry| {
+                        !kept_before.keeps(entry, 0, start) &&
+                            !kept_after.keeps(entry, end, self.len())
+                    })
+                    .map(|entry| {
+                        let at = match entry.bias {
+                            Bias::Left => start,
+                            Bias::Right => start + replacement_len,
+                        };
+
+                        AnchorEntry { at, ..entry }
+                    })
+                    .collect();
+
+        spliced.with_root(Node::add_anchors(&spliced.root, moved))
+    }
+}
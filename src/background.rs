@@ -0,0 +1,190 @@
+//!
+//! Loading a file on a worker thread, with what's been loaded so far
+//! available as it arrives, e.g. so an editor can show the start of a large
+//! file straight away. Only available with the `sync` feature.
+//!
+//! The worker decodes the file as `Rope::load_file` does and, after each
+//! chunk, publishes a rope of everything loaded so far. Each one is a
+//! complete rope in its own right, so readers see a consistent prefix of
+//! the file that only ever grows. The loaded leaves are kept in a stack of
+//! perfectly balanced subtrees (merging two of the same size as another
+//! arrives, like carrying in binary addition), so each snapshot is built in
+//! O(log n) and has O(log n) depth. When the whole file is in, the final
+//! rope is balanced as by `Rope::from_chunks`.
+//!
+
+use std::any::Any;
+use std::fs::File;
+use std::hash::Hash;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+use super::Rope;
+use super::load::{ChunkReader, LoadError, LoadOptions};
+
+type LoadResult<M, V> = Result<Rope<char, M, V>, LoadError<M, V>>;
+
+struct State<M, V> {
+    loaded: Rope<char, M, V>,
+    result: Option<LoadResult<M, V>>,
+}
+
+struct Handoff<M, V> {
+    state: Mutex<State<M, V>>,
+    changed: Condvar,
+}
+
+impl<M, V> Handoff<M, V> {
+
+    fn lock(&self) -> MutexGuard<'_, State<M, V>> {
+        // the worker never panics while holding the lock
+        self.state.lock().unwrap()
+    }
+}
+
+/// A file being loaded on a worker thread.
+pub struct BackgroundLoad<M = (), V = ()> {
+    shared: Arc<Handoff<M, V>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl<M, V> BackgroundLoad<M, V>
+    where M: Eq + Hash + Copy + Send + Sync + 'static, V: Clone + Send + Sync + 'static {
+
+    /// Everything loaded so far, or the whole file once it's loaded.
+    pub fn snapshot(&self) -> Rope<char, M, V> {
+        self.shared.lock().loaded.clone()
+    }
+
+    /// Whether the worker has finished, successfully or not.
+    pub fn is_done(&self) -> bool {
+        self.shared.lock().result.is_some()
+    }
+
+    /// Wait until more than `len` chars have been loaded or the load is
+    /// done, and return what's been loaded.
+    pub fn wait_for_more(&self, len: usize) -> Rope<char, M, V> {
+        let mut state = self.shared.lock();
+
+        while state.loaded.len() <= len && state.result.is_none() {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+
+        state.loaded.clone()
+    }
+
+    /// Wait for the load to finish, and return the whole rope. If the
+    /// classifier or progress callback panicked, that's a
+    /// `LoadError::Panicked`.
+    pub fn wait(mut self) -> LoadResult<M, V> {
+        if let Some(worker) = self.worker.take() {
+            worker.join().expect("background load panicked");
+        }
+
+        self.shared.lock().result.take().unwrap()
+    }
+}
+
+/// Leaves in a stack of perfectly balanced subtrees, each with its number
+/// of leaves, getting smaller towards the top.
+struct Forest<M, V> {
+    trees: Vec<(Rope<char, M, V>, usize)>,
+}
+
+impl<M: Eq + Hash + Copy, V: Clone> Forest<M, V> {
+
+    fn push(&mut self, leaf: Rope<char, M, V>) {
+        let mut tree = (leaf, 1);
+
+        while self.trees.last().is_some_and(|last| last.1 == tree.1) {
+            let (left, count) = self.trees.pop().unwrap();
+            tree = (Rope::concat(&left, &tree.0), count * 2);
+        }
+
+        self.trees.push(tree);
+    }
+
+    fn rope(&self) -> Rope<char, M, V> {
+        let mut trees = self.trees.iter().rev();
+        let last = trees.next().map(|tree| tree.0.clone()).unwrap_or_else(|| Rope::new(&[]));
+
+        trees.fold(last, |right, tree| Rope::concat(&tree.0, &right))
+    }
+}
+
+/// Load `file` on the worker thread, publishing each prefix to `shared`.
+fn load<M, V>(shared: &Handoff<M, V>, file: File, total: u64, options: LoadOptions<M, V>) -> LoadResult<M, V>
+    where M: Eq + Hash + Copy, V: Clone {
+
+    let mut chunks = ChunkReader::new(file, Some(total), options);
+    let mut leaves = Vec::new();
+    let mut forest = Forest { trees: Vec::new() };
+
+    loop {
+        match chunks.next_chunk() {
+            Ok(Some(chunk)) => {
+                let leaf = Rope::from_chunk(chunk);
+                forest.push(leaf.clone());
+                leaves.push(leaf);
+
+                let loaded = forest.rope();
+                shared.lock().loaded = loaded;
+                shared.changed.notify_all();
+            },
+
+            Ok(None) if chunks.cancelled() => return Err(LoadError::Cancelled(forest.rope())),
+            Ok(None) => return Ok(Rope::from_leaves(leaves)),
+            Err(error) => return Err(LoadError::Io(error)),
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => panic.downcast_ref::<String>().cloned().unwrap_or_else(|| "unknown panic".to_string()),
+    }
+}
+
+impl<M, V> Rope<char, M, V>
+    where M: Eq + Hash + Copy + Send + Sync + 'static, V: Clone + Send + Sync + 'static {
+
+    /// Start loading the file at `path` on a worker thread. The file is
+    /// opened straight away, so failing to open it is reported here.
+    pub fn load_file_in_background<P>(path: P, options: LoadOptions<'static, M, V>) -> io::Result<BackgroundLoad<M, V>>
+        where P: AsRef<Path> {
+
+        let file = File::open(path)?;
+        let total = file.metadata()?.len();
+
+        let shared = Arc::new(Handoff {
+            state: Mutex::new(State { loaded: Rope::new(&[]), result: None }),
+            changed: Condvar::new(),
+        });
+
+        let worker_shared = shared.clone();
+
+        let worker = thread::spawn(move || {
+            let shared = worker_shared;
+
+            // the classifier and progress callback are the caller's code, and
+            // if they panic, waiters still need to hear that the load is over
+            let result = panic::catch_unwind(AssertUnwindSafe(|| load(&shared, file, total, options)))
+                .unwrap_or_else(|panic| Err(LoadError::Panicked(panic_message(&*panic))));
+
+            let mut state = shared.lock();
+
+            if let Ok(ref rope) = result {
+                state.loaded = rope.clone();
+            }
+
+            state.result = Some(result);
+            shared.changed.notify_all();
+        });
+
+        Ok(BackgroundLoad { shared, worker: Some(worker) })
+    }
+}
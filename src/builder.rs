@@ -0,0 +1,144 @@
+//!
+//! Building a rope piece by piece, e.g. from formatted output, without
+//! collecting it all into one buffer first.
+//!
+
+use std::fmt;
+use std::hash::Hash;
+use std::io;
+use std::mem;
+
+use super::{Chunk, Classifier, Rope, DEFAULT_LEAF_SIZE};
+
+/// Collects values into chunks of a fixed capacity, which become the leaves
+/// of a balanced rope as with `Rope::from_chunks`.
+///
+/// A builder of bytes implements `io::Write` and `fmt::Write` (taking
+/// strings as UTF-8), and a builder of chars implements `fmt::Write`, so
+/// either can be filled with `write!`. With both traits in scope, a builder
+/// of bytes needs `io::Write::write_fmt` or `fmt::Write::write_fmt` spelt
+/// out instead.
+pub struct RopeBuilder<T, M = (), V = ()> {
+    capacity: usize,
+    classifier: Option<Classifier<T, M, V>>,
+
+    chunk: Chunk<T, M, V>,
+    leaves: Vec<Rope<T, M, V>>,
+    len: usize,
+}
+
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> RopeBuilder<T, M, V> {
+
+    /// A builder making leaves of `DEFAULT_LEAF_SIZE` values.
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_LEAF_SIZE)
+    }
+
+    /// A builder making leaves of `capacity` values (except perhaps the
+    /// last).
+    pub fn with_capacity(capacity: usize) -> Self {
+        if capacity == 0 {
+            panic!("leaf capacity must be greater than zero");
+        }
+
+        RopeBuilder {
+            capacity,
+            classifier: None,
+            chunk: Chunk::with_capacity(capacity),
+            leaves: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Like `with_capacity`, with values marked by `classifier` as they're
+    /// added. The finished rope keeps using it, as with
+    /// `Chunk::with_classifier`.
+    pub fn with_classifier(capacity: usize, classifier: &Classifier<T, M, V>) -> Self {
+        let mut builder = Self::with_capacity(capacity);
+        builder.classifier = Some(classifier.clone());
+        builder.chunk = builder.new_chunk();
+        builder
+    }
+
+    fn new_chunk(&self) -> Chunk<T, M, V> {
+        match self.classifier {
+            Some(ref classifier) => Chunk::with_classifier(self.capacity, classifier),
+            None => Chunk::with_capacity(self.capacity),
+        }
+    }
+
+    /// The number of values added so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, value: T) {
+        self.extend_from_slice(&[value]);
+    }
+
+    pub fn extend_from_slice(&mut self, mut values: &[T]) {
+        self.len += values.len();
+
+        while !values.is_empty() {
+            let room = self.capacity - self.chunk.data.len();
+            let (now, later) = values.split_at(room.min(values.len()));
+
+            self.chunk.extend_from_slice(now);
+            values = later;
+
+            if self.chunk.data.len() == self.capacity {
+                let next = self.new_chunk();
+                let full = mem::replace(&mut self.chunk, next);
+                self.leaves.push(Rope::from_chunk(full));
+            }
+        }
+    }
+
+    /// The balanced rope of everything added.
+    pub fn finish(mut self) -> Rope<T, M, V> {
+        let last = mem::replace(&mut self.chunk, Chunk::with_capacity(0));
+        self.leaves.push(Rope::from_chunk(last));
+
+        Rope::from_leaves(self.leaves)
+    }
+}
+
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> Default for RopeBuilder<T, M, V> {
+
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Eq + Hash + Copy, V: Clone> io::Write for RopeBuilder<u8, M, V> {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<M: Eq + Hash + Copy, V: Clone> fmt::Write for RopeBuilder<u8, M, V> {
+
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl<M: Eq + Hash + Copy, V: Clone> fmt::Write for RopeBuilder<char, M, V> {
+
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let chars: Vec<char> = s.chars().collect();
+        self.extend_from_slice(&chars);
+        Ok(())
+    }
+}
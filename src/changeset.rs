@@ -0,0 +1,531 @@
+//!
+//! Descriptions of edits ("changesets") that can be applied to a `Rope`.
+//!
+//! A `ChangeSet` walks over a document from start to end, retaining,
+//! inserting or deleting values as it goes. Unlike a sequence of `splice`s,
+//! it can be inverted (to undo it), composed with the changeset that follows
+//! it, transformed against a concurrent changeset (as in operational
+//! transformation), and used to work out where a position in the old
+//! document ended up in the new one.
+//!
+//! Changesets are kept in a canonical form: adjacent operations of the same
+//! kind are merged, and an insertion next to a deletion always comes first,
+//! so two changesets that do the same thing compare equal.
+//!
+
+use std::cmp::min;
+use std::hash::Hash;
+
+use super::{Bias, Rope};
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Operation<T> {
+    /// Keep the next `n` values as they are.
+    Retain(usize),
+    Insert(Vec<T>),
+    /// Drop the next `n` values.
+    Delete(usize),
+}
+
+use self::Operation::*;
+
+/// A region changed by a `ChangeSet`: `old_start..old_end` of the document
+/// it applies to became `new_start..new_end` of the one it produces.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_end: usize,
+    pub new_start: usize,
+    pub new_end: usize,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ChangeSet<T> {
+    operations: Vec<Operation<T>>,
+    len_before: usize,
+    len_after: usize,
+}
+
+impl<T: Clone> ChangeSet<T> {
+
+    /// An empty changeset, to be built up with `retain`, `insert` and
+    /// `delete`.
+    pub fn new() -> Self {
+        ChangeSet {
+            operations: Vec::new(),
+            len_before: 0,
+            len_after: 0,
+        }
+    }
+
+    /// A changeset that leaves a document of length `len` unchanged.
+    pub fn identity(len: usize) -> Self {
+        let mut changes = Self::new();
+        changes.retain(len);
+        changes
+    }
+
+    /// The changeset equivalent to `Rope::splice`-ing `values` over
+    /// `start..end` of a document of length `len`.
+    pub fn splice(len: usize, start: usize, end: usize, values: &[T]) -> Self {
+        if start > end || end > len {
+            panic!("bad splice indices: {}, {}", start, end);
+        }
+
+        let mut changes = Self::new();
+        changes.retain(start);
+        changes.insert(values);
+        changes.delete(end - start);
+        changes.retain(len - end);
+        changes
+    }
+
+    pub fn operations(&self) -> &[Operation<T>] {
+        &self.operations
+    }
+
+    /// The length of the documents this changeset applies to.
+    pub fn len_before(&self) -> usize {
+        self.len_before
+    }
+
+    /// The length of the documents this changeset produces.
+    pub fn len_after(&self) -> usize {
+        self.len_after
+    }
+
+    /// If this changeset only touches one contiguous range, the `start`,
+    /// `end` and replacement values of the equivalent `splice`.
+    pub fn as_splice(&self) -> Option<(usize, usize, &[T])> {
+        let (start, ops) = match self.operations.split_first() {
+            Some((&Retain(n), rest)) => (n, rest),
+            _ => (0, &self.operations[..]),
+        };
+
+        let ops = match ops.split_last() {
+            Some((&Retain(_), rest)) => rest,
+            _ => ops,
+        };
+
+        match *ops {
+            [Insert(ref values)] => Some((start, start, values)),
+            [Delete(n)] => Some((start, start + n, &[])),
+            [Insert(ref values), Delete(n)] => Some((start, start + n, values)),
+            _ => None,
+        }
+    }
+
+    /// The regions this changeset changes, in order.
+    pub fn hunks(&self) -> Vec<Hunk> {
+        let mut hunks = Vec::new();
+        let mut old = 0;
+        let mut new = 0;
+
+        for op in &self.operations {
+            let (old_len, new_len) = match *op {
+                Retain(n) => {
+                    old += n;
+                    new += n;
+                    continue;
+                },
+                Insert(ref values) => (0, values.len()),
+                Delete(n) => (n, 0),
+            };
+
+            match hunks.last_mut() {
+                Some(&mut Hunk { ref mut old_end, ref mut new_end, .. })
+                    if *old_end == old && *new_end == new => {
+
+                    *old_end += old_len;
+                    *new_end += new_len;
+                },
+
+                _ => hunks.push(Hunk {
+                    old_start: old,
+                    old_end: old + old_len,
+                    new_start: new,
+                    new_end: new + new_len,
+                }),
+            }
+
+            old += old_len;
+            new += new_len;
+        }
+
+        hunks
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.operations.iter().all(|op| matches!(*op, Retain(_)))
+    }
+
+    pub fn retain(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+
+        self.len_before += n;
+        self.len_after += n;
+
+        if let Some(&mut Retain(ref mut last)) = self.operations.last_mut() {
+            *last += n;
+            return;
+        }
+
+        self.operations.push(Retain(n));
+    }
+
+    pub fn insert(&mut self, values: &[T]) {
+        self.insert_vec(values.to_vec());
+    }
+
+    pub fn insert_vec(&mut self, values: Vec<T>) {
+        if values.is_empty() {
+            return;
+        }
+
+        self.len_after += values.len();
+
+        // insertions go before any deletion at the same position
+        let at = match self.operations.last() {
+            Some(&Delete(_)) => self.operations.len() - 1,
+            _ => self.operations.len(),
+        };
+
+        if at > 0 {
+            if let Insert(ref mut existing) = self.operations[at - 1] {
+                existing.extend(values);
+                return;
+            }
+        }
+
+        self.operations.insert(at, Insert(values));
+    }
+
+    pub fn delete(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+
+        self.len_before += n;
+
+        if let Some(&mut Delete(ref mut last)) = self.operations.last_mut() {
+            *last += n;
+            return;
+        }
+
+        self.operations.push(Delete(n));
+    }
+
+    fn push(&mut self, op: Operation<T>) {
+        match op {
+            Retain(n) => self.retain(n),
+            Insert(values) => self.insert_vec(values),
+            Delete(n) => self.delete(n),
+        }
+    }
+
+    /// Apply this changeset to `rope`, whose length must be `len_before`.
+    /// Each run of insertions and deletions becomes one `splice`, so
+    /// markers, spans and anchors are carried over just as they are by
+    /// `Rope::replace`.
+    pub fn apply<M, V>(&self, rope: &Rope<T, M, V>) -> Rope<T, M, V>
+        where M: Eq + Hash + Copy, V: Clone {
+
+        if rope.len() != self.len_before {
+            panic!("changeset expects length {}, rope has length {}", self.len_before, rope.len());
+        }
+
+        let mut result = rope.clone();
+        let mut at = 0;
+
+        let mut inserted = Vec::new();
+        let mut deleted = 0;
+
+        for op in &self.operations {
+            match *op {
+                Retain(n) => {
+                    if !inserted.is_empty() || deleted > 0 {
+                        result = result.replace(at, at + deleted, &inserted);
+                        at += inserted.len();
+                        inserted.clear();
+                        deleted = 0;
+                    }
+
+                    at += n;
+                },
+                Insert(ref values) => inserted.extend_from_slice(values),
+                Delete(n) => deleted += n,
+            }
+        }
+
+        if !inserted.is_empty() || deleted > 0 {
+            result = result.replace(at, at + deleted, &inserted);
+        }
+
+        result
+    }
+
+    /// The changeset that undoes this one, given the `original` document it
+    /// applies to (which is where deleted values are put back from).
+    pub fn invert<M, V>(&self, original: &Rope<T, M, V>) -> Self
+        where M: Eq + Hash + Copy, V: Clone {
+
+        if original.len() != self.len_before {
+            panic!("changeset expects length {}, rope has length {}", self.len_before, original.len());
+        }
+
+        let mut inverted = Self::new();
+        let mut at = 0;
+
+        for op in &self.operations {
+            match *op {
+                Retain(n) => {
+                    inverted.retain(n);
+                    at += n;
+                },
+                Insert(ref values) => inverted.delete(values.len()),
+                Delete(n) => {
+                    inverted.insert_vec(original.slice(at, at + n).iter().cloned().collect());
+                    at += n;
+                },
+            }
+        }
+
+        inverted
+    }
+
+    /// A single changeset doing this one and then `next`, whose
+    /// `len_before` must be this one's `len_after`.
+    pub fn compose(&self, next: &Self) -> Self {
+        if self.len_after != next.len_before {
+            panic!("can't compose changesets: lengths {} and {} differ", self.len_after, next.len_before);
+        }
+
+        let mut composed = Self::new();
+
+        let mut firsts = self.operations.iter().cloned();
+        let mut seconds = next.operations.iter().cloned();
+        let mut first = firsts.next();
+        let mut second = seconds.next();
+
+        loop {
+            match (first, second) {
+                (None, None) => break,
+
+                // deletions from the first and insertions by the second
+                // don't interact with anything in the other changeset
+                (Some(Delete(n)), op) => {
+                    composed.delete(n);
+                    first = firsts.next();
+                    second = op;
+                },
+
+                (op, Some(Insert(values))) => {
+                    composed.insert_vec(values);
+                    first = op;
+                    second = seconds.next();
+                },
+
+                (Some(Retain(a)), Some(Retain(b))) => {
+                    let n = min(a, b);
+                    composed.retain(n);
+                    first = remaining_retain(a - n).or_else(|| firsts.next());
+                    second = remaining_retain(b - n).or_else(|| seconds.next());
+                },
+
+                (Some(Retain(a)), Some(Delete(b))) => {
+                    let n = min(a, b);
+                    composed.delete(n);
+                    first = remaining_retain(a - n).or_else(|| firsts.next());
+                    second = remaining_delete(b - n).or_else(|| seconds.next());
+                },
+
+                (Some(Insert(mut values)), Some(Retain(b))) => {
+                    let n = min(values.len(), b);
+                    let rest = values.split_off(n);
+                    composed.insert_vec(values);
+                    first = remaining_insert(rest).or_else(|| firsts.next());
+                    second = remaining_retain(b - n).or_else(|| seconds.next());
+                },
+
+                // values inserted by the first and deleted by the second
+                // cancel out
+                (Some(Insert(mut values)), Some(Delete(b))) => {
+                    let n = min(values.len(), b);
+                    let rest = values.split_off(n);
+                    first = remaining_insert(rest).or_else(|| firsts.next());
+                    second = remaining_delete(b - n).or_else(|| seconds.next());
+                },
+
+                (None, Some(_)) | (Some(_), None) => unreachable!(),
+            }
+        }
+
+        composed
+    }
+
+    /// Transform this changeset and `other`, which apply to the same
+    /// document, into `(this', other')` where `other'` applies after this
+    /// changeset and `this'` after `other`, such that both orders give the
+    /// same document. Where both insert at the same index, this changeset's
+    /// values go first.
+    pub fn transform(&self, other: &Self) -> (Self, Self) {
+        if self.len_before != other.len_before {
+            panic!("can't transform changesets: lengths {} and {} differ", self.len_before, other.len_before);
+        }
+
+        let mut this_prime = Self::new();
+        let mut other_prime = Self::new();
+
+        let mut these = self.operations.iter().cloned();
+        let mut others = other.operations.iter().cloned();
+        let mut this = these.next();
+        let mut that = others.next();
+
+        loop {
+            match (this, that) {
+                (None, None) => break,
+
+                (Some(Insert(values)), op) => {
+                    other_prime.retain(values.len());
+                    this_prime.insert_vec(values);
+                    this = these.next();
+                    that = op;
+                },
+
+                (op, Some(Insert(values))) => {
+                    this_prime.retain(values.len());
+                    other_prime.insert_vec(values);
+                    this = op;
+                    that = others.next();
+                },
+
+                (Some(Retain(a)), Some(Retain(b))) => {
+                    let n = min(a, b);
+                    this_prime.retain(n);
+                    other_prime.retain(n);
+                    this = remaining_retain(a - n).or_else(|| these.next());
+                    that = remaining_retain(b - n).or_else(|| others.next());
+                },
+
+                // values deleted by both are already gone either way
+                (Some(Delete(a)), Some(Delete(b))) => {
+                    let n = min(a, b);
+                    this = remaining_delete(a - n).or_else(|| these.next());
+                    that = remaining_delete(b - n).or_else(|| others.next());
+                },
+
+                (Some(Delete(a)), Some(Retain(b))) => {
+                    let n = min(a, b);
+                    this_prime.delete(n);
+                    this = remaining_delete(a - n).or_else(|| these.next());
+                    that = remaining_retain(b - n).or_else(|| others.next());
+                },
+
+                (Some(Retain(a)), Some(Delete(b))) => {
+                    let n = min(a, b);
+                    other_prime.delete(n);
+                    this = remaining_retain(a - n).or_else(|| these.next());
+                    that = remaining_delete(b - n).or_else(|| others.next());
+                },
+
+                (None, Some(_)) | (Some(_), None) => unreachable!(),
+            }
+        }
+
+        (this_prime, other_prime)
+    }
+
+    /// Where the gap before index `at` in the old document ends up in the
+    /// new one. `bias` decides which side of an insertion exactly at `at`
+    /// the position ends up on, and positions inside a replaced range move
+    /// to its start (`Left`) or to the end of its replacement (`Right`), as
+    /// anchors do.
+    pub fn map_position(&self, at: usize, bias: Bias) -> usize {
+        if at > self.len_before {
+            panic!("position exceeds bounds (length {:?}, index {:?})", self.len_before, at);
+        }
+
+        let mut old = 0;
+        let mut new = 0;
+
+        // the length of the insertions at `old`, which a position in a
+        // range deleted right after them might end up before
+        let mut inserted = 0;
+
+        for op in &self.operations {
+            match *op {
+                Retain(n) => {
+                    if at < old + n {
+                        return new + (at - old);
+                    }
+
+                    old += n;
+                    new += n;
+                    inserted = 0;
+                },
+
+                Insert(ref values) => {
+                    if at == old && bias == Bias::Left {
+                        return new;
+                    }
+
+                    new += values.len();
+                    inserted += values.len();
+                },
+
+                Delete(n) => {
+                    if at <= old + n {
+                        return match bias {
+                            Bias::Left => new - inserted,
+                            Bias::Right => new,
+                        };
+                    }
+
+                    old += n;
+                    inserted = 0;
+                },
+            }
+        }
+
+        new + (at - old)
+    }
+
+    /// Map the range `start..end` to the new document. Insertions exactly at
+    /// either end of the range don't grow it.
+    pub fn map_range(&self, start: usize, end: usize) -> (usize, usize) {
+        let new_start = self.map_position(start, Bias::Right);
+        let new_end = self.map_position(end, Bias::Left);
+
+        (new_start, new_end.max(new_start))
+    }
+}
+
+impl<T: Clone> Default for ChangeSet<T> {
+
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone> Extend<Operation<T>> for ChangeSet<T> {
+
+    fn extend<I: IntoIterator<Item = Operation<T>>>(&mut self, ops: I) {
+        for op in ops {
+            self.push(op);
+        }
+    }
+}
+
+fn remaining_retain<T>(n: usize) -> Option<Operation<T>> {
+    if n > 0 { Some(Retain(n)) } else { None }
+}
+
+fn remaining_delete<T>(n: usize) -> Option<Operation<T>> {
+    if n > 0 { Some(Delete(n)) } else { None }
+}
+
+fn remaining_insert<T>(values: Vec<T>) -> Option<Operation<T>> {
+    if !values.is_empty() { Some(Insert(values)) } else { None }
+}
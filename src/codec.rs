@@ -0,0 +1,331 @@
+//!
+//! A small binary encoding for the values, markers and marker values stored
+//! in a `Rope`, used by the snapshot format and the node store.
+//!
+//! Unsigned integers are written as LEB128 varints, signed integers are
+//! zigzag-encoded first, and everything else is built up from those.
+//!
+
+use std::error::Error;
+use std::fmt;
+
+/// Something that went wrong reading encoded data.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    /// The data didn't start with the expected magic number.
+    BadMagic,
+    UnsupportedVersion(u32),
+    BadChecksum,
+    /// The data ended in the middle of something.
+    Truncated,
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for DecodeError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::BadMagic => write!(f, "not a recognised format"),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported format version {}", version),
+            DecodeError::BadChecksum => write!(f, "checksum mismatch"),
+            DecodeError::Truncated => write!(f, "unexpected end of data"),
+            DecodeError::Corrupt(what) => write!(f, "corrupt data: {}", what),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/// Types that can be written to and read back from bytes. `decode` reads
+/// from the front of `input` and advances it past what it read.
+pub trait Codec: Sized {
+    fn encode(&self, out: &mut Vec<u8>);
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError>;
+}
+
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+
+    out.push(value as u8);
+}
+
+pub fn read_varint(input: &mut &[u8]) -> Result<u64, DecodeError> {
+    let mut value = 0u64;
+    let mut shift = 0;
+
+    loop {
+        let byte = read_byte(input)?;
+
+        if shift > 63 || (shift == 63 && byte > 1) {
+            return Err(DecodeError::Corrupt("varint overflows 64 bits"));
+        }
+
+        value |= u64::from(byte & 0x7f) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+
+        shift += 7;
+    }
+}
+
+pub fn read_byte(input: &mut &[u8]) -> Result<u8, DecodeError> {
+    match input.split_first() {
+        Some((&byte, rest)) => {
+            *input = rest;
+            Ok(byte)
+        },
+        None => Err(DecodeError::Truncated),
+    }
+}
+
+pub fn read_bytes<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8], DecodeError> {
+    if input.len() < n {
+        return Err(DecodeError::Truncated);
+    }
+
+    let (bytes, rest) = input.split_at(n);
+    *input = rest;
+    Ok(bytes)
+}
+
+/// A length or index, which must fit in a `usize` on this platform.
+pub fn read_usize(input: &mut &[u8]) -> Result<usize, DecodeError> {
+    let value = read_varint(input)?;
+
+    if value > usize::MAX as u64 {
+        return Err(DecodeError::Corrupt("length too large for this platform"));
+    }
+
+    Ok(value as usize)
+}
+
+/// The CRC-32 (IEEE) checksum of `bytes`.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a_2f98, 0x7137_4491, 0xb5c0_fbcf, 0xe9b5_dba5, 0x3956_c25b, 0x59f1_11f1, 0x923f_82a4, 0xab1c_5ed5,
+    0xd807_aa98, 0x1283_5b01, 0x2431_85be, 0x550c_7dc3, 0x72be_5d74, 0x80de_b1fe, 0x9bdc_06a7, 0xc19b_f174,
+    0xe49b_69c1, 0xefbe_4786, 0x0fc1_9dc6, 0x240c_a1cc, 0x2de9_2c6f, 0x4a74_84aa, 0x5cb0_a9dc, 0x76f9_88da,
+    0x983e_5152, 0xa831_c66d, 0xb003_27c8, 0xbf59_7fc7, 0xc6e0_0bf3, 0xd5a7_9147, 0x06ca_6351, 0x1429_2967,
+    0x27b7_0a85, 0x2e1b_2138, 0x4d2c_6dfc, 0x5338_0d13, 0x650a_7354, 0x766a_0abb, 0x81c2_c92e, 0x9272_2c85,
+    0xa2bf_e8a1, 0xa81a_664b, 0xc24b_8b70, 0xc76c_51a3, 0xd192_e819, 0xd699_0624, 0xf40e_3585, 0x106a_a070,
+    0x19a4_c116, 0x1e37_6c08, 0x2748_774c, 0x34b0_bcb5, 0x391c_0cb3, 0x4ed8_aa4a, 0x5b9c_ca4f, 0x682e_6ff3,
+    0x748f_82ee, 0x78a5_636f, 0x84c8_7814, 0x8cc7_0208, 0x90be_fffa, 0xa450_6ceb, 0xbef9_a3f7, 0xc671_78f2,
+];
+
+/// The SHA-256 digest of `bytes`.
+pub fn sha256(bytes: &[u8]) -> [u8; 32] {
+    let mut state: [u32; 8] = [
+        0x6a09_e667, 0xbb67_ae85, 0x3c6e_f372, 0xa54f_f53a, 0x510e_527f, 0x9b05_688c, 0x1f83_d9ab, 0x5be0_cd19,
+    ];
+
+    let mut message = bytes.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((bytes.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (word, added) in state.iter_mut().zip(&[a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(*added);
+        }
+    }
+
+    let mut digest = [0; 32];
+
+    for (bytes, word) in digest.chunks_mut(4).zip(&state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+
+    digest
+}
+
+macro_rules! unsigned_codec {
+    ($($t:ty),*) => {$(
+        impl Codec for $t {
+
+            fn encode(&self, out: &mut Vec<u8>) {
+                write_varint(out, *self as u64);
+            }
+
+            fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+                let value = read_varint(input)?;
+
+                if value > <$t>::MAX as u64 {
+                    return Err(DecodeError::Corrupt("integer out of range"));
+                }
+
+                Ok(value as $t)
+            }
+        }
+    )*};
+}
+
+macro_rules! signed_codec {
+    ($($t:ty),*) => {$(
+        impl Codec for $t {
+
+            fn encode(&self, out: &mut Vec<u8>) {
+                let value = *self as i64;
+                write_varint(out, ((value << 1) ^ (value >> 63)) as u64);
+            }
+
+            fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+                let zigzag = read_varint(input)?;
+                let value = ((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64);
+
+                if value < <$t>::MIN as i64 || value > <$t>::MAX as i64 {
+                    return Err(DecodeError::Corrupt("integer out of range"));
+                }
+
+                Ok(value as $t)
+            }
+        }
+    )*};
+}
+
+unsigned_codec!(u8, u16, u32, u64, usize);
+signed_codec!(i8, i16, i32, i64, isize);
+
+impl Codec for () {
+
+    fn encode(&self, _out: &mut Vec<u8>) {}
+
+    fn decode(_input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(())
+    }
+}
+
+impl Codec for bool {
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match read_byte(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::Corrupt("invalid bool")),
+        }
+    }
+}
+
+impl Codec for char {
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, u64::from(*self as u32));
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let value = u32::decode(input)?;
+        ::std::char::from_u32(value).ok_or(DecodeError::Corrupt("invalid char"))
+    }
+}
+
+impl Codec for String {
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = read_usize(input)?;
+        let bytes = read_bytes(input, len)?;
+
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::Corrupt("invalid UTF-8"))
+    }
+}
+
+impl<T: Codec> Codec for Option<T> {
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Some(ref value) => {
+                out.push(1);
+                value.encode(out);
+            },
+            None => out.push(0),
+        }
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match read_byte(input)? {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode(input)?)),
+            _ => Err(DecodeError::Corrupt("invalid option")),
+        }
+    }
+}
+
+impl<T: Codec> Codec for Vec<T> {
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        self.iter().for_each(|value| value.encode(out));
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = read_usize(input)?;
+        // a corrupt length mustn't allocate more than the input could hold
+        let mut values = Vec::with_capacity(len.min(input.len()));
+
+        for _ in 0..len {
+            values.push(T::decode(input)?);
+        }
+
+        Ok(values)
+    }
+}
@@ -0,0 +1,355 @@
+//!
+//! A sequence CRDT for collaborative editing, keeping its visible document
+//! in a `Rope`.
+//!
+//! This is a Replicated Growable Array (RGA): every value ever inserted gets
+//! a unique `ElementId` (a Lamport clock and the id of the replica that
+//! inserted it) and remembers the element it was inserted after. Removed
+//! elements stay behind as tombstones so later insertions can still find
+//! their place. Concurrent insertions after the same element are ordered by
+//! id, newest first, so replicas that have seen the same operations end up
+//! with the same document whatever order they saw them in.
+//!
+//! Operations whose dependencies (the element inserted after, or the element
+//! removed) haven't arrived yet are held back until they do, and operations
+//! seen twice are ignored, so they can be delivered in any order.
+//!
+//! Operations implement `Codec` (when their values do) for sending between
+//! peers, and a `Replica::state` is just a `Vec` of them.
+//!
+//! The element metadata is kept in a plain `Vec`, so integrating an
+//! operation takes time linear in the number of elements (including
+//! tombstones), on top of the O(log n) `Rope` edit.
+//!
+
+use std::collections::HashSet;
+use std::hash::Hash;
+
+use super::{Classifier, Rope};
+use super::codec::{self, Codec, DecodeError};
+
+/// Identifies an inserted element. Ids are ordered by clock first, so an
+/// element inserted after another has seen it has a greater id.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct ElementId {
+    pub clock: u64,
+    pub replica: u64,
+}
+
+/// An operation to be sent to other replicas.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Op<T> {
+    /// Insert `value` just after `origin`, or at the start of the document.
+    Insert {
+        id: ElementId,
+        origin: Option<ElementId>,
+        value: T,
+    },
+
+    Remove {
+        id: ElementId,
+    },
+
+    /// An element that was inserted and has since been removed, as sent by
+    /// `Replica::state` in place of an `Insert` and a `Remove`.
+    Tombstone {
+        id: ElementId,
+        origin: Option<ElementId>,
+    },
+}
+
+impl Codec for ElementId {
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        codec::write_varint(out, self.clock);
+        codec::write_varint(out, self.replica);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(ElementId { clock: codec::read_varint(input)?, replica: codec::read_varint(input)? })
+    }
+}
+
+const INSERT: u8 = 0;
+const REMOVE: u8 = 1;
+const TOMBSTONE: u8 = 2;
+
+impl<T: Codec> Codec for Op<T> {
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Op::Insert { ref id, ref origin, ref value } => {
+                out.push(INSERT);
+                id.encode(out);
+                origin.encode(out);
+                value.encode(out);
+            },
+
+            Op::Remove { ref id } => {
+                out.push(REMOVE);
+                id.encode(out);
+            },
+
+            Op::Tombstone { ref id, ref origin } => {
+                out.push(TOMBSTONE);
+                id.encode(out);
+                origin.encode(out);
+            },
+        }
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match codec::read_byte(input)? {
+            INSERT => Ok(Op::Insert {
+                id: ElementId::decode(input)?,
+                origin: Option::decode(input)?,
+                value: T::decode(input)?,
+            }),
+
+            REMOVE => Ok(Op::Remove { id: ElementId::decode(input)? }),
+
+            TOMBSTONE => Ok(Op::Tombstone {
+                id: ElementId::decode(input)?,
+                origin: Option::decode(input)?,
+            }),
+
+            _ => Err(DecodeError::Corrupt("unknown operation")),
+        }
+    }
+}
+
+struct Element {
+    id: ElementId,
+    origin: Option<ElementId>,
+    removed: bool,
+}
+
+pub struct Replica<T, M = (), V = ()> {
+    id: u64,
+    clock: u64,
+
+    // every element in document order, including tombstones
+    elements: Vec<Element>,
+    known: HashSet<ElementId>,
+
+    pending: Vec<Op<T>>,
+    rope: Rope<T, M, V>,
+}
+
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> Replica<T, M, V> {
+
+    /// An empty document, edited locally by the replica `id`. Every replica
+    /// editing the document needs a different id.
+    pub fn new(id: u64) -> Self {
+        Self::with_rope(id, Rope::new(&[]))
+    }
+
+    /// Like `new`, with the document marked by `classifier`.
+    pub fn with_classifier(id: u64, classifier: &Classifier<T, M, V>) -> Self {
+        Self::with_rope(id, Rope::new(&[]).classified(classifier))
+    }
+
+    fn with_rope(id: u64, rope: Rope<T, M, V>) -> Self {
+        Replica {
+            id,
+            clock: 0,
+            elements: Vec::new(),
+            known: HashSet::new(),
+            pending: Vec::new(),
+            rope,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The visible document.
+    pub fn rope(&self) -> &Rope<T, M, V> {
+        &self.rope
+    }
+
+    /// The number of operations waiting for others to arrive.
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The index in `elements` of the visible element `at`, or
+    /// `elements.len()` if `at` is the length of the document.
+    fn element_index(&self, at: usize) -> usize {
+        let mut visible = 0;
+
+        for (i, element) in self.elements.iter().enumerate() {
+            if !element.removed {
+                if visible == at {
+                    return i;
+                }
+
+                visible += 1;
+            }
+        }
+
+        self.elements.len()
+    }
+
+    fn visible_before(&self, index: usize) -> usize {
+        self.elements[..index].iter().filter(|element| !element.removed).count()
+    }
+
+    fn position(&self, id: ElementId) -> usize {
+        self.elements.iter().position(|element| element.id == id).unwrap()
+    }
+
+    /// Insert `values` at index `at` of the document, returning the
+    /// operations to send to other replicas.
+    pub fn insert(&mut self, at: usize, values: &[T]) -> Vec<Op<T>> {
+        if at > self.rope.len() {
+            panic!("insert index exceeds bounds (length {:?}, index {:?})", self.rope.len(), at);
+        }
+
+        // new ids are greater than any we've seen, so our values go right
+        // after the element they follow
+        let index = self.element_index(at);
+        let mut origin = if index > 0 { Some(self.elements[index - 1].id) } else { None };
+        let mut ops = Vec::with_capacity(values.len());
+
+        for (i, value) in values.iter().enumerate() {
+            self.clock += 1;
+            let id = ElementId { clock: self.clock, replica: self.id };
+
+            self.elements.insert(index + i, Element { id, origin, removed: false });
+            self.known.insert(id);
+
+            ops.push(Op::Insert { id, origin, value: value.clone() });
+            origin = Some(id);
+        }
+
+        self.rope = self.rope.insert(at, values);
+        ops
+    }
+
+    /// Remove `start..end` (`end` EXclusive) from the document, returning
+    /// the operations to send to other replicas.
+    pub fn remove(&mut self, start: usize, end: usize) -> Vec<Op<T>> {
+        if start > end || end > self.rope.len() {
+            panic!("bad remove indices: {}, {}", start, end);
+        }
+
+        let mut ops = Vec::with_capacity(end - start);
+        let mut index = self.element_index(start);
+
+        while ops.len() < end - start {
+            if !self.elements[index].removed {
+                self.elements[index].removed = true;
+                ops.push(Op::Remove { id: self.elements[index].id });
+            }
+
+            index += 1;
+        }
+
+        if start < end {
+            self.rope = self.rope.remove(start, end);
+        }
+
+        ops
+    }
+
+    /// Integrate an operation from another replica (or this one: operations
+    /// seen before are ignored).
+    pub fn apply(&mut self, op: Op<T>) {
+        if !self.is_ready(&op) {
+            self.pending.push(op);
+            return;
+        }
+
+        self.integrate(op);
+
+        // something waiting for that operation might be ready now
+        while let Some(i) = self.pending.iter().position(|op| self.is_ready(op)) {
+            let op = self.pending.swap_remove(i);
+            self.integrate(op);
+        }
+    }
+
+    pub fn apply_all<I: IntoIterator<Item = Op<T>>>(&mut self, ops: I) {
+        for op in ops {
+            self.apply(op);
+        }
+    }
+
+    fn is_ready(&self, op: &Op<T>) -> bool {
+        match *op {
+            Op::Insert { origin: Some(origin), .. } |
+            Op::Tombstone { origin: Some(origin), .. } => self.known.contains(&origin),
+            Op::Insert { origin: None, .. } | Op::Tombstone { origin: None, .. } => true,
+            Op::Remove { id } => self.known.contains(&id),
+        }
+    }
+
+    fn integrate(&mut self, op: Op<T>) {
+        match op {
+            Op::Insert { id, .. } | Op::Tombstone { id, .. } if self.known.contains(&id) => {
+                if let Op::Tombstone { .. } = op {
+                    self.integrate(Op::Remove { id });
+                }
+            },
+
+            Op::Insert { id, origin, value } => self.add_element(id, origin, Some(value)),
+            Op::Tombstone { id, origin } => self.add_element(id, origin, None),
+
+            Op::Remove { id } => {
+                let index = self.position(id);
+
+                if !self.elements[index].removed {
+                    let at = self.visible_before(index);
+                    self.elements[index].removed = true;
+                    self.rope = self.rope.remove(at, at + 1);
+                }
+            },
+        }
+    }
+
+    /// Add the new element `id` after `origin`, with no value if it's a
+    /// tombstone.
+    fn add_element(&mut self, id: ElementId, origin: Option<ElementId>, value: Option<T>) {
+        let mut index = match origin {
+            Some(origin) => self.position(origin) + 1,
+            None => 0,
+        };
+
+        // skip past concurrent insertions after the same origin with
+        // greater ids, and everything inserted after those
+        while index < self.elements.len() && self.elements[index].id > id {
+            index += 1;
+        }
+
+        let at = self.visible_before(index);
+        let removed = value.is_none();
+
+        self.elements.insert(index, Element { id, origin, removed });
+        self.known.insert(id);
+        self.clock = self.clock.max(id.clock);
+
+        if let Some(value) = value {
+            self.rope = self.rope.insert(at, &[value]);
+        }
+    }
+
+    /// Operations reproducing this replica's whole state (including
+    /// tombstones) on another replica, e.g. one joining late.
+    pub fn state(&self) -> Vec<Op<T>> {
+        let mut values = self.rope.iter();
+
+        self.elements.iter().map(|element| {
+            if element.removed {
+                Op::Tombstone { id: element.id, origin: element.origin }
+            } else {
+                Op::Insert {
+                    id: element.id,
+                    origin: element.origin,
+                    value: values.next().unwrap().clone(),
+                }
+            }
+        }).collect()
+    }
+}
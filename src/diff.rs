@@ -0,0 +1,243 @@
+//!
+//! Diffs between two versions of a `Rope`.
+//!
+//! Versions made from one another by persistent edits share most of their
+//! nodes, so rather than comparing them value by value we first line up the
+//! subtrees they share (by pointer), descending only into the ones that
+//! differ. Only the values in the regions between shared subtrees are then
+//! compared, with Myers' O(ND) algorithm in its linear space form.
+//!
+
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::ops::Range;
+
+use super::{ChangeSet, Link, Node, Shared, Rope};
+use super::Node::*;
+
+type Nodes<T, M, V> = Vec<Link<T, M, V>>;
+
+impl<T: Clone + PartialEq, M: Eq + Hash + Copy, V: Clone> Rope<T, M, V> {
+
+    /// An edit script turning `old` into `new`: applying it to `old` gives
+    /// a rope with the same values as `new`, and its `hunks` are the regions
+    /// that changed.
+    pub fn diff(old: &Self, new: &Self) -> ChangeSet<T> {
+        let (old_nodes, new_nodes) = unshared(vec![old.root.clone()], vec![new.root.clone()]);
+
+        let shared = common_subsequence(old_nodes.len(), new_nodes.len(), |a, b| {
+            Shared::ptr_eq(&old_nodes[a], &new_nodes[b])
+        });
+
+        let mut changes = ChangeSet::new();
+        let (mut a, mut b) = (0, 0);
+
+        for (next_a, next_b) in shared {
+            diff_values(&old_nodes[a..next_a], &new_nodes[b..next_b], &mut changes);
+            changes.retain(old_nodes[next_a].len());
+
+            a = next_a + 1;
+            b = next_b + 1;
+        }
+
+        diff_values(&old_nodes[a..], &new_nodes[b..], &mut changes);
+        changes
+    }
+}
+
+/// Split both sequences of nodes until every node in one is either shared
+/// with the other or a leaf, dropping empty nodes on the way.
+fn unshared<T, M, V>(mut old: Nodes<T, M, V>, mut new: Nodes<T, M, V>) -> (Nodes<T, M, V>, Nodes<T, M, V>)
+    where T: Clone, M: Eq + Hash + Copy, V: Clone {
+
+    loop {
+        let old_ptrs: HashSet<*const Node<T, M, V>> = old.iter().map(Shared::as_ptr).collect();
+        let new_ptrs: HashSet<*const Node<T, M, V>> = new.iter().map(Shared::as_ptr).collect();
+
+        let mut split = false;
+        old = split_unshared(old, &new_ptrs, &mut split);
+        new = split_unshared(new, &old_ptrs, &mut split);
+
+        if !split {
+            return (old, new);
+        }
+    }
+}
+
+fn split_unshared<T, M, V>(nodes: Nodes<T, M, V>,
+                           shared: &HashSet<*const Node<T, M, V>>,
+                           split: &mut bool) -> Nodes<T, M, V>
+    where T: Clone, M: Eq + Hash + Copy, V: Clone {
+
+    let mut result = Vec::with_capacity(nodes.len());
+
+    for node in nodes {
+        if node.len() == 0 {
+            continue;
+        }
+
+        match *node {
+            Concat { ref left, ref right, .. } if !shared.contains(&Shared::as_ptr(&node)) => {
+                result.push(left.clone());
+                result.push(right.clone());
+                *split = true;
+            },
+            _ => result.push(node.clone()),
+        }
+    }
+
+    result
+}
+
+fn push_values<'a, T, M, V>(node: &'a Node<T, M, V>, values: &mut Vec<&'a T>) {
+    match *node {
+        Concat { ref left, ref right, .. } => {
+            push_values(left, values);
+            push_values(right, values);
+        },
+        Flat { ref data, .. } => values.extend(data.iter()),
+    }
+}
+
+/// Add the changes turning the values under `old` into those under `new`.
+fn diff_values<T, M, V>(old: &[Link<T, M, V>], new: &[Link<T, M, V>], changes: &mut ChangeSet<T>)
+    where T: Clone + PartialEq {
+
+    let mut old_values = Vec::new();
+    let mut new_values = Vec::new();
+    old.iter().for_each(|node| push_values(node, &mut old_values));
+    new.iter().for_each(|node| push_values(node, &mut new_values));
+
+    let same = common_subsequence(old_values.len(), new_values.len(), |a, b| {
+        old_values[a] == new_values[b]
+    });
+
+    let (mut a, mut b) = (0, 0);
+
+    for (next_a, next_b) in same {
+        changes.delete(next_a - a);
+        changes.insert_vec(new_values[b..next_b].iter().map(|&value| value.clone()).collect());
+        changes.retain(1);
+
+        a = next_a + 1;
+        b = next_b + 1;
+    }
+
+    changes.delete(old_values.len() - a);
+    changes.insert_vec(new_values[b..].iter().map(|&value| value.clone()).collect());
+}
+
+/// The pairs of indices of a longest common subsequence of two sequences of
+/// lengths `n` and `m`, whose elements are compared by `eq`.
+fn common_subsequence<F>(n: usize, m: usize, eq: F) -> Vec<(usize, usize)>
+    where F: Fn(usize, usize) -> bool {
+
+    let mut pairs = Vec::new();
+    push_common(0..n, 0..m, &eq, &mut pairs);
+    pairs
+}
+
+/// Push the pairs of a longest common subsequence of `a` and `b`, in order.
+/// Common prefixes and suffixes are matched directly, and the rest split
+/// at the middle of its shortest edit script and matched piece by piece, so
+/// we only ever need O(n + m) memory (Myers' linear space refinement).
+fn push_common<F>(a: Range<usize>, b: Range<usize>, eq: &F, pairs: &mut Vec<(usize, usize)>)
+    where F: Fn(usize, usize) -> bool {
+
+    let (mut a, mut b) = (a, b);
+
+    while a.start < a.end && b.start < b.end && eq(a.start, b.start) {
+        pairs.push((a.start, b.start));
+        a.start += 1;
+        b.start += 1;
+    }
+
+    let mut suffix = 0;
+    while suffix < a.len() && suffix < b.len() && eq(a.end - suffix - 1, b.end - suffix - 1) {
+        suffix += 1;
+    }
+
+    a.end -= suffix;
+    b.end -= suffix;
+
+    // with the prefix and suffix gone, both halves are smaller than the whole
+    if !a.is_empty() && !b.is_empty() {
+        let (x, y, u, v) = middle_snake(a.clone(), b.clone(), eq);
+
+        push_common(a.start..x, b.start..y, eq, pairs);
+        pairs.extend((x..u).zip(y..v));
+        push_common(u..a.end, v..b.end, eq, pairs);
+    }
+
+    pairs.extend((a.end..a.end + suffix).zip(b.end..b.end + suffix));
+}
+
+/// The start and end `(x, y, u, v)` of the middle snake of a shortest edit
+/// script turning `a` into `b`: a run of matching values `x..u`, `y..v`
+/// which the script passes through halfway. Found by running Myers'
+/// algorithm forwards from the start and backwards from the end at once
+/// until the two meet.
+fn middle_snake<F>(a: Range<usize>, b: Range<usize>, eq: &F) -> (usize, usize, usize, usize)
+    where F: Fn(usize, usize) -> bool {
+
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (n + m + 1) / 2;
+    let delta = n - m;
+
+    // forward[offset + k] is the furthest x reached on diagonal k = x - y,
+    // and backward[offset + c] the same, counting x and y back from the
+    // ends, so backward diagonal c is forward diagonal delta - c
+    let mut forward = vec![0isize; 2 * max as usize + 3];
+    let mut backward = vec![0isize; 2 * max as usize + 3];
+    let diagonal = |k: isize| (max + 1 + k) as usize;
+
+    for d in 0..max + 1 {
+        for k in (-d..d + 1).step_by(2) {
+            let down = k == -d || (k != d && forward[diagonal(k - 1)] < forward[diagonal(k + 1)]);
+            let start = if down {
+                forward[diagonal(k + 1)]
+            } else {
+                forward[diagonal(k - 1)] + 1
+            };
+
+            let mut x = start;
+            while x < n && x - k < m && eq(a.start + x as usize, b.start + (x - k) as usize) {
+                x += 1;
+            }
+
+            forward[diagonal(k)] = x;
+
+            let c = delta - k;
+            if delta % 2 != 0 && c.abs() < d && x + backward[diagonal(c)] >= n {
+                let at = |x: isize, y: isize| (a.start + x as usize, b.start + y as usize);
+                let ((x0, y0), (u, v)) = (at(start, start - k), at(x, x - k));
+                return (x0, y0, u, v);
+            }
+        }
+
+        for c in (-d..d + 1).step_by(2) {
+            let down = c == -d || (c != d && backward[diagonal(c - 1)] < backward[diagonal(c + 1)]);
+            let start = if down {
+                backward[diagonal(c + 1)]
+            } else {
+                backward[diagonal(c - 1)] + 1
+            };
+
+            let mut x = start;
+            while x < n && x - c < m && eq(a.end - 1 - x as usize, b.end - 1 - (x - c) as usize) {
+                x += 1;
+            }
+
+            backward[diagonal(c)] = x;
+
+            let k = delta - c;
+            if delta % 2 == 0 && k.abs() <= d && x + forward[diagonal(k)] >= n {
+                let at = |x: isize, y: isize| (a.end - x as usize, b.end - y as usize);
+                let ((x0, y0), (u, v)) = (at(x, x - c), at(start, start - c));
+                return (x0, y0, u, v);
+            }
+        }
+    }
+
+    unreachable!("no middle snake between {:?} and {:?}", a, b)
+}
@@ -0,0 +1,421 @@
+//!
+//! A document shared between threads: any number of readers, and a writer
+//! publishing new versions. Only available with the `sync` feature.
+//!
+//! The document only holds the root of its current rope, and ropes are
+//! persistent, so a reader takes a snapshot by cloning that root (a
+//! reference count increment) and then works on the snapshot for as long
+//! as it likes without holding anything.
+//!
+//! Readers never take a lock. The document keeps two copies of its
+//! snapshot, one current and one stale, and publishing overwrites the stale
+//! one and then atomically makes it current. Each copy counts the readers
+//! cloning it, so the writer only overwrites a copy nobody is reading, and
+//! a reader that finds the copy it picked is no longer current just tries
+//! again. The writer may have to wait for a reader to finish cloning a
+//! root, but never for a reader to let go of a snapshot, and readers only
+//! wait for a writer by retrying a clone. Writers take a lock among
+//! themselves, so versions are still published one at a time. The stale
+//! copy keeps the version before the current one alive until the next is
+//! published.
+//!
+//! Observers subscribed to a document are told about every version
+//! published, in order, with the version before it and the ranges that
+//! changed. The ranges come from the changeset when a version is made with
+//! `apply_over`, and otherwise from `Rope::diff`, which is only run when an
+//! observer first asks for them.
+//!
+//! Publishing only queues a version for the observers while it holds the
+//! writers' lock. The queue is then delivered with no lock held but the
+//! observers' own, one version at a time, by whichever publishing thread
+//! finds it waiting, so slow observers don't hold up readers and observers
+//! can take snapshots (or even publish) themselves.
+//!
+
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::mem;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+
+use super::{ChangeSet, Hunk, Rope};
+
+/// A version of a `Document`'s rope.
+pub struct Snapshot<T, M = (), V = ()> {
+    rope: Rope<T, M, V>,
+    version: u64,
+}
+
+impl<T, M, V> Snapshot<T, M, V> {
+
+    pub fn rope(&self) -> &Rope<T, M, V> {
+        &self.rope
+    }
+
+    /// The document's version when the snapshot was taken, counting from 0
+    /// and going up by one for each version published.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+impl<T, M, V> Clone for Snapshot<T, M, V> {
+
+    fn clone(&self) -> Self {
+        Snapshot { rope: self.rope.clone(), version: self.version }
+    }
+}
+
+/// A version published to a `Document`, as delivered to its observers.
+pub struct Change<T, M = (), V = ()> {
+    old: Rope<T, M, V>,
+    new: Rope<T, M, V>,
+    version: u64,
+
+    changes: Option<ChangeSet<T>>,
+    hunks: OnceLock<Vec<Hunk>>,
+}
+
+impl<T, M, V> Change<T, M, V> {
+
+    /// The version before this one.
+    pub fn old_rope(&self) -> &Rope<T, M, V> {
+        &self.old
+    }
+
+    pub fn new_rope(&self) -> &Rope<T, M, V> {
+        &self.new
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+impl<T, M, V> Change<T, M, V>
+    where T: Clone + PartialEq, M: Eq + Hash + Copy, V: Clone {
+
+    /// The ranges that changed between the old rope and the new one, in order.
+    pub fn hunks(&self) -> &[Hunk] {
+        self.hunks.get_or_init(|| match self.changes {
+            Some(ref changes) => changes.hunks(),
+            None => Rope::diff(&self.old, &self.new).hunks(),
+        })
+    }
+}
+
+/// Identifies an observer added by `Document::subscribe` or
+/// `Document::subscribe_channel`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct ObserverId(u64);
+
+type Callback<T, M, V> = Box<dyn FnMut(&Change<T, M, V>) + Send>;
+
+/// The receiving end of `Document::subscribe_channel`.
+pub type ChangeReceiver<T, M = (), V = ()> = Receiver<Arc<Change<T, M, V>>>;
+
+enum Observer<T, M, V> {
+    Callback(Callback<T, M, V>),
+    Channel(Sender<Arc<Change<T, M, V>>>),
+}
+
+struct Observers<T, M, V> {
+    next_id: u64,
+    list: Vec<(ObserverId, Observer<T, M, V>)>,
+}
+
+/// Versions waiting to be delivered to the observers, oldest first.
+struct Pending<T, M, V> {
+    queue: VecDeque<Arc<Change<T, M, V>>>,
+    // whether some thread is delivering them
+    delivering: bool,
+}
+
+/// Lets another thread take over delivering if an observer panics.
+struct Delivering<'a, T: 'a, M: 'a, V: 'a>(&'a Mutex<Pending<T, M, V>>);
+
+impl<'a, T, M, V> Drop for Delivering<'a, T, M, V> {
+
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).delivering = false;
+        }
+    }
+}
+
+/// One of a `Document`'s two copies of its snapshot.
+struct Slot<T, M, V> {
+    snapshot: UnsafeCell<Snapshot<T, M, V>>,
+
+    // the readers cloning `snapshot` right now
+    readers: AtomicUsize,
+}
+
+impl<T, M, V> Slot<T, M, V> {
+
+    fn new(snapshot: Snapshot<T, M, V>) -> Self {
+        Slot { snapshot: UnsafeCell::new(snapshot), readers: AtomicUsize::new(0) }
+    }
+}
+
+/// The current version of a rope, shared between threads.
+pub struct Document<T, M = (), V = ()> {
+    slots: [Slot<T, M, V>; 2],
+
+    // the index of the slot holding the current snapshot
+    current: AtomicUsize,
+
+    // held by whoever is publishing; readers never take it
+    writing: Mutex<()>,
+
+    // the same as the current snapshot's version, for checking without
+    // cloning it
+    version: AtomicU64,
+
+    // the number of observers, so publishing can skip making a `Change`
+    // without waiting for the observers' lock
+    observing: AtomicUsize,
+
+    // only ever locked briefly, and after `writing` if both are
+    pending: Mutex<Pending<T, M, V>>,
+
+    // held while delivering, but never along with `writing` or `pending`
+    observers: Mutex<Observers<T, M, V>>,
+}
+
+// a slot's snapshot is only written by the writer holding `writing`, and
+// only while no reader is cloning it (see `snapshot` and `swap_in`)
+unsafe impl<T, M, V> Sync for Document<T, M, V>
+    where Rope<T, M, V>: Send + Sync, T: Send + Sync {}
+
+impl<T, M, V> Document<T, M, V>
+    where T: Clone + Send + Sync, M: Eq + Hash + Copy + Send + Sync, V: Clone + Send + Sync {
+
+    /// A document whose version 0 is `rope`. Share it between threads with
+    /// an `Arc`.
+    pub fn new(rope: Rope<T, M, V>) -> Self {
+        Document {
+            slots: [Slot::new(Snapshot { rope: rope.clone(), version: 0 }),
+                    Slot::new(Snapshot { rope, version: 0 })],
+            current: AtomicUsize::new(0),
+            writing: Mutex::new(()),
+            version: AtomicU64::new(0),
+            observing: AtomicUsize::new(0),
+            pending: Mutex::new(Pending { queue: VecDeque::new(), delivering: false }),
+            observers: Mutex::new(Observers { next_id: 0, list: Vec::new() }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        // the lock guards nothing but the right to publish
+        self.writing.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The current snapshot, for a writer holding `writing`.
+    fn current(&self, _writing: &MutexGuard<'_, ()>) -> &Snapshot<T, M, V> {
+        let slot = &self.slots[self.current.load(Ordering::SeqCst)];
+
+        // only writers change the current slot or write to a slot, and only
+        // to the one that isn't current
+        unsafe { &*slot.snapshot.get() }
+    }
+
+    fn lock_pending(&self) -> MutexGuard<'_, Pending<T, M, V>> {
+        self.pending.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock_observers(&self) -> MutexGuard<'_, Observers<T, M, V>> {
+        // an observer may have panicked, which leaves the list itself intact
+        self.observers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The current version, for readers to check whether a snapshot is
+    /// stale without taking a new one.
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    pub fn is_current(&self, snapshot: &Snapshot<T, M, V>) -> bool {
+        self.version() == snapshot.version
+    }
+
+    pub fn snapshot(&self) -> Snapshot<T, M, V> {
+        loop {
+            let current = self.current.load(Ordering::SeqCst);
+            let slot = &self.slots[current];
+
+            slot.readers.fetch_add(1, Ordering::SeqCst);
+
+            // the writer won't overwrite the slot while we're counted, but it
+            // may have started to before we were, if the slot has stopped
+            // being current since we picked it
+            if self.current.load(Ordering::SeqCst) == current {
+                let snapshot = unsafe { (*slot.snapshot.get()).clone() };
+
+                slot.readers.fetch_sub(1, Ordering::SeqCst);
+                return snapshot;
+            }
+
+            slot.readers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Make `rope` the current version, returning its version number.
+    pub fn publish(&self, rope: Rope<T, M, V>) -> u64 {
+        self.swap_in(self.lock(), rope, None)
+    }
+
+    /// Publish `rope` only if the current version is still `base`, the one
+    /// it was made from; otherwise return the newer version, e.g. for
+    /// rebasing onto it. This keeps edits from being lost if there's ever
+    /// more than one writer.
+    pub fn publish_over(&self, base: u64, rope: Rope<T, M, V>) -> Result<u64, Snapshot<T, M, V>> {
+        self.swap_in_over(base, rope, None)
+    }
+
+    /// Apply `changes` to version `base` and publish the result, as with
+    /// `publish_over`. Observers get their ranges from `changes` rather
+    /// than a diff. The changes are applied without holding any lock.
+    pub fn apply_over(&self, base: u64, changes: &ChangeSet<T>) -> Result<u64, Snapshot<T, M, V>> {
+        let snapshot = self.snapshot();
+
+        if snapshot.version != base {
+            return Err(snapshot);
+        }
+
+        let rope = changes.apply(&snapshot.rope);
+        self.swap_in_over(base, rope, Some(changes.clone()))
+    }
+
+    fn swap_in_over(&self, base: u64, rope: Rope<T, M, V>, changes: Option<ChangeSet<T>>) -> Result<u64, Snapshot<T, M, V>> {
+        let writing = self.lock();
+
+        if self.current(&writing).version != base {
+            return Err(self.current(&writing).clone());
+        }
+
+        Ok(self.swap_in(writing, rope, changes))
+    }
+
+    fn swap_in(&self, writing: MutexGuard<'_, ()>, rope: Rope<T, M, V>, changes: Option<ChangeSet<T>>) -> u64 {
+        let old = self.current(&writing).rope.clone();
+        let version = self.current(&writing).version + 1;
+        let new = rope.clone();
+
+        // wait for any readers still cloning the stale snapshot, who will
+        // find it isn't current and try again
+        let stale = 1 - self.current.load(Ordering::SeqCst);
+        let slot = &self.slots[stale];
+
+        while slot.readers.load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+
+        // readers counted from now on won't read the slot until it's current
+        let replaced = mem::replace(unsafe { &mut *slot.snapshot.get() }, Snapshot { rope, version });
+
+        self.current.store(stale, Ordering::SeqCst);
+        self.version.store(version, Ordering::Release);
+        drop(replaced);
+
+        if self.observing.load(Ordering::Acquire) == 0 {
+            return version;
+        }
+
+        let change = Arc::new(Change {
+            old,
+            new,
+            version,
+            changes,
+            hunks: OnceLock::new(),
+        });
+
+        // queue it before letting go of `writing`, so versions are queued in
+        // order, and deliver it unless another thread already is
+        let deliver = {
+            let mut pending = self.lock_pending();
+            pending.queue.push_back(change);
+            !mem::replace(&mut pending.delivering, true)
+        };
+
+        drop(writing);
+
+        if deliver {
+            self.deliver();
+        }
+
+        version
+    }
+
+    fn deliver(&self) {
+        let _delivering = Delivering(&self.pending);
+
+        loop {
+            let change = {
+                let mut pending = self.lock_pending();
+
+                match pending.queue.pop_front() {
+                    Some(change) => change,
+                    None => {
+                        pending.delivering = false;
+                        return;
+                    },
+                }
+            };
+
+            let mut observers = self.lock_observers();
+
+            observers.list.retain_mut(|&mut (_, ref mut observer)| match *observer {
+                Observer::Callback(ref mut callback) => {
+                    callback(&change);
+                    true
+                },
+                // a receiver that's gone away unsubscribes its channel
+                Observer::Channel(ref sender) => sender.send(change.clone()).is_ok(),
+            });
+
+            self.observing.store(observers.list.len(), Ordering::Release);
+        }
+    }
+
+    fn add_observer(&self, observer: Observer<T, M, V>) -> ObserverId {
+        let mut observers = self.lock_observers();
+        let id = ObserverId(observers.next_id);
+
+        observers.next_id += 1;
+        observers.list.push((id, observer));
+        self.observing.store(observers.list.len(), Ordering::Release);
+        id
+    }
+
+    /// Call `callback` for each version published from now on, in order,
+    /// on a thread publishing to the document (not necessarily the one that
+    /// published that version). Callbacks can take snapshots and publish,
+    /// but mustn't subscribe or unsubscribe.
+    pub fn subscribe<F>(&self, callback: F) -> ObserverId
+        where F: FnMut(&Change<T, M, V>) + Send + 'static {
+
+        self.add_observer(Observer::Callback(Box::new(callback)))
+    }
+
+    /// A channel receiving each version published from now on. Dropping the
+    /// receiver unsubscribes it.
+    pub fn subscribe_channel(&self) -> (ObserverId, ChangeReceiver<T, M, V>) {
+        let (sender, receiver) = mpsc::channel();
+        (self.add_observer(Observer::Channel(sender)), receiver)
+    }
+
+    /// Stop telling an observer about new versions, returning whether it
+    /// was still subscribed.
+    pub fn unsubscribe(&self, id: ObserverId) -> bool {
+        let mut observers = self.lock_observers();
+        let count = observers.list.len();
+
+        observers.list.retain(|&(observer, _)| observer != id);
+        self.observing.store(observers.list.len(), Ordering::Release);
+        observers.list.len() < count
+    }
+}
@@ -0,0 +1,259 @@
+//!
+//! Linear undo/redo history over `Rope` versions.
+//!
+//! Each step keeps the `Rope` it produced, so undoing and redoing just switch
+//! between versions that share most of their structure. Consecutive small
+//! edits (typing, or deleting with backspace) made within a short time of
+//! each other are coalesced into one step, and the oldest steps are dropped
+//! once the history holds too many of them, or too many inserted and deleted
+//! values.
+//!
+
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use super::{Bias, ChangeSet, Rope};
+use super::Operation::*;
+
+const DEFAULT_MAX_STEPS: usize = 1000;
+const DEFAULT_COALESCE_WINDOW: Duration = Duration::from_secs(1);
+
+struct Step<T, M, V> {
+    rope: Rope<T, M, V>,
+    changes: ChangeSet<T>,
+
+    // where to put the cursor after undoing or redoing this step
+    cursor_before: Option<usize>,
+    cursor_after: Option<usize>,
+
+    time: Instant,
+}
+
+impl<T: Clone, M, V> Step<T, M, V> {
+
+    /// Roughly how much memory this step keeps alive: the values it inserted
+    /// and those it deleted.
+    fn size(&self) -> usize {
+        self.changes.operations().iter().map(|op| match *op {
+            Retain(_) => 0,
+            Insert(ref values) => values.len(),
+            Delete(n) => n,
+        }).sum()
+    }
+}
+
+pub struct History<T, M = (), V = ()> {
+    // the oldest version we can undo back to
+    base: Rope<T, M, V>,
+
+    steps: VecDeque<Step<T, M, V>>,
+
+    // how many of `steps` are applied to get the current version
+    current: usize,
+
+    size: usize,
+    max_steps: usize,
+    max_size: usize,
+
+    coalesce_window: Duration,
+    coalescing: bool,
+
+    cursor_hint: Option<usize>,
+}
+
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> History<T, M, V> {
+
+    pub fn new(rope: Rope<T, M, V>) -> Self {
+        History {
+            base: rope,
+            steps: VecDeque::new(),
+            current: 0,
+            size: 0,
+            max_steps: DEFAULT_MAX_STEPS,
+            max_size: usize::MAX,
+            coalesce_window: DEFAULT_COALESCE_WINDOW,
+            coalescing: true,
+            cursor_hint: None,
+        }
+    }
+
+    /// The current version of the document.
+    pub fn rope(&self) -> &Rope<T, M, V> {
+        match self.current {
+            0 => &self.base,
+            n => &self.steps[n - 1].rope,
+        }
+    }
+
+    /// Keep at most `max_steps` steps, dropping the oldest first.
+    pub fn set_max_steps(&mut self, max_steps: usize) {
+        self.max_steps = max_steps;
+        self.prune();
+    }
+
+    /// Keep at most `max_size` inserted and deleted values across all
+    /// steps, dropping the oldest steps first. The current step is always
+    /// kept, however big it is.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.prune();
+    }
+
+    /// How long after one small edit another can still be coalesced into
+    /// the same step. A zero window turns coalescing off.
+    pub fn set_coalesce_window(&mut self, window: Duration) {
+        self.coalesce_window = window;
+    }
+
+    /// Make sure the next edit starts a new step, e.g. because the cursor
+    /// was moved.
+    pub fn break_coalescing(&mut self) {
+        self.coalescing = false;
+    }
+
+    /// Apply `changes` to the current version as a new step, discarding
+    /// anything that could have been redone. `cursor` is the cursor position
+    /// before the edit, to be restored when it's undone.
+    pub fn edit(&mut self, changes: ChangeSet<T>, cursor: Option<usize>) -> &Rope<T, M, V> {
+        self.edit_at(changes, cursor, Instant::now())
+    }
+
+    /// Like `edit`, but as if the edit happened at `time`.
+    pub fn edit_at(&mut self,
+                   changes: ChangeSet<T>,
+                   cursor: Option<usize>,
+                   time: Instant) -> &Rope<T, M, V> {
+
+        let rope = changes.apply(self.rope());
+        let cursor_after = cursor.map(|at| changes.map_position(at, Bias::Right));
+
+        while self.steps.len() > self.current {
+            let undone = self.steps.pop_back().unwrap();
+            self.size -= undone.size();
+        }
+
+        if self.coalesces_with_last(&changes, time) {
+            let last = self.steps.back_mut().unwrap();
+            self.size -= last.size();
+
+            last.changes = last.changes.compose(&changes);
+            last.rope = rope;
+            last.cursor_after = cursor_after;
+            last.time = time;
+
+            self.size += last.size();
+        } else {
+            let step = Step {
+                rope,
+                changes,
+                cursor_before: cursor,
+                cursor_after,
+                time,
+            };
+
+            self.size += step.size();
+            self.steps.push_back(step);
+            self.current += 1;
+        }
+
+        self.coalescing = true;
+        self.cursor_hint = cursor_after;
+        self.prune();
+
+        self.rope()
+    }
+
+    /// Whether `changes` continues the last step: both are insertions (or
+    /// both deletions) and `changes` picks up where the step left off.
+    fn coalesces_with_last(&self, changes: &ChangeSet<T>, time: Instant) -> bool {
+        let last = match self.steps.back() {
+            Some(last) if self.coalescing && self.current == self.steps.len() => last,
+            _ => return false,
+        };
+
+        if time.saturating_duration_since(last.time) >= self.coalesce_window {
+            return false;
+        }
+
+        match (last.changes.as_splice(), changes.as_splice()) {
+            (Some((start, end, inserted)), Some((next_start, next_end, next_inserted))) => {
+                if start == end && next_start == next_end {
+                    !inserted.is_empty() && next_start == start + inserted.len()
+                } else if inserted.is_empty() && next_inserted.is_empty() {
+                    // backspace, or delete
+                    next_end == start || next_start == start
+                } else {
+                    false
+                }
+            },
+
+            _ => false,
+        }
+    }
+
+    fn prune(&mut self) {
+        while self.current > 1 &&
+              (self.steps.len() > self.max_steps || self.size > self.max_size) {
+
+            let oldest = self.steps.pop_front().unwrap();
+            self.size -= oldest.size();
+            self.base = oldest.rope;
+            self.current -= 1;
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.current > 0
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.current < self.steps.len()
+    }
+
+    pub fn undo_count(&self) -> usize {
+        self.current
+    }
+
+    pub fn redo_count(&self) -> usize {
+        self.steps.len() - self.current
+    }
+
+    /// Go back to the version before the last step, returning false if
+    /// there's nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        if !self.can_undo() {
+            return false;
+        }
+
+        self.current -= 1;
+        self.cursor_hint = self.steps[self.current].cursor_before;
+        self.coalescing = false;
+        true
+    }
+
+    /// Reapply the last undone step, returning false if there's nothing to
+    /// redo.
+    pub fn redo(&mut self) -> bool {
+        if !self.can_redo() {
+            return false;
+        }
+
+        self.cursor_hint = self.steps[self.current].cursor_after;
+        self.current += 1;
+        self.coalescing = false;
+        true
+    }
+
+    /// Where the cursor should go after the last edit, undo or redo, if the
+    /// edit it came from recorded a cursor.
+    pub fn cursor_hint(&self) -> Option<usize> {
+        self.cursor_hint
+    }
+
+    /// The total number of values inserted and deleted by the recorded
+    /// steps, which is what `set_max_size` limits.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
@@ -0,0 +1,315 @@
+//!
+//! An append-only journal of edits, for recovering unsaved changes after a
+//! crash.
+//!
+//! A `Journal` applies each edit to a rope and appends a record of it to a
+//! file. After a crash, `Journal::replay` applies the recorded edits to the
+//! rope the journal was started from (e.g. the last saved version) to get
+//! back the rope as it was.
+//!
+//! The file starts with the magic bytes `ROPEJRNL` and a little-endian
+//! `u32` format version. Each record is then the little-endian `u32`
+//! length of its payload, the CRC-32 of the payload, and the payload: `0`,
+//! the index and the inserted values; `1`, the start and end of the
+//! removed range; or `2`, the start and end of the replaced range and the
+//! new values. If the last record was only partly written when the editor
+//! crashed, it's ignored; a damaged record anywhere else is an error,
+//! including one whose length runs past the end of the file with good
+//! records hidden after it.
+//!
+
+use std::fs::{File, OpenOptions};
+use std::hash::Hash;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+
+use super::Rope;
+use super::codec::{self, Codec, DecodeError};
+
+const MAGIC: &[u8] = b"ROPEJRNL";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = 12;
+
+const INSERT: u8 = 0;
+const REMOVE: u8 = 1;
+const REPLACE: u8 = 2;
+
+/// When a `Journal` asks the OS to flush records to disk. Records that
+/// haven't been flushed can be lost if the whole system goes down, though
+/// not if only the editor crashes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SyncPolicy {
+    /// Leave it to the OS (or explicit calls to `Journal::sync`).
+    Never,
+    /// After every record.
+    Always,
+    /// After every `n` records.
+    Every(usize),
+}
+
+pub struct Journal<T> {
+    file: File,
+    policy: SyncPolicy,
+    unsynced: usize,
+    values: PhantomData<T>,
+}
+
+fn invalid(error: DecodeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// The payload length and checksum at the start of the record `bytes`.
+fn record_header(bytes: &[u8]) -> (usize, u32) {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[..4]);
+    let len = u32::from_le_bytes(word) as usize;
+    word.copy_from_slice(&bytes[4..8]);
+
+    (len, u32::from_le_bytes(word))
+}
+
+/// Whether `bytes` starts with a whole record that checks out. Every
+/// payload has at least its tag.
+fn is_record(bytes: &[u8]) -> bool {
+    if bytes.len() < 9 {
+        return false;
+    }
+
+    let (len, checksum) = record_header(bytes);
+    len > 0 && len <= bytes.len() - 8 && codec::crc32(&bytes[8..8 + len]) == checksum
+}
+
+/// The length of the valid part of the journal `bytes`, after its header,
+/// passing each record's payload to `each`.
+fn scan<F>(bytes: &[u8], mut each: F) -> io::Result<usize>
+    where F: FnMut(&[u8]) -> io::Result<()> {
+
+    if bytes.len() < HEADER_LEN {
+        // a journal that crashed before its header was written holds nothing
+        return if MAGIC.starts_with(&bytes[..bytes.len().min(MAGIC.len())]) {
+            Ok(0)
+        } else {
+            Err(invalid(DecodeError::BadMagic))
+        };
+    }
+
+    if &bytes[..MAGIC.len()] != MAGIC {
+        return Err(invalid(DecodeError::BadMagic));
+    }
+
+    let mut version = [0; 4];
+    version.copy_from_slice(&bytes[MAGIC.len()..HEADER_LEN]);
+    let version = u32::from_le_bytes(version);
+
+    if version != VERSION {
+        return Err(invalid(DecodeError::UnsupportedVersion(version)));
+    }
+
+    let mut at = HEADER_LEN;
+
+    while at < bytes.len() {
+        let rest = &bytes[at..];
+
+        // only the last record can have been cut short
+        if rest.len() < 8 {
+            break;
+        }
+
+        let (len, checksum) = record_header(rest);
+
+        if rest.len() - 8 < len {
+            // a length running past the end is a torn record, unless it
+            // was damaged and hides good records after it
+            if (9..rest.len()).any(|from| is_record(&rest[from..])) {
+                return Err(invalid(DecodeError::Corrupt("record length")));
+            }
+
+            break;
+        }
+
+        let payload = &rest[8..8 + len];
+
+        if codec::crc32(payload) != checksum {
+            if 8 + len == rest.len() {
+                break;
+            }
+
+            return Err(invalid(DecodeError::BadChecksum));
+        }
+
+        each(payload)?;
+        at += 8 + len;
+    }
+
+    Ok(at)
+}
+
+impl<T: Clone + Codec> Journal<T> {
+
+    /// Start a new journal at `path`, replacing any file already there.
+    pub fn create<P: AsRef<Path>>(path: P, policy: SyncPolicy) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        file.write_all(&header)?;
+
+        if policy != SyncPolicy::Never {
+            file.sync_all()?;
+        }
+
+        Ok(Journal { file, policy, unsynced: 0, values: PhantomData })
+    }
+
+    /// Carry on writing the journal at `path`, first dropping any partly
+    /// written record at its end.
+    pub fn append<P: AsRef<Path>>(path: P, policy: SyncPolicy) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).append(true).open(path.as_ref())?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let valid = scan(&bytes, |_| Ok(()))?;
+
+        if valid < HEADER_LEN {
+            return Self::create(path, policy);
+        }
+
+        file.set_len(valid as u64)?;
+
+        Ok(Journal { file, policy, unsynced: 0, values: PhantomData })
+    }
+
+    /// Apply the edits recorded at `path` to `base`, the rope the journal
+    /// was started from.
+    pub fn replay<P, M, V>(path: P, base: &Rope<T, M, V>) -> io::Result<Rope<T, M, V>>
+        where P: AsRef<Path>, M: Eq + Hash + Copy, V: Clone {
+
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+
+        let mut rope = base.clone();
+
+        scan(&bytes, |payload| {
+            rope = replay_record(payload, &rope).map_err(invalid)?;
+            Ok(())
+        })?;
+
+        Ok(rope)
+    }
+
+    /// Insert `values` at index `at` of `rope`, recording the edit.
+    pub fn insert<M, V>(&mut self, rope: &Rope<T, M, V>, at: usize, values: &[T]) -> io::Result<Rope<T, M, V>>
+        where M: Eq + Hash + Copy, V: Clone {
+
+        let edited = rope.insert(at, values);
+
+        let mut payload = vec![INSERT];
+        codec::write_varint(&mut payload, at as u64);
+        write_values(&mut payload, values);
+        self.write_record(&payload)?;
+
+        Ok(edited)
+    }
+
+    /// Remove `start..end` (`end` EXclusive) from `rope`, recording the edit.
+    pub fn remove<M, V>(&mut self, rope: &Rope<T, M, V>, start: usize, end: usize) -> io::Result<Rope<T, M, V>>
+        where M: Eq + Hash + Copy, V: Clone {
+
+        let edited = rope.remove(start, end);
+
+        let mut payload = vec![REMOVE];
+        codec::write_varint(&mut payload, start as u64);
+        codec::write_varint(&mut payload, end as u64);
+        self.write_record(&payload)?;
+
+        Ok(edited)
+    }
+
+    /// Replace `start..end` (`end` EXclusive) of `rope` with `values`,
+    /// recording the edit.
+    pub fn replace<M, V>(&mut self,
+                         rope: &Rope<T, M, V>,
+                         start: usize,
+                         end: usize,
+                         values: &[T]) -> io::Result<Rope<T, M, V>>
+        where M: Eq + Hash + Copy, V: Clone {
+
+        let edited = rope.replace(start, end, values);
+
+        let mut payload = vec![REPLACE];
+        codec::write_varint(&mut payload, start as u64);
+        codec::write_varint(&mut payload, end as u64);
+        write_values(&mut payload, values);
+        self.write_record(&payload)?;
+
+        Ok(edited)
+    }
+
+    fn write_record(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mut record = Vec::with_capacity(payload.len() + 8);
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&codec::crc32(payload).to_le_bytes());
+        record.extend_from_slice(payload);
+
+        self.file.write_all(&record)?;
+        self.wrote()
+    }
+
+    fn wrote(&mut self) -> io::Result<()> {
+        self.unsynced += 1;
+
+        match self.policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Every(n) if self.unsynced >= n => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Flush every record written so far to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.unsynced = 0;
+        self.file.sync_data()
+    }
+}
+
+/// Writes a slice as `Vec<T>` encodes.
+fn write_values<T: Codec>(out: &mut Vec<u8>, values: &[T]) {
+    codec::write_varint(out, values.len() as u64);
+    values.iter().for_each(|value| value.encode(out));
+}
+
+fn replay_record<T, M, V>(mut input: &[u8], rope: &Rope<T, M, V>) -> Result<Rope<T, M, V>, DecodeError>
+    where T: Clone + Codec, M: Eq + Hash + Copy, V: Clone {
+
+    let input = &mut input;
+    let tag = codec::read_byte(input)?;
+
+    let (start, end, values) = match tag {
+        INSERT => {
+            let at = codec::read_usize(input)?;
+            (at, at, Vec::decode(input)?)
+        },
+
+        REMOVE | REPLACE => {
+            let start = codec::read_usize(input)?;
+            let end = codec::read_usize(input)?;
+            let values = if tag == REPLACE { Vec::decode(input)? } else { Vec::new() };
+            (start, end, values)
+        },
+
+        _ => return Err(DecodeError::Corrupt("unknown edit")),
+    };
+
+    if !input.is_empty() {
+        return Err(DecodeError::Corrupt("trailing data"));
+    }
+
+    // the journal's edits were valid on the rope it was started from, so
+    // these mean it's being replayed on the wrong one
+    if start > end || end > rope.len() {
+        return Err(DecodeError::Corrupt("edit out of bounds"));
+    }
+
+    Ok(rope.replace(start, end, &values))
+}
@@ -49,11 +49,114 @@
 //! the 43rd line break in my text buffer" or "how many open parenthesis
 //! characters appear in my text buffer".
 //!
+//! Each marked index also carries a value (type parameter `V`, `()` by
+//! default), so markers can be used to attach e.g. diagnostic messages or
+//! token kinds to positions with `Chunk::annotate`, and looked up again with
+//! `Rope::nth_annotation`, `Rope::annotation_at` and `Rope::annotations`.
+//!
+//! Rather than marking values by hand with `Chunk::mark_at`, a `Classifier`
+//! can mark them automatically, and will go on marking values added by later
+//! edits:
+//!
+//! ```
+//! use persistent_rope::{Chunk, Classifier, Rope};
+//! let newlines: Classifier<char, ()> =
+//!     Classifier::new(|&c: &char| if c == '\n' { Some(()) } else { None });
+//!
+//! let mut chunk = Chunk::with_classifier(8, &newlines);
+//! chunk.extend_from_slice(&['a', '\n', 'b']);
+//!
+//! let rope = Rope::from_chunk(chunk).insert(3, &['\n', 'c']);
+//! assert_eq!(2, rope.marker_count(()));
+//! assert_eq!(Some(3), rope.index_for_nth_marker((), 1));
+//! ```
+//!
+//! ## Piece tables
+//!
+//! Leaves can also point into a buffer shared with other leaves rather than
+//! owning their values. `PieceTable` uses this to build a `Rope` over an
+//! original buffer and an append-only add buffer without copying either:
+//!
+//! ```
+//! use persistent_rope::PieceTable;
+//! let mut table: PieceTable<usize> = PieceTable::new(vec![1, 2, 3]);
+//! table.insert(1, &[7, 8]);
+//! table.remove(3, 4);
+//!
+//! let as_vec: Vec<usize> = table.rope().iter().cloned().collect();
+//! assert_eq!(vec![1, 7, 8, 3], as_vec);
+//! ```
+//!
+//! ## Changesets
+//!
+//! A `ChangeSet` describes an edit as a walk over the document, and can be
+//! applied, inverted, composed with later edits, and used to map positions
+//! from the old document to the new one:
+//!
+//! ```
+//! use persistent_rope::{Bias, ChangeSet, Rope};
+//! let rope: Rope<usize> = Rope::new(&[1, 2, 3]);
+//! let changes = ChangeSet::splice(3, 1, 2, &[7, 8]);
+//!
+//! let edited = changes.apply(&rope);
+//! let as_vec: Vec<usize> = edited.iter().cloned().collect();
+//! assert_eq!(vec![1, 7, 8, 3], as_vec);
+//! assert_eq!(3, changes.map_position(2, Bias::Right));
+//!
+//! let undone = changes.invert(&rope).apply(&edited);
+//! let as_vec: Vec<usize> = undone.iter().cloned().collect();
+//! assert_eq!(vec![1, 2, 3], as_vec);
+//! ```
+//!
+//! `History` records the versions produced by a sequence of changesets, for
+//! undo and redo, and `UndoTree` does the same without losing undone edits.
+//!
+//! ## Serialization
+//!
+//! With the `serde` feature, `Rope` and `Chunk` implement `Serialize` and
+//! `Deserialize`, as their values and markers, with the markers sorted so
+//! the output is always the same.
+//!
+//! `Rope::write_snapshot` writes a set of ropes, such as the versions in a
+//! `History`, in a compact binary format that keeps the nodes they share
+//! shared when they're read back with `Rope::read_snapshot`. Values,
+//! markers and marker values need to implement `Codec`.
+//!
+//! A `NodeStore` keeps nodes on disk by the hash of their contents, so
+//! saving each version of a document only writes the nodes that changed
+//! (and a version can be read back a node at a time, as a `LazyRope`),
+//! and a `Journal` records edits as they're made so they can be replayed
+//! after a crash.
+//!
+//! For ropes of bytes, `Rope::reader` gives a `Read`, `BufRead` and `Seek`
+//! serving the rope's leaves directly, and `Rope::write_to` writes a rope
+//! with one `IoSlice` per leaf. Going the other way, a `RopeBuilder` can
+//! be filled with `write!` and finished into a balanced rope, and
+//! `Rope::load_file` loads a text file in chunks, detecting its encoding.
+//!
+//! ## Threads
+//!
+//! Ropes share their nodes with `Rc`, so they can't be sent between
+//! threads. With the `sync` feature they use `Arc` instead (as do the
+//! buffers of a `PieceTable`), and `Rope::load_file_in_background` loads a
+//! file on a worker thread. Classifiers and load progress callbacks have to
+//! be `Send + Sync` (or just `Send`) either way, so turning the feature on
+//! never stops code that compiled without it from compiling. A
+//! `Document` holds the current version of a rope for one writer thread to
+//! publish new versions of and any number of readers to take snapshots of.
+//! Observers can subscribe to a document, with a callback or a channel, to
+//! be told what changed in each new version.
+//!
+//! The `rayon` feature (which turns on `sync`) adds `Rope::par_iter` and
+//! `Rope::par_chunks`, rayon parallel iterators that split the work where
+//! the rope's tree does, and `Rope::par_fold`. `Rope::par_from_slice`
+//! builds a rope from a slice in parallel, classifying each leaf on its own
+//! thread, and gives the same tree as building it sequentially.
+//!
 //! # TODO
 //!
 //! * Loading data could still be more space and time efficient, possibly
-//!   with the addition of lazy-loading nodes or nodes that point into a
-//!   shared slice.
+//!   with the addition of lazy-loading nodes.
 //!
 //! # Disclaimer
 //!
@@ -66,82 +169,306 @@
 #![cfg_attr(feature = "lint", feature(plugin))]
 #![cfg_attr(feature = "lint", plugin(clippy))]
 
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+
 use std::slice::Iter;
 use std::borrow::Borrow;
-use std::ops::Index;
-use std::rc::*;
-use std::cmp::{max};
+use std::ops::{Deref, Index};
+use std::iter::FromIterator;
+use std::cmp::{max, min};
 
 use std::hash::Hash;
 use std::collections::HashMap;
-use std::collections::BTreeSet;
-
-type Link<T, M> = Rc<Node<T, M>>;
-//type Markers<M> = BTreeMap<usize, HashSet<M>>;
-type Markers<M> = HashMap<M, BTreeSet<usize>>;
-
-enum Node<T, M> {
+use std::collections::BTreeMap;
+
+// Nodes are shared with `Rc`, or with the `sync` feature `Arc`, so ropes can
+// be sent between threads; everything else just says `Shared`.
+#[cfg(not(feature = "sync"))]
+use std::rc::{Rc as Shared, Weak};
+#[cfg(feature = "sync")]
+use std::sync::{Arc as Shared, Weak};
+
+mod anchors;
+#[cfg(feature = "sync")]
+mod background;
+mod builder;
+mod changeset;
+mod codec;
+mod crdt;
+mod diff;
+#[cfg(feature = "sync")]
+mod document;
+mod history;
+mod journal;
+mod load;
+mod merge;
+#[cfg(feature = "rayon")]
+mod parallel;
+mod piece_table;
+mod reader;
+#[cfg(feature = "serde")]
+mod serde_impls;
+mod snapshot;
+mod spans;
+mod store;
+mod undo_tree;
+
+pub use anchors::{AnchorId, Bias};
+#[cfg(feature = "sync")]
+pub use background::BackgroundLoad;
+pub use builder::RopeBuilder;
+pub use changeset::{ChangeSet, Hunk, Operation};
+pub use codec::{Codec, DecodeError};
+pub use crdt::{ElementId, Op, Replica};
+#[cfg(feature = "sync")]
+pub use document::{Change, ChangeReceiver, Document, ObserverId, Snapshot};
+pub use history::History;
+pub use journal::{Journal, SyncPolicy};
+pub use load::{Encoding, LoadError, LoadOptions, DEFAULT_CHUNK_SIZE};
+pub use merge::{Conflict, Merge};
+#[cfg(feature = "rayon")]
+pub use parallel::{ParChunks, ParValues};
+pub use piece_table::PieceTable;
+pub use reader::RopeReader;
+pub use spans::{Span, SpanId};
+pub use store::{LazyRope, NodeHash, NodeStore};
+pub use undo_tree::{Branch, RevisionId, UndoTree};
+
+use anchors::{AnchorEntry, AnchorSet, Cuts};
+use spans::SpanList;
+
+/// The number of values per leaf used by constructors that don't take a leaf
+/// size, e.g. `Rope::from_iter_balanced`.
+pub const DEFAULT_LEAF_SIZE: usize = 1024;
+
+type Link<T, M, V> = Shared<Node<T, M, V>>;
+type JoinFn<T, M, V> = fn(&Link<T, M, V>, &Link<T, M, V>) -> Link<T, M, V>;
+// `Send + Sync` whether or not ropes are, so that turning on `sync` doesn't
+// change which classifiers compile
+type ClassifyFn<T, M, V> = dyn Fn(&T, &mut dyn FnMut(M, V)) + Send + Sync;
+
+type Markers<M, V> = HashMap<M, BTreeMap<usize, V>>;
+
+enum Node<T, M, V> {
     Concat {
         depth: usize,
         left_len: usize,
         markers: HashMap<M, (usize, usize)>,
         len: usize,
-        left: Link<T, M>,
-        right: Link<T, M>,
+        left: Link<T, M, V>,
+        right: Link<T, M, V>,
+
+        // the spans crossing `left_len`, and the number in the whole subtree
+        spans: SpanList<M, V>,
+        span_count: usize,
+
+        // if there are spans on only one side and none crossing, the
+        // nearest node below that has spans of its own or on both sides,
+        // and its offset, so looking for spans can skip straight to it
+        spans_below: Option<(usize, Link<T, M, V>)>,
+
+        // every anchor in the subtree
+        anchors: AnchorSet,
     },
 
     Flat {
-        data: Vec<T>,
-        markers: Markers<M>,
+        data: Buffer<T>,
+        markers: Shared<Markers<M, V>>,
+        spans: SpanList<M, V>,
+        anchors: Shared<Vec<AnchorEntry>>,
     },
 }
 
 use Node::*;
 
-pub struct Rope<T, M = ()> {
-    root: Link<T, M>,
+/// The values stored in a `Flat` node: either a vector owned by the node
+/// itself, or a range of a buffer shared with other nodes (see `PieceTable`).
+/// Either way it's behind a reference-counted pointer, so that nodes can be rebuilt with e.g.
+/// different markers without copying their values.
+enum Buffer<T> {
+    Owned(Shared<Vec<T>>),
+    Shared {
+        buffer: Shared<Vec<T>>,
+        start: usize,
+        end: usize,
+    },
 }
 
-pub struct Values<'a, T: 'a, M: 'a + Eq + Hash> {
-    stack: Vec<&'a Link<T, M>>,
+impl<T> Clone for Buffer<T> {
+
+    fn clone(&self) -> Self {
+        match *self {
+            Buffer::Owned(ref data) => Buffer::Owned(data.clone()),
+            Buffer::Shared { ref buffer, start, end } => {
+                Buffer::Shared { buffer: buffer.clone(), start, end }
+            },
+        }
+    }
+}
+
+impl<T> Deref for Buffer<T> {
+
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match *self {
+            Buffer::Owned(ref data) => data,
+            Buffer::Shared { ref buffer, start, end } => &buffer[start..end],
+        }
+    }
+}
+
+pub struct Rope<T, M = (), V = ()> {
+    root: Link<T, M, V>,
+    classifier: Option<Classifier<T, M, V>>,
+}
+
+pub struct Values<'a, T: 'a, M: 'a + Eq + Hash, V: 'a> {
+    stack: Vec<&'a Link<T, M, V>>,
     flat_iter: Iter<'a, T>,
 }
 
 /// Used in the creation of new `Rope`s
-pub struct Chunk<T, M> {
+pub struct Chunk<T, M, V = ()> {
     data: Vec<T>,
-    markers: Markers<M>,
+    markers: Markers<M, V>,
+    classifier: Option<Classifier<T, M, V>>,
+}
+
+/// Marks values automatically as they're added to a `Rope`, e.g. marking
+/// every newline in a text buffer.
+///
+/// A rope remembers the classifier it was built with and runs it over any
+/// values that are later inserted or concatenated onto it, so its markers
+/// can't drift out of sync with its contents: a side of a `concat` that
+/// wasn't marked by the classifier the result ends up with is re-marked.
+/// Marking a leaf with a classifier replaces whatever markers it had before.
+pub struct Classifier<T, M, V = ()> {
+    classify: Shared<ClassifyFn<T, M, V>>,
+}
+
+impl<T, M, V: Default> Classifier<T, M, V> {
+
+    /// A classifier that gives each value at most one marker.
+    pub fn new<F>(classify: F) -> Self
+        where F: Fn(&T) -> Option<M> + Send + Sync + 'static {
+
+        Self::multi(move |value: &T, mark: &mut dyn FnMut(M)| {
+            if let Some(marker) = classify(value) {
+                mark(marker);
+            }
+        })
+    }
+
+    /// A classifier that can give each value any number of markers, by
+    /// calling `mark` once for each.
+    pub fn multi<F>(classify: F) -> Self
+        where F: Fn(&T, &mut dyn FnMut(M)) + Send + Sync + 'static {
+
+        Self::annotating(move |value: &T, annotate: &mut dyn FnMut(M, V)| {
+            classify(value, &mut |marker| annotate(marker, V::default()));
+        })
+    }
+}
+
+impl<T, M, V> Classifier<T, M, V> {
+
+    /// Like `multi`, but each marker carries a value, as with
+    /// `Chunk::annotate`.
+    pub fn annotating<F>(classify: F) -> Self
+        where F: Fn(&T, &mut dyn FnMut(M, V)) + Send + Sync + 'static {
+
+        Classifier { classify: Shared::new(classify) }
+    }
+
+    fn is(&self, other: &Self) -> bool {
+        Shared::ptr_eq(&self.classify, &other.classify)
+    }
+}
+
+impl<T, M: Eq + Hash + Copy, V> Classifier<T, M, V> {
+
+    fn mark(&self, offset: usize, values: &[T], markers: &mut Markers<M, V>) {
+        for (i, value) in values.iter().enumerate() {
+            (self.classify)(value, &mut |marker, annotation| {
+                markers.entry(marker).or_default().insert(offset + i, annotation);
+            });
+        }
+    }
+}
+
+impl<T, M, V> Clone for Classifier<T, M, V> {
+
+    fn clone(&self) -> Self {
+        Classifier { classify: self.classify.clone() }
+    }
 }
 
-impl<T: Clone, M: Eq + Hash + Copy> Chunk<T, M> {
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> Chunk<T, M, V> {
 
     pub fn with_capacity(capacity: usize) -> Self {
         Chunk {
             data: Vec::with_capacity(capacity),
             markers: HashMap::new(),
+            classifier: None,
+        }
+    }
+
+    /// A chunk that marks values with `classifier` as they're added. A
+    /// `Rope` made from it keeps using `classifier` for later edits.
+    pub fn with_classifier(capacity: usize, classifier: &Classifier<T, M, V>) -> Self {
+        Chunk {
+            data: Vec::with_capacity(capacity),
+            markers: HashMap::new(),
+            classifier: Some(classifier.clone()),
         }
     }
 
     pub fn push(&mut self, value: T) {
         self.data.push(value);
+        self.classify_from(self.data.len() - 1);
     }
 
     pub fn extend_from_slice(&mut self, slice: &[T]) {
+        let start = self.data.len();
         self.data.extend_from_slice(slice);
+        self.classify_from(start);
     }
 
-    pub fn mark_at(&mut self, marker: M, at: usize) {
+    fn classify_from(&mut self, start: usize) {
+        if let Some(ref classifier) = self.classifier {
+            classifier.mark(start, &self.data[start..], &mut self.markers);
+        }
+    }
+
+    /// Mark index `at` with `marker`, carrying `value`. Each index holds at
+    /// most one value per marker, so annotating the same index twice with
+    /// the same marker replaces the earlier value.
+    pub fn annotate(&mut self, at: usize, marker: M, value: V) {
         if at >= self.data.len() {
             panic!("attempted to mark outside data range");
         } else {
             self.markers.entry(marker)
-                        .or_insert(BTreeSet::new())
-                        .insert(at);
+                        .or_default()
+                        .insert(at, value);
         }
     }
 }
 
-impl<T: Clone, M: Eq + Hash + Copy> Node<T, M> {
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone + Default> Chunk<T, M, V> {
+
+    /// Mark index `at` with `marker`, carrying the default value.
+    pub fn mark_at(&mut self, marker: M, at: usize) {
+        self.annotate(at, marker, V::default());
+    }
+}
+
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> Node<T, M, V> {
 
     fn depth(&self) -> usize {
         match *self {
@@ -180,7 +507,33 @@ impl<T: Clone, M: Eq + Hash + Copy> Node<T, M> {
     }
 
     // TODO: Optimize for concatenating short subtrees -> Flat
-    fn concat(left: &Rc<Self>, right: &Rc<Self>) -> Rc<Self> {
+    fn concat(left: &Shared<Self>, right: &Shared<Self>) -> Shared<Self> {
+        Self::concat_spanning(left, right, SpanList::new(Vec::new()))
+    }
+
+    /// Concatenate, with `spans` (which must all cross the boundary between
+    /// `left` and `right`) stored in the new node.
+    fn concat_spanning(left: &Shared<Self>,
+                       right: &Shared<Self>,
+                       spans: SpanList<M, V>) -> Shared<Self> {
+
+        Shared::new(Concat {
+            depth: max(left.depth(), right.depth()) + 1,
+            left_len: left.len(),
+            markers: Self::concat_marker_counts(left, right),
+            len: left.len() + right.len(),
+            left: left.clone(),
+            right: right.clone(),
+            span_count: left.span_count() + right.span_count() + spans.len(),
+            spans_below: Self::spans_below(left, right, &spans),
+            spans,
+            anchors: Self::anchor_union(left, right),
+        })
+    }
+
+    /// The markers a `Concat` of `left` and `right` stores: how many of
+    /// each are in `left`, and how many in both.
+    fn concat_marker_counts(left: &Self, right: &Self) -> HashMap<M, (usize, usize)> {
         let mut counts: HashMap<M, (usize, usize)> =
             left.marker_counts()
                 .iter()
@@ -191,30 +544,50 @@ impl<T: Clone, M: Eq + Hash + Copy> Node<T, M> {
             counts.entry(marker).or_insert((0, 0)).1 += count;
         }
 
-        Rc::new(Concat {
-            depth: max(left.depth(), right.depth()) + 1,
-            left_len: left.len(),
-            markers: counts,
-            len: left.len() + right.len(),
-            left: left.clone(),
-            right: right.clone(),
-        })
+        counts
     }
 
-    fn slice(&self, start: usize, end: usize) -> Rc<Self> {
-        match *self {
-            Flat { ref data, ref markers } => {
-                // TODO: hopefully rust itself will panic on OOB indices here?
-                let mut slice = Vec::with_capacity(end - start);
-                slice.extend_from_slice(&data[start..end]);
+    /// Takes the node by reference-counted pointer so that slicing out a whole subtree can just
+    /// hand back a new reference to it rather than copying. `cuts` says which
+    /// ends of the slice are cuts through the rope, for the sake of anchors.
+    fn slice(node: &Shared<Self>, start: usize, end: usize, cuts: Cuts) -> Shared<Self> {
+        let whole = start == 0 && end == node.len();
+
+        if whole && (!node.has_anchors() || (!cuts.start && !cuts.end)) {
+            return node.clone();
+        }
+
+        match **node {
+            Flat { ref data, ref markers, ref spans, ref anchors } => {
+                let sliced_data = match *data {
+                    _ if whole => data.clone(),
+
+                    Buffer::Owned(ref values) => {
+                        let mut slice = Vec::with_capacity(end - start);
+                        slice.extend_from_slice(&values[start..end]);
+                        Buffer::Owned(Shared::new(slice))
+                    },
+
+                    // shared buffers are never copied, just narrowed
+                    Buffer::Shared { ref buffer, start: offset, .. } => {
+                        if end > data.len() {
+                            panic!("bad slice indices: {}, {}", start, end);
+                        }
+
+                        Buffer::Shared {
+                            buffer: buffer.clone(),
+                            start: offset + start,
+                            end: offset + end,
+                        }
+                    },
+                };
 
                 let mut new_markers = HashMap::new();
 
-                for (&marker, ref indices) in markers.iter() {
-                    let sliced_markers: BTreeSet<usize> =
-                        indices.iter()
-                               .cloned()
-                               .filter(|&i| i >= start && i < end)
+                for (&marker, indices) in markers.iter() {
+                    let sliced_markers: BTreeMap<usize, V> =
+                        indices.range(start..end)
+                               .map(|(&i, value)| (i - start, value.clone()))
                                .collect();
 
                     if !sliced_markers.is_empty() {
@@ -222,37 +595,191 @@ impl<T: Clone, M: Eq + Hash + Copy> Node<T, M> {
                     }
                 }
 
-                Rc::new(Flat { data: slice, markers: new_markers })
+                let sliced_anchors =
+                    anchors.iter()
+                           .filter(|entry| cuts.keeps(entry, start, end))
+                           .map(|entry| AnchorEntry { at: entry.at - start, ..*entry })
+                           .collect();
+
+                Shared::new(Flat {
+                    data: sliced_data,
+                    markers: Shared::new(new_markers),
+                    spans: SpanList::new(spans.clipped(start, end)),
+                    anchors: Shared::new(sliced_anchors),
+                })
             },
 
-            Concat { left_len, left: ref o_left, right: ref o_right, .. } => {
-                let do_left = start < left_len;
-                let do_right = end >= left_len;
+            Concat { left_len, ref left, ref right, ref spans, .. } => {
+                let crossing = spans.clipped(start, end);
+
+                // if we're slicing one side or the other, the spans crossing
+                // this node now belong further down, and the anchors at the
+                // edge of the other side are still in the slice
+                if end <= left_len {
+                    let left_sub = Self::slice(left, start, end, cuts);
+                    let edge = if end == left_len { right.anchors_at_edge(false) } else { Vec::new() };
+
+                    Self::add_spans(&Self::add_edge_anchors(&left_sub, end - start, edge, cuts), crossing)
+                } else if start >= left_len {
+                    let right_sub = Self::slice(right, start - left_len, end - left_len, cuts);
+                    let edge = if start == left_len { left.anchors_at_edge(true) } else { Vec::new() };
+
+                    Self::add_spans(&Self::add_edge_anchors(&right_sub, 0, edge, cuts), crossing)
 
                 // if the slice straddles this concat node
-                if do_left && do_right {
-                    let left = o_left.as_ref();
-                    let right = o_right.as_ref();
-
-                    let left_sub = left.slice(start, left_len);
-                    let right_sub = right.slice(0, end - left_len);
-
-                    Self::concat(&left_sub, &right_sub)
-                
-                // if we're sliceing one side or the other
-                } else if do_left {
-                    o_left.as_ref().slice(start, end)
-                } else if do_right {
-                    o_right.as_ref().slice(0, end - left_len)
-
-                // do people do this? I don't know
                 } else {
-                    panic!("should not have gotten here!")
+                    let left_cuts = Cuts { start: cuts.start, end: false };
+                    let right_cuts = Cuts { start: false, end: cuts.end };
+
+                    let left_sub = Self::slice(left, start, left_len, left_cuts);
+                    let right_sub = Self::slice(right, 0, end - left_len, right_cuts);
+
+                    Self::concat_spanning(&left_sub, &right_sub, SpanList::new(crossing))
                 }
             }
         }
     }
 
+    /// Add `edge`, the anchors on the edge of a sibling that touches the
+    /// slice `node` at `at`, if `cuts` lets them into the slice.
+    fn add_edge_anchors(node: &Shared<Self>,
+                        at: usize,
+                        edge: Vec<AnchorEntry>,
+                        cuts: Cuts) -> Shared<Self> {
+
+        let kept = edge.into_iter()
+                       .map(|entry| AnchorEntry { at, ..entry })
+                       .filter(|entry| cuts.keeps(entry, 0, node.len()))
+                       .collect();
+
+        Self::add_anchors(node, kept)
+    }
+
+    /// Like `concat`, but doesn't bother creating a `Concat` node when one of
+    /// the sides is empty (and has no anchors to keep), and keeps the tree
+    /// balanced so that edits don't make it any deeper than they must.
+    fn join(left: &Shared<Self>, right: &Shared<Self>) -> Shared<Self> {
+        if left.len() == 0 && !left.has_anchors() {
+            right.clone()
+        } else if right.len() == 0 && !right.has_anchors() {
+            left.clone()
+        } else {
+            Self::join_balanced(left, right)
+        }
+    }
+
+    /// Concatenate as an AVL tree joins: if one side is more than a level
+    /// deeper than the other, join the shallower one onto the near edge of
+    /// the deeper one, rotating nodes on the way back up. Spans stored in
+    /// the nodes taken apart are put back where they belong afterwards.
+    fn join_balanced(left: &Shared<Self>, right: &Shared<Self>) -> Shared<Self> {
+        if left.depth() > right.depth() + 1 {
+            if let Concat { left: ref outer, right: ref inner, .. } = **left {
+                let joined = Self::rotated(outer, &Self::join_balanced(inner, right));
+                return Self::add_spans(&joined, left.own_spans(0));
+            }
+        } else if right.depth() > left.depth() + 1 {
+            if let Concat { left: ref inner, right: ref outer, .. } = **right {
+                let joined = Self::rotated(&Self::join_balanced(left, inner), outer);
+                return Self::add_spans(&joined, right.own_spans(left.len()));
+            }
+        }
+
+        Self::concat(left, right)
+    }
+
+    /// Concatenate two balanced nodes whose depths differ by at most two,
+    /// rotating once or twice if they differ by two.
+    fn rotated(left: &Shared<Self>, right: &Shared<Self>) -> Shared<Self> {
+        if left.depth() > right.depth() + 1 {
+            if let Concat { left: ref a, right: ref b, .. } = **left {
+                let mut spans = left.own_spans(0);
+
+                let rotated = match **b {
+                    Concat { left: ref b1, right: ref b2, .. } if b.depth() > a.depth() => {
+                        spans.extend(b.own_spans(a.len()));
+                        Self::concat(&Self::concat(a, b1), &Self::concat(b2, right))
+                    },
+                    _ => Self::concat(a, &Self::concat(b, right)),
+                };
+
+                return Self::add_spans(&rotated, spans);
+            }
+        } else if right.depth() > left.depth() + 1 {
+            if let Concat { left: ref a, right: ref b, .. } = **right {
+                let mut spans = right.own_spans(left.len());
+
+                let rotated = match **a {
+                    Concat { left: ref a1, right: ref a2, .. } if a.depth() > b.depth() => {
+                        spans.extend(a.own_spans(left.len()));
+                        Self::concat(&Self::concat(left, a1), &Self::concat(a2, b))
+                    },
+                    _ => Self::concat(&Self::concat(left, a), b),
+                };
+
+                return Self::add_spans(&rotated, spans);
+            }
+        }
+
+        Self::concat(left, right)
+    }
+
+    /// Build a tree of minimal depth over a nonempty run of nodes.
+    fn balanced(nodes: &[Shared<Self>]) -> Shared<Self> {
+        if nodes.len() == 1 {
+            nodes[0].clone()
+        } else {
+            let (left, right) = nodes.split_at(nodes.len().div_ceil(2));
+            Self::concat(&Self::balanced(left), &Self::balanced(right))
+        }
+    }
+
+    /// Re-mark every leaf under this node with `classifier`.
+    fn classify(&self, classifier: &Classifier<T, M, V>) -> Shared<Self> {
+        match *self {
+            Flat { ref data, ref spans, ref anchors, .. } => {
+                let mut markers = HashMap::new();
+                classifier.mark(0, data, &mut markers);
+
+                Shared::new(Flat {
+                    data: data.clone(),
+                    markers: Shared::new(markers),
+                    spans: spans.clone(),
+                    anchors: anchors.clone(),
+                })
+            },
+
+            Concat { ref left, ref right, ref spans, .. } => {
+                Self::concat_spanning(&left.classify(classifier),
+                                      &right.classify(classifier),
+                                      spans.clone())
+            },
+        }
+    }
+
+    /// Copy the contents and markers of this node onto the end of `chunk`.
+    fn flatten_into(&self, chunk: &mut Chunk<T, M, V>) {
+        match *self {
+            Flat { ref data, ref markers, .. } => {
+                let offset = chunk.data.len();
+                chunk.extend_from_slice(data);
+
+                for (&marker, indices) in markers.iter() {
+                    chunk.markers.entry(marker)
+                                 .or_default()
+                                 .extend(indices.iter().map(|(&i, value)| {
+                                     (offset + i, value.clone())
+                                 }));
+                }
+            },
+
+            Concat { ref left, ref right, .. } => {
+                left.flatten_into(chunk);
+                right.flatten_into(chunk);
+            },
+        }
+    }
+
     fn at(&self, index: usize) -> &T {
         if index >= self.len() {
             panic!("index exceeds bounds (length {:?}, index {:?})", self.len(), index)
@@ -274,14 +801,14 @@ impl<T: Clone, M: Eq + Hash + Copy> Node<T, M> {
     }
 
     /// Find the index in the rope which has been marked with the `n`th
-    /// instance of `marker`. Useful for e.g. finding the index of the `n`th
-    /// newline.
-    fn index_for_nth_marker(&self, marker: M, n: usize) -> Option<usize> {
+    /// instance of `marker`, and the value it was marked with. Useful for
+    /// e.g. finding the index of the `n`th newline.
+    fn nth_marker(&self, marker: M, n: usize) -> Option<(usize, &V)> {
         match *self {
             Flat { ref markers, .. } => {
                 match markers.get(&marker) {
                     None => None,
-                    Some(indices) => indices.iter().nth(n).map(|&i| i)
+                    Some(indices) => indices.iter().nth(n).map(|(&i, value)| (i, value))
                 }
             },
 
@@ -290,10 +817,10 @@ impl<T: Clone, M: Eq + Hash + Copy> Node<T, M> {
                     None => None,
                     Some(&(left_count, count)) => {
                         if n < left_count {
-                            left.index_for_nth_marker(marker, n)
+                            left.nth_marker(marker, n)
                         } else if n < count {
-                            right.index_for_nth_marker(marker, n - left_count)
-                                 .map(|i| left_len + i)
+                            right.nth_marker(marker, n - left_count)
+                                 .map(|(i, value)| (left_len + i, value))
                         } else {
                             None
                         }
@@ -303,25 +830,113 @@ impl<T: Clone, M: Eq + Hash + Copy> Node<T, M> {
         }
     }
 
+    fn marker_at(&self, marker: M, index: usize) -> Option<&V> {
+        match *self {
+            Flat { ref markers, .. } => {
+                markers.get(&marker).and_then(|indices| indices.get(&index))
+            },
+
+            Concat { ref markers, ref left, ref right, left_len, .. } => {
+                if !markers.contains_key(&marker) {
+                    None
+                } else if index < left_len {
+                    left.marker_at(marker, index)
+                } else {
+                    right.marker_at(marker, index - left_len)
+                }
+            }
+        }
+    }
+
+    /// Push every instance of `marker` in `start..end` onto `found`, with
+    /// indices offset by `offset`. Subtrees without the marker are skipped.
+    fn markers_in<'a>(&'a self,
+                      marker: M,
+                      start: usize,
+                      end: usize,
+                      offset: usize,
+                      found: &mut Vec<(usize, &'a V)>) {
+        match *self {
+            Flat { ref markers, .. } => {
+                if let Some(indices) = markers.get(&marker) {
+                    found.extend(indices.range(start..end)
+                                        .map(|(&i, value)| (offset + i, value)));
+                }
+            },
+
+            Concat { ref markers, ref left, ref right, left_len, .. } => {
+                if !markers.contains_key(&marker) {
+                    return;
+                }
+
+                if start < left_len {
+                    left.markers_in(marker, start, min(end, left_len), offset, found);
+                }
+
+                if end > left_len {
+                    right.markers_in(marker,
+                                     start.saturating_sub(left_len),
+                                     end - left_len,
+                                     offset + left_len,
+                                     found);
+                }
+            }
+        }
+    }
+
 }
 
-impl<T: Clone, M: Eq + Hash + Copy> Rope<T, M> {
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> Rope<T, M, V> {
 
     pub fn new(data: &[T]) -> Self {
         let mut data_vec = Vec::with_capacity(data.len());
-        data_vec.extend_from_slice(&data);
+        data_vec.extend_from_slice(data);
 
-        Rope { root: Rc::new( Flat {
-            data: data_vec,
-            markers: HashMap::new(),
-        })}
+        Rope {
+            root: Shared::new(Flat {
+                data: Buffer::Owned(Shared::new(data_vec)),
+                markers: Shared::new(HashMap::new()),
+                spans: SpanList::new(Vec::new()),
+                anchors: Shared::new(Vec::new()),
+            }),
+            classifier: None,
+        }
     }
 
-    pub fn from_chunk(chunk: Chunk<T, M>) -> Self {
-        Rope { root: Rc::new(Flat {
-            data: chunk.data,
-            markers: chunk.markers,
-        })}
+    /// If the chunk was created `with_classifier`, the rope will keep using
+    /// that classifier for any values added to it later.
+    pub fn from_chunk(chunk: Chunk<T, M, V>) -> Self {
+        Rope {
+            root: Shared::new(Flat {
+                data: Buffer::Owned(Shared::new(chunk.data)),
+                markers: Shared::new(chunk.markers),
+                spans: SpanList::new(Vec::new()),
+                anchors: Shared::new(Vec::new()),
+            }),
+            classifier: chunk.classifier,
+        }
+    }
+
+    /// Create a flat `Rope` viewing `buffer[start..end]` without copying it.
+    /// `markers` are relative to `start`.
+    fn from_shared(buffer: &Shared<Vec<T>>,
+                   start: usize,
+                   end: usize,
+                   markers: Markers<M, V>) -> Self {
+
+        if start > end || end > buffer.len() {
+            panic!("bad buffer range: {}, {}", start, end);
+        }
+
+        Rope {
+            root: Shared::new(Flat {
+                data: Buffer::Shared { buffer: buffer.clone(), start, end },
+                markers: Shared::new(markers),
+                spans: SpanList::new(Vec::new()),
+                anchors: Shared::new(Vec::new()),
+            }),
+            classifier: None,
+        }
     }
 
     /// The nodes in the rope are all immutable, so creating a new rope is
@@ -335,40 +950,147 @@ impl<T: Clone, M: Eq + Hash + Copy> Rope<T, M> {
     /// indicates e.g. an IO or decoding error. In the latter case, that error
     /// is returned from this method call as `Err(some_error)`.
     ///
+    /// Each chunk becomes one leaf of a perfectly balanced tree. A loader
+    /// that returns `Ok(None)` straight away produces an empty rope.
+    ///
     /// We don't put any restrictions on the size of chunks, but clients are
     /// encouraged to use the `Chunk::with_capacity(capacity)` initializer,
     /// where `capacity` is the maximum number of items expected per-chunk,
     /// to avoid continuous reallocations.
     pub fn from_chunks<F, E>(mut loader: F) -> Result<Self, E>
-        where F: FnMut() -> Result<Option<Chunk<T, M>>, E> {
+        where F: FnMut() -> Result<Option<Chunk<T, M, V>>, E> {
 
-        let mut stack = Vec::new();
+        let mut leaves = Vec::new();
 
-        'outer: loop {
-            match loader() {
-                Err(e) => return Err(e),
-                Ok(None) => break 'outer,
+        while let Some(chunk) = loader()? {
+            leaves.push(Self::from_chunk(chunk));
+        }
 
-                Ok(Some(chunk)) => {
-                    stack.push(Self::from_chunk(chunk));
+        Ok(Self::from_leaves(leaves))
+    }
 
-                    while stack.len() > 1 &&
-                        stack[stack.len() - 1].depth() == stack[stack.len() - 2].depth() {
+    /// Like `from_chunks`, but for when the chunks are available from an
+    /// iterator and loading them can't fail.
+    pub fn from_chunk_iter<I>(chunks: I) -> Self
+        where I: IntoIterator<Item = Chunk<T, M, V>> {
 
-                        let right = stack.pop().unwrap();
-                        let left = stack.pop().unwrap();
-                        stack.push(Self::concat(&left, &right));
-                    }
-                } // end match OK
+        Self::from_leaves(chunks.into_iter()
+                                .map(Self::from_chunk)
+                                .collect())
+    }
+
+    /// Copy `data` into leaves of at most `leaf_size` values each.
+    pub fn from_slice_with_leaf_size(data: &[T], leaf_size: usize) -> Self {
+        if leaf_size == 0 {
+            panic!("leaf size must be greater than zero");
+        }
+
+        Self::from_chunk_iter(data.chunks(leaf_size).map(|values| {
+            let mut chunk = Chunk::with_capacity(values.len());
+            chunk.extend_from_slice(values);
+            chunk
+        }))
+    }
+
+    /// Collect `values` into leaves of `DEFAULT_LEAF_SIZE` values each.
+    pub fn from_iter_balanced<I>(values: I) -> Self
+        where I: IntoIterator<Item = T> {
+
+        let mut values = values.into_iter();
+        let mut leaves = Vec::new();
+
+        loop {
+            let mut chunk = Chunk::with_capacity(DEFAULT_LEAF_SIZE);
+
+            for value in values.by_ref().take(DEFAULT_LEAF_SIZE) {
+                chunk.push(value);
+            }
+
+            if chunk.data.is_empty() {
+                break;
             }
-        } // end 'outer
 
-        let init = stack.pop().unwrap();
-        let rope = stack.into_iter()
-                        .rev()
-                        .fold(init, |right, left| Rope::concat(&left, &right));
+            leaves.push(Self::from_chunk(chunk));
+        }
+
+        Self::from_leaves(leaves)
+    }
+
+    /// Assemble a perfectly balanced rope from flat ropes, in order. Empty
+    /// ones are dropped, and none at all makes an empty rope. Like `concat`,
+    /// the first classifier found is applied to the whole result.
+    fn from_leaves(leaves: Vec<Self>) -> Self {
+        let classifier = leaves.iter()
+                               .filter_map(|leaf| leaf.classifier.clone())
+                               .next();
+
+        let roots: Vec<Link<T, M, V>> =
+            leaves.iter()
+                  .filter(|leaf| !leaf.is_empty())
+                  .map(|leaf| leaf.root_classified_by(&classifier))
+                  .collect();
+
+        if roots.is_empty() {
+            Rope { classifier, ..Self::new(&[]) }
+        } else {
+            Rope { root: Node::balanced(&roots), classifier }
+        }
+    }
+
+    /// The classifier this rope marks new values with, if any.
+    pub fn classifier(&self) -> Option<&Classifier<T, M, V>> {
+        self.classifier.as_ref()
+    }
+
+    /// A copy of this rope re-marked by `classifier`, which will also be used
+    /// to mark any values added to it later.
+    pub fn classified(&self, classifier: &Classifier<T, M, V>) -> Self {
+        Rope {
+            root: self.root.classify(classifier),
+            classifier: Some(classifier.clone()),
+        }
+    }
+
+    /// The root of this rope, re-marked by `classifier` unless it's already
+    /// been marked by it.
+    fn root_classified_by(&self, classifier: &Option<Classifier<T, M, V>>) -> Link<T, M, V> {
+        match (classifier.as_ref(), self.classifier.as_ref()) {
+            (Some(wanted), Some(current)) if wanted.is(current) => {
+                self.root.clone()
+            },
+            (Some(wanted), _) => self.root.classify(wanted),
+            (None, _) => self.root.clone(),
+        }
+    }
+
+    /// Combine two ropes with `join_nodes`, using the left rope's classifier,
+    /// or failing that the right's, to mark both sides.
+    fn combine(left: &Self,
+               right: &Self,
+               join_nodes: JoinFn<T, M, V>) -> Self {
+
+        let classifier = left.classifier.clone()
+                                        .or_else(|| right.classifier.clone());
+
+        Rope {
+            root: join_nodes(&left.root_classified_by(&classifier),
+                             &right.root_classified_by(&classifier)),
+            classifier,
+        }
+    }
+
+    /// Slice without the checks done by `slice`, allowing empty results.
+    fn sub_rope(&self, start: usize, end: usize) -> Self {
+        self.cut(start, end, Cuts { start: start > 0, end: end < self.len() })
+    }
+
+    fn cut(&self, start: usize, end: usize, cuts: Cuts) -> Self {
+        self.with_root(Node::slice(&self.root, start, end, cuts))
+    }
 
-        Ok(rope)
+    /// A rope with the same classifier as this one.
+    fn with_root(&self, root: Link<T, M, V>) -> Self {
+        Rope { root, classifier: self.classifier.clone() }
     }
 
     pub fn len(&self) -> usize {
@@ -394,10 +1116,11 @@ impl<T: Clone, M: Eq + Hash + Copy> Rope<T, M> {
         }
     }
 
+    /// If only one of the ropes has a classifier, or they have different
+    /// ones, the other side is re-marked so that the whole result matches
+    /// the classifier it ends up with (the left rope's, if it has one).
     pub fn concat(left: &Self, right: &Self) -> Self {
-        Rope {
-            root: Node::concat(&left.root, &right.root),
-        }
+        Self::combine(left, right, Node::concat)
     }
 
     /// `start` is inclusive, `end` is EXclusive.
@@ -406,22 +1129,112 @@ impl<T: Clone, M: Eq + Hash + Copy> Rope<T, M> {
             panic!("bad slice indices: {}, {}", start, end);
         }
 
-        Rope {
-            root: self.root.slice(start, end),
+        self.sub_rope(start, end)
+    }
+
+    /// Replace the range `start..end` (`end` EXclusive) with the contents of
+    /// `replacement`, returning the new `Rope`. Either the range or the
+    /// replacement may be empty. The replacement is classified as it would
+    /// be by `concat`, and spans and anchors are adjusted as described in
+    /// the `spans` and `anchors` modules.
+    pub fn splice(&self, start: usize, end: usize, replacement: &Self) -> Self {
+        if start > end || end > self.len() {
+            panic!("bad splice indices: {}, {}", start, end);
         }
+
+        let spliced = self.splice_spans(start, end, replacement.len(), |rope| {
+            // we're cutting the rope at `start` and `end` even if they're
+            // at its ends, so anchors there are split between the sides
+            let before = rope.cut(0, start, Cuts { start: false, end: true });
+            let after = rope.cut(end, rope.len(), Cuts { start: true, end: false });
+            let joined = Self::combine(&before, replacement, Node::join);
+
+            Self::combine(&joined, &after, Node::join)
+        });
+
+        self.splice_anchors(start, end, replacement.len(), spliced)
+    }
+
+    pub fn insert(&self, at: usize, values: &[T]) -> Self {
+        self.splice(at, at, &Rope::new(values))
+    }
+
+    /// `start` is inclusive, `end` is EXclusive.
+    pub fn remove(&self, start: usize, end: usize) -> Self {
+        self.splice(start, end, &Rope::new(&[]))
+    }
+
+    pub fn replace(&self, start: usize, end: usize, values: &[T]) -> Self {
+        self.splice(start, end, &Rope::new(values))
+    }
+
+    /// Copy the whole rope into a single contiguous `Flat` leaf, keeping its
+    /// markers, spans and anchors. This also drops any references the rope holds into
+    /// shared buffers, e.g. those of a `PieceTable`.
+    pub fn flatten(&self) -> Self {
+        let mut chunk = Chunk::with_capacity(self.len());
+        self.root.flatten_into(&mut chunk);
+
+        let flat = Self::from_chunk(chunk);
+        let spans = self.spans_overlapping(0, self.len());
+
+        let mut anchors = Vec::new();
+        self.root.anchors_in(0, self.len(), 0, &mut anchors);
+
+        self.with_root(Node::add_anchors(&Node::add_spans(&flat.root, spans), anchors))
     }
 
     pub fn index_for_nth_marker(&self, marker: M, n: usize) -> Option<usize> {
-        self.root.index_for_nth_marker(marker, n)
+        self.root.nth_marker(marker, n).map(|(i, _)| i)
+    }
+
+    /// Like `index_for_nth_marker`, but also returns the marker's value.
+    pub fn nth_annotation(&self, marker: M, n: usize) -> Option<(usize, &V)> {
+        self.root.nth_marker(marker, n)
     }
 
-    pub fn iter(&self) -> Values<T, M> {
+    /// The value `index` is marked with by `marker`, if it is marked.
+    pub fn annotation_at(&self, marker: M, index: usize) -> Option<&V> {
+        self.root.marker_at(marker, index)
+    }
+
+    /// Every index in `start..end` marked with `marker`, in order, along with
+    /// the marker's value.
+    pub fn annotations(&self, marker: M, start: usize, end: usize) -> Vec<(usize, &V)> {
+        let mut found = Vec::new();
+        let end = min(end, self.len());
+
+        if start < end {
+            self.root.markers_in(marker, start, end, 0, &mut found);
+        }
+
+        found
+    }
+
+    pub fn iter(&self) -> Values<'_, T, M, V> {
         Values::new(&self.root)
     }
 
 }
 
-impl<T: Clone, M: Eq + Hash + Copy> Index<usize> for Rope<T, M> {
+impl<T, M, V> Clone for Rope<T, M, V> {
+
+    fn clone(&self) -> Self {
+        Rope {
+            root: self.root.clone(),
+            classifier: self.classifier.clone(),
+        }
+    }
+}
+
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> FromIterator<T> for Rope<T, M, V> {
+
+    fn from_iter<I: IntoIterator<Item = T>>(values: I) -> Self {
+        Self::from_iter_balanced(values)
+    }
+}
+
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> Index<usize> for Rope<T, M, V> {
 
     type Output = T;
 
@@ -430,16 +1243,16 @@ impl<T: Clone, M: Eq + Hash + Copy> Index<usize> for Rope<T, M> {
     }
 }
 
-impl<'a, T: Clone, M: Eq + Hash + Copy> Values<'a, T, M> {
+impl<'a, T: Clone, M: Eq + Hash + Copy, V: Clone> Values<'a, T, M, V> {
 
-    fn new(mut ptr: &'a Link<T, M>) -> Self {
-        let mut stack: Vec<&'a Link<T, M>> = Vec::with_capacity(ptr.depth());
+    fn new(mut ptr: &'a Link<T, M, V>) -> Self {
+        let mut stack: Vec<&'a Link<T, M, V>> = Vec::with_capacity(ptr.depth());
 
         loop {
             match *ptr.borrow() {
                 Flat { ref data, .. } => {
                     return Values {
-                        stack: stack,
+                        stack,
                         flat_iter: data.iter(),
                     };
                 },
@@ -453,7 +1266,7 @@ impl<'a, T: Clone, M: Eq + Hash + Copy> Values<'a, T, M> {
     }
 }
 
-impl<'a, T: Clone, M: Eq + Hash + Copy> Iterator for Values<'a, T, M> {
+impl<'a, T: Clone, M: Eq + Hash + Copy, V: Clone> Iterator for Values<'a, T, M, V> {
 
     type Item = &'a T;
 
@@ -507,15 +1320,17 @@ impl<'a, T: Clone, M: Eq + Hash + Copy> Iterator for Values<'a, T, M> {
     }
 }
 
-impl<'a, T: Clone, M: Eq + Hash + Copy> IntoIterator for &'a Rope<T, M> {
+impl<'a, T: Clone, M: Eq + Hash + Copy, V: Clone> IntoIterator for &'a Rope<T, M, V> {
 
     type Item = &'a T;
-    type IntoIter = Values<'a, T, M>;
+    type IntoIter = Values<'a, T, M, V>;
 
     fn into_iter(self) -> Self::IntoIter {
         self.iter()
     }
 }
 
+// the oldest tests predate clippy's `useless_vec` and `unnecessary_operation`
 #[cfg(test)]
+#[allow(clippy::useless_vec, clippy::unnecessary_operation)]
 mod tests;
@@ -0,0 +1,373 @@
+//!
+//! Loading text files into ropes of chars, a chunk at a time.
+//!
+//! `Rope::load_file` reads a file in fixed-size chunks and decodes each one
+//! into a leaf with `Rope::from_chunks`, so a file is never held in memory
+//! twice. The encoding is taken from a byte order mark if there is one, and
+//! otherwise guessed from the first chunk: UTF-16 if it's mostly ASCII with
+//! zero bytes in between, UTF-8 if it's valid UTF-8, and Latin-1 if not.
+//! Bytes that turn out to be invalid later on are decoded as U+FFFD, and a
+//! multibyte sequence split between two chunks is carried over whole into
+//! the second.
+//!
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str;
+
+use super::{Chunk, Classifier, Rope};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+impl Encoding {
+
+    /// Guess the encoding of text starting with `bytes`, returning it and
+    /// the length of its byte order mark (if any).
+    pub fn detect(bytes: &[u8]) -> (Encoding, usize) {
+        if bytes.starts_with(&[0xef, 0xbb, 0xbf]) {
+            return (Encoding::Utf8, 3);
+        } else if bytes.starts_with(&[0xff, 0xfe]) {
+            return (Encoding::Utf16Le, 2);
+        } else if bytes.starts_with(&[0xfe, 0xff]) {
+            return (Encoding::Utf16Be, 2);
+        }
+
+        // ASCII text in UTF-16 has a zero in every other byte
+        let pairs = bytes.len() / 2;
+        let zeros_at = |parity: usize| bytes.iter().skip(parity).step_by(2).filter(|&&b| b == 0).count();
+
+        if pairs > 0 {
+            let (even, odd) = (zeros_at(0), zeros_at(1));
+
+            if odd * 2 > pairs && even * 8 < pairs {
+                return (Encoding::Utf16Le, 0);
+            } else if even * 2 > pairs && odd * 8 < pairs {
+                return (Encoding::Utf16Be, 0);
+            }
+        }
+
+        let utf8 = match str::from_utf8(bytes) {
+            Ok(_) => true,
+            // only incomplete at the end, where the chunk was cut off
+            Err(error) => error.error_len().is_none(),
+        };
+
+        (if utf8 { Encoding::Utf8 } else { Encoding::Latin1 }, 0)
+    }
+
+    /// Decode as much of `bytes` as possible onto `out`, returning how many
+    /// bytes were used. Unless `last`, an incomplete sequence at the end is
+    /// left for the next call.
+    fn decode(self, bytes: &[u8], last: bool, out: &mut Vec<char>) -> usize {
+        match self {
+            Encoding::Latin1 => {
+                out.extend(bytes.iter().map(|&b| b as char));
+                bytes.len()
+            },
+
+            Encoding::Utf8 => {
+                let mut at = 0;
+
+                while at < bytes.len() {
+                    match str::from_utf8(&bytes[at..]) {
+                        Ok(text) => {
+                            out.extend(text.chars());
+                            at = bytes.len();
+                        },
+
+                        Err(error) => {
+                            let valid = at + error.valid_up_to();
+                            out.extend(str::from_utf8(&bytes[at..valid]).unwrap().chars());
+
+                            match error.error_len() {
+                                Some(len) => {
+                                    out.push('\u{fffd}');
+                                    at = valid + len;
+                                },
+                                None if last => {
+                                    out.push('\u{fffd}');
+                                    at = bytes.len();
+                                },
+                                None => return valid,
+                            }
+                        },
+                    }
+                }
+
+                at
+            },
+
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                let unit = |pair: &[u8]| if self == Encoding::Utf16Le {
+                    u16::from_le_bytes([pair[0], pair[1]])
+                } else {
+                    u16::from_be_bytes([pair[0], pair[1]])
+                };
+
+                let mut units: Vec<u16> = bytes.chunks_exact(2).map(unit).collect();
+                let mut used = units.len() * 2;
+
+                // keep a high surrogate for the low one in the next chunk
+                if !last && units.last().is_some_and(|&u| (0xd800..0xdc00).contains(&u)) {
+                    units.pop();
+                    used -= 2;
+                }
+
+                out.extend(::std::char::decode_utf16(units).map(|c| c.unwrap_or('\u{fffd}')));
+
+                if last && used < bytes.len() {
+                    out.push('\u{fffd}');
+                    used = bytes.len();
+                }
+
+                used
+            },
+        }
+    }
+}
+
+/// Why `Rope::load_file` didn't load a whole file.
+pub enum LoadError<M = (), V = ()> {
+    Io(io::Error),
+
+    /// The progress callback cancelled the load, and this is what had been
+    /// loaded so far.
+    Cancelled(Rope<char, M, V>),
+
+    /// The classifier or progress callback panicked while loading in the
+    /// background, with this message.
+    Panicked(String),
+}
+
+impl<M, V> fmt::Debug for LoadError<M, V> {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Io(ref error) => f.debug_tuple("Io").field(error).finish(),
+            LoadError::Cancelled(_) => f.write_str("Cancelled(..)"),
+            LoadError::Panicked(ref message) => f.debug_tuple("Panicked").field(message).finish(),
+        }
+    }
+}
+
+impl<M, V> fmt::Display for LoadError<M, V> {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Io(ref error) => write!(f, "{}", error),
+            LoadError::Cancelled(_) => f.write_str("loading was cancelled"),
+            LoadError::Panicked(ref message) => write!(f, "loading panicked: {}", message),
+        }
+    }
+}
+
+impl<M, V> Error for LoadError<M, V> {}
+
+impl<M, V> From<io::Error> for LoadError<M, V> {
+
+    fn from(error: io::Error) -> Self {
+        LoadError::Io(error)
+    }
+}
+
+// `Send` so the options can go to a background load's worker thread
+type Progress<'a> = Box<dyn FnMut(u64, Option<u64>) -> bool + Send + 'a>;
+
+pub struct LoadOptions<'a, M = (), V = ()> {
+    chunk_size: usize,
+    encoding: Option<Encoding>,
+    classifier: Option<Classifier<char, M, V>>,
+    progress: Option<Progress<'a>>,
+}
+
+impl<'a, M: Eq + Hash + Copy, V: Clone> LoadOptions<'a, M, V> {
+
+    /// Read `DEFAULT_CHUNK_SIZE` bytes at a time, guess the encoding, and
+    /// don't mark anything.
+    pub fn new() -> Self {
+        LoadOptions { chunk_size: DEFAULT_CHUNK_SIZE, encoding: None, classifier: None, progress: None }
+    }
+
+    /// Read `size` bytes at a time; each chunk becomes one leaf.
+    pub fn chunk_size(mut self, size: usize) -> Self {
+        if size == 0 {
+            panic!("chunk size must be greater than zero");
+        }
+
+        self.chunk_size = size;
+        self
+    }
+
+    /// Decode as `encoding` rather than guessing. A byte order mark is still
+    /// skipped if it matches.
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = Some(encoding);
+        self
+    }
+
+    /// Mark the loaded text with `classifier`, which the rope keeps using.
+    pub fn classifier(mut self, classifier: &Classifier<char, M, V>) -> Self {
+        self.classifier = Some(classifier.clone());
+        self
+    }
+
+    /// Call `progress` after each chunk with the number of bytes read so far
+    /// and the total, if known. Returning `false` cancels the load.
+    pub fn progress<F>(mut self, progress: F) -> Self
+        where F: FnMut(u64, Option<u64>) -> bool + Send + 'a {
+
+        self.progress = Some(Box::new(progress));
+        self
+    }
+}
+
+impl<'a, M: Eq + Hash + Copy + Send + Sync + 'static, V: Clone + Default> LoadOptions<'a, M, V> {
+
+    /// Mark every `'\n'` with `marker`.
+    pub fn newlines(self, marker: M) -> Self {
+        self.classifier(&Classifier::new(move |&c: &char| if c == '\n' { Some(marker) } else { None }))
+    }
+}
+
+impl<'a, M: Eq + Hash + Copy, V: Clone> Default for LoadOptions<'a, M, V> {
+
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads and decodes text a chunk at a time, as set up by `LoadOptions`.
+pub struct ChunkReader<'a, R, M, V> {
+    reader: R,
+    total: Option<u64>,
+    options: LoadOptions<'a, M, V>,
+
+    encoding: Option<Encoding>,
+    // whether the byte order mark has been looked for
+    detected: bool,
+    // bytes read but not yet decoded, e.g. half a multibyte sequence
+    bytes: Vec<u8>,
+    read: u64,
+    done: bool,
+    cancelled: bool,
+}
+
+impl<'a, R: Read, M: Eq + Hash + Copy, V: Clone> ChunkReader<'a, R, M, V> {
+
+    pub fn new(reader: R, total: Option<u64>, options: LoadOptions<'a, M, V>) -> Self {
+        ChunkReader {
+            reader,
+            total,
+            encoding: options.encoding,
+            detected: false,
+            options,
+            bytes: Vec::new(),
+            read: 0,
+            done: false,
+            cancelled: false,
+        }
+    }
+
+    /// Settle on an encoding for the bytes read so far, returning the length
+    /// of the byte order mark to skip.
+    fn detect(&mut self) -> usize {
+        let (found, bom) = Encoding::detect(&self.bytes);
+        self.detected = true;
+
+        match self.encoding {
+            Some(known) if known != found => 0,
+            Some(_) => bom,
+            None => {
+                self.encoding = Some(found);
+                bom
+            },
+        }
+    }
+
+    /// Whether the progress callback cancelled the load.
+    pub fn cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// The next chunk of text, or `None` at the end of the input or when
+    /// the load was cancelled.
+    pub fn next_chunk(&mut self) -> io::Result<Option<Chunk<char, M, V>>> {
+        let mut chars = Vec::new();
+
+        while chars.is_empty() && !self.done && !self.cancelled {
+            let carried = self.bytes.len();
+            self.bytes.resize(carried + self.options.chunk_size, 0);
+
+            let n = loop {
+                match self.reader.read(&mut self.bytes[carried..]) {
+                    Err(ref error) if error.kind() == io::ErrorKind::Interrupted => {},
+                    result => break result?,
+                }
+            };
+
+            self.bytes.truncate(carried + n);
+            self.done = n == 0;
+
+            self.read += n as u64;
+
+            // a byte order mark can be up to 4 bytes, and a reader can hand
+            // over fewer than that at a time, so wait for them all
+            if self.detected || self.bytes.len() >= 4 || self.done {
+                let start = if self.detected { 0 } else { self.detect() };
+                let used = start + self.encoding.unwrap().decode(&self.bytes[start..], self.done, &mut chars);
+                self.bytes.drain(..used);
+            }
+
+            if let Some(ref mut progress) = self.options.progress {
+                self.cancelled = !progress(self.read, self.total);
+            }
+        }
+
+        if chars.is_empty() {
+            return Ok(None);
+        }
+
+        let mut chunk = match self.options.classifier {
+            Some(ref classifier) => Chunk::with_classifier(chars.len(), classifier),
+            None => Chunk::with_capacity(chars.len()),
+        };
+
+        chunk.extend_from_slice(&chars);
+        Ok(Some(chunk))
+    }
+}
+
+impl<M: Eq + Hash + Copy, V: Clone> Rope<char, M, V> {
+
+    pub fn load_file<P: AsRef<Path>>(path: P, options: LoadOptions<M, V>) -> Result<Self, LoadError<M, V>> {
+        let file = File::open(path)?;
+        let total = file.metadata()?.len();
+
+        Self::load(file, Some(total), options)
+    }
+
+    /// Like `load_file`, reading from `reader`, with `total` bytes to read
+    /// if that's known.
+    pub fn load<R: Read>(reader: R, total: Option<u64>, options: LoadOptions<M, V>) -> Result<Self, LoadError<M, V>> {
+        let mut chunks = ChunkReader::new(reader, total, options);
+        let rope = Rope::from_chunks(|| chunks.next_chunk())?;
+
+        if chunks.cancelled() {
+            Err(LoadError::Cancelled(rope))
+        } else {
+            Ok(rope)
+        }
+    }
+}
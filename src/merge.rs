@@ -0,0 +1,242 @@
+//!
+//! Three-way merges of ropes that were edited independently from a common
+//! base.
+//!
+//! Both descendants are diffed against the base, and edits that don't
+//! overlap are applied to the base together, so the merged rope still
+//! shares every untouched subtree with it. As with `git merge`, two edits
+//! overlap if their ranges of the base intersect or even touch (so two
+//! insertions at the same place, whose order would be ambiguous, overlap).
+//! Overlapping edits that made the same change are applied once; any others
+//! are conflicts.
+//!
+
+use std::hash::Hash;
+use std::ops::Range;
+
+use super::{ChangeSet, Hunk, Rope};
+
+/// Edits from `ours` and `theirs` that couldn't be merged. Each range is
+/// relative to its own rope.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Conflict {
+    pub base: Range<usize>,
+    pub ours: Range<usize>,
+    pub theirs: Range<usize>,
+
+    /// Where our side of the conflict ended up in the merged rope.
+    pub merged: Range<usize>,
+}
+
+pub struct Merge<T, M = (), V = ()> {
+    rope: Rope<T, M, V>,
+    conflicts: Vec<Conflict>,
+
+    ours: Rope<T, M, V>,
+    theirs: Rope<T, M, V>,
+}
+
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> Merge<T, M, V> {
+
+    /// The merged rope. Conflicting regions hold our side of the conflict.
+    pub fn rope(&self) -> &Rope<T, M, V> {
+        &self.rope
+    }
+
+    pub fn conflicts(&self) -> &[Conflict] {
+        &self.conflicts
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+impl<M: Eq + Hash + Copy, V: Clone> Merge<char, M, V> {
+
+    /// The merged text with each conflict written out between conflict
+    /// markers, as `git merge` does. Conflicts are widened to whole lines,
+    /// and conflicts sharing a line are written out together.
+    pub fn with_conflict_markers(&self) -> Rope<char, M, V> {
+        let rope = &self.rope;
+        let mut groups: Vec<(Range<usize>, Vec<&Conflict>)> = Vec::new();
+
+        for conflict in &self.conflicts {
+            let mut start = conflict.merged.start;
+            while start > 0 && rope[start - 1] != '\n' {
+                start -= 1;
+            }
+
+            let mut end = conflict.merged.end;
+            while end < rope.len() && (end == 0 || rope[end - 1] != '\n') {
+                end += 1;
+            }
+
+            match groups.last_mut() {
+                Some(&mut (ref mut lines, ref mut conflicts)) if start < lines.end => {
+                    lines.end = end;
+                    conflicts.push(conflict);
+                },
+                _ => groups.push((start..end, vec![conflict])),
+            }
+        }
+
+        let mut marked = rope.clone();
+
+        for (lines, conflicts) in groups.iter().rev() {
+            let mut text = Vec::new();
+
+            text.extend("<<<<<<< ours\n".chars());
+            self.push_side(lines, conflicts, Side::Ours, &mut text);
+            text.extend("=======\n".chars());
+            self.push_side(lines, conflicts, Side::Theirs, &mut text);
+            text.extend(">>>>>>> theirs\n".chars());
+
+            marked = marked.replace(lines.start, lines.end, &text);
+        }
+
+        marked
+    }
+
+    /// Push the lines `lines` of the merged rope as they'd be with one
+    /// side's version of each of `conflicts`.
+    fn push_side(&self,
+                 lines: &Range<usize>,
+                 conflicts: &[&Conflict],
+                 side: Side,
+                 text: &mut Vec<char>) {
+
+        let mut at = lines.start;
+
+        for conflict in conflicts {
+            let (rope, range) = match side {
+                Side::Ours => (&self.ours, &conflict.ours),
+                Side::Theirs => (&self.theirs, &conflict.theirs),
+            };
+
+            text.extend(values(&self.rope, &(at..conflict.merged.start)));
+            text.extend(values(rope, range));
+            at = conflict.merged.end;
+        }
+
+        text.extend(values(&self.rope, &(at..lines.end)));
+
+        if text.last() != Some(&'\n') {
+            text.push('\n');
+        }
+    }
+}
+
+fn values<T, M, V>(rope: &Rope<T, M, V>, range: &Range<usize>) -> Vec<T>
+    where T: Clone, M: Eq + Hash + Copy, V: Clone {
+
+    if range.is_empty() {
+        Vec::new()
+    } else {
+        rope.slice(range.start, range.end).iter().cloned().collect()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Side {
+    Ours,
+    Theirs,
+}
+
+fn overlap(a: &Hunk, b: &Hunk) -> bool {
+    a.old_start <= b.old_end && b.old_start <= a.old_end
+}
+
+impl<T: Clone + PartialEq, M: Eq + Hash + Copy, V: Clone> Rope<T, M, V> {
+
+    /// Merge the edits that took `base` to `ours` with those that took it
+    /// to `theirs`.
+    pub fn merge3(base: &Self, ours: &Self, theirs: &Self) -> Merge<T, M, V> {
+        let mut hunks: Vec<(Hunk, Side)> =
+            Rope::diff(base, ours).hunks().into_iter().map(|hunk| (hunk, Side::Ours))
+                .chain(Rope::diff(base, theirs).hunks().into_iter().map(|hunk| (hunk, Side::Theirs)))
+                .collect();
+
+        hunks.sort_by_key(|&(hunk, _)| (hunk.old_start, hunk.old_end));
+
+        let mut changes = ChangeSet::new();
+        let mut conflicts = Vec::new();
+
+        // how far we've got through the base, and how much each side had
+        // grown or shrunk by that point
+        let mut at = 0;
+        let mut merged_len = 0;
+        let (mut ours_delta, mut theirs_delta) = (0isize, 0isize);
+
+        let mut hunks = hunks.into_iter().peekable();
+
+        while let Some(first) = hunks.next() {
+            let mut cluster = vec![first];
+
+            while let Some(next) = hunks.peek().cloned() {
+                if !cluster.iter().any(|&(hunk, _)| overlap(&hunk, &next.0)) {
+                    break;
+                }
+
+                cluster.push(next);
+                hunks.next();
+            }
+
+            let start = cluster[0].0.old_start;
+            let end = cluster.iter().map(|&(hunk, _)| hunk.old_end).max().unwrap();
+
+            let growth = |side: Side| -> isize {
+                cluster.iter()
+                       .filter(|&&(_, s)| s == side)
+                       .map(|&(hunk, _)| {
+                           (hunk.new_end - hunk.new_start) as isize -
+                               (hunk.old_end - hunk.old_start) as isize
+                       })
+                       .sum()
+            };
+
+            let shifted = |at: usize, delta: isize| (at as isize + delta) as usize;
+
+            let ours_range = shifted(start, ours_delta)..shifted(end, ours_delta + growth(Side::Ours));
+            let theirs_range = shifted(start, theirs_delta)..shifted(end, theirs_delta + growth(Side::Theirs));
+
+            let has_ours = cluster.iter().any(|&(_, side)| side == Side::Ours);
+            let has_theirs = cluster.iter().any(|&(_, side)| side == Side::Theirs);
+
+            let replacement = if has_ours {
+                values(ours, &ours_range)
+            } else {
+                values(theirs, &theirs_range)
+            };
+
+            if has_ours && has_theirs && replacement != values(theirs, &theirs_range) {
+                let merged_start = merged_len + (start - at);
+
+                conflicts.push(Conflict {
+                    base: start..end,
+                    ours: ours_range,
+                    theirs: theirs_range,
+                    merged: merged_start..merged_start + replacement.len(),
+                });
+            }
+
+            changes.retain(start - at);
+            merged_len += start - at + replacement.len();
+            changes.insert_vec(replacement);
+            changes.delete(end - start);
+
+            at = end;
+            ours_delta += growth(Side::Ours);
+            theirs_delta += growth(Side::Theirs);
+        }
+
+        changes.retain(base.len() - at);
+
+        Merge {
+            rope: changes.apply(base),
+            conflicts,
+            ours: ours.clone(),
+            theirs: theirs.clone(),
+        }
+    }
+}
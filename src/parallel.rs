@@ -0,0 +1,216 @@
+//!
+//! Parallel iteration over ropes with rayon, behind the `rayon` feature
+//! (which turns on `sync`).
+//!
+//! A rope is already split up for us: `par_iter` and `par_chunks` hand the
+//! two sides of a `Concat` to different threads as rayon asks for work, so
+//! no values are copied and the pieces line up with the tree. `par_iter`
+//! goes on to split large leaves in half, since a rope made with
+//! `Rope::new` is a single leaf; `par_chunks` gives whole leaves.
+//!
+//! Going the other way, `Rope::par_from_slice` and friends copy a slice
+//! into leaves (marking each with a classifier, if there is one) on
+//! rayon's threads, and then join the leaves up in parallel. The tree is
+//! split exactly as `Rope::from_chunks` splits it, so the rope built is
+//! the same, node for node, as the one built one leaf at a time.
+//!
+
+use std::hash::Hash;
+
+use rayon::iter::ParallelIterator;
+use rayon::iter::plumbing::{bridge_unindexed, Folder, UnindexedConsumer, UnindexedProducer};
+use rayon::slice::ParallelSlice;
+
+use super::{Chunk, Classifier, Link, Node, Rope, Values, DEFAULT_LEAF_SIZE};
+use super::Node::*;
+
+/// A parallel iterator over the values of a rope, from `Rope::par_iter`.
+pub struct ParValues<'a, T: 'a, M: 'a, V: 'a> {
+    root: &'a Link<T, M, V>,
+}
+
+/// A parallel iterator over the leaves of a rope, as slices, from
+/// `Rope::par_chunks`.
+pub struct ParChunks<'a, T: 'a, M: 'a, V: 'a> {
+    root: &'a Node<T, M, V>,
+}
+
+enum Piece<'a, T: 'a, M: 'a, V: 'a> {
+    Node(&'a Link<T, M, V>),
+    Values(&'a [T]),
+}
+
+impl<'a, T, M, V> Piece<'a, T, M, V> {
+
+    fn split(self) -> (Self, Option<Self>) {
+        match self {
+            Piece::Node(node) => match **node {
+                Concat { ref left, ref right, .. } => (Piece::Node(left), Some(Piece::Node(right))),
+                Flat { ref data, .. } => Piece::Values(data).split(),
+            },
+
+            Piece::Values(values) if values.len() > 1 => {
+                let (left, right) = values.split_at(values.len() / 2);
+                (Piece::Values(left), Some(Piece::Values(right)))
+            },
+            piece => (piece, None),
+        }
+    }
+}
+
+struct ValuesProducer<'a, T: 'a, M: 'a, V: 'a>(Piece<'a, T, M, V>);
+
+impl<'a, T, M, V> UnindexedProducer for ValuesProducer<'a, T, M, V>
+    where T: Clone + Send + Sync, M: Eq + Hash + Copy + Send + Sync, V: Clone + Send + Sync {
+
+    type Item = &'a T;
+
+    fn split(self) -> (Self, Option<Self>) {
+        let (left, right) = self.0.split();
+        (ValuesProducer(left), right.map(ValuesProducer))
+    }
+
+    fn fold_with<F: Folder<Self::Item>>(self, folder: F) -> F {
+        match self.0 {
+            Piece::Node(node) => folder.consume_iter(Values::new(node)),
+            Piece::Values(values) => folder.consume_iter(values),
+        }
+    }
+}
+
+struct ChunksProducer<'a, T: 'a, M: 'a, V: 'a>(&'a Node<T, M, V>);
+
+impl<'a, T, M, V> UnindexedProducer for ChunksProducer<'a, T, M, V>
+    where T: Send + Sync, M: Send + Sync, V: Send + Sync {
+
+    type Item = &'a [T];
+
+    fn split(self) -> (Self, Option<Self>) {
+        match *self.0 {
+            Concat { ref left, ref right, .. } => (ChunksProducer(left), Some(ChunksProducer(right))),
+            Flat { .. } => (self, None),
+        }
+    }
+
+    fn fold_with<F: Folder<Self::Item>>(self, folder: F) -> F {
+        match *self.0 {
+            Concat { ref left, ref right, .. } => {
+                let folder = ChunksProducer(left).fold_with(folder);
+
+                if folder.full() {
+                    folder
+                } else {
+                    ChunksProducer(right).fold_with(folder)
+                }
+            },
+
+            Flat { ref data, .. } if data.is_empty() => folder,
+            Flat { ref data, .. } => folder.consume(data),
+        }
+    }
+}
+
+impl<'a, T, M, V> ParallelIterator for ParValues<'a, T, M, V>
+    where T: Clone + Send + Sync, M: Eq + Hash + Copy + Send + Sync, V: Clone + Send + Sync {
+
+    type Item = &'a T;
+
+    fn drive_unindexed<C: UnindexedConsumer<Self::Item>>(self, consumer: C) -> C::Result {
+        bridge_unindexed(ValuesProducer(Piece::Node(self.root)), consumer)
+    }
+}
+
+impl<'a, T, M, V> ParallelIterator for ParChunks<'a, T, M, V>
+    where T: Send + Sync, M: Send + Sync, V: Send + Sync {
+
+    type Item = &'a [T];
+
+    fn drive_unindexed<C: UnindexedConsumer<Self::Item>>(self, consumer: C) -> C::Result {
+        bridge_unindexed(ChunksProducer(self.root), consumer)
+    }
+}
+
+impl<T, M, V> Rope<T, M, V>
+    where T: Clone + Send + Sync, M: Eq + Hash + Copy + Send + Sync, V: Clone + Send + Sync {
+
+    /// The values of the rope, in parallel. Order-preserving adaptors like
+    /// `collect` still see them in order.
+    pub fn par_iter(&self) -> ParValues<'_, T, M, V> {
+        ParValues { root: &self.root }
+    }
+
+    /// The rope's leaves, in parallel, each as one slice. Empty leaves are
+    /// skipped.
+    pub fn par_chunks(&self) -> ParChunks<'_, T, M, V> {
+        ParChunks { root: &self.root }
+    }
+
+    /// Fold the rope's values in parallel: each piece of the rope handed to
+    /// a thread is folded with `fold`, starting from `identity()`, and the
+    /// results for neighbouring pieces are combined with `reduce`, left then
+    /// right. So `reduce` needs to be associative, but not commutative, and
+    /// `identity()` should make no difference to it.
+    pub fn par_fold<A, I, F, R>(&self, identity: I, fold: F, reduce: R) -> A
+        where A: Send,
+              I: Fn() -> A + Send + Sync,
+              F: Fn(A, &T) -> A + Send + Sync,
+              R: Fn(A, A) -> A + Send + Sync {
+
+        self.par_iter().fold(&identity, fold).reduce(&identity, reduce)
+    }
+
+    /// Copy `data` into leaves of `DEFAULT_LEAF_SIZE` values each, in
+    /// parallel.
+    pub fn par_from_slice(data: &[T]) -> Self {
+        Self::par_from_leaves_of(data, DEFAULT_LEAF_SIZE, None)
+    }
+
+    /// Like `from_slice_with_leaf_size`, in parallel.
+    pub fn par_from_slice_with_leaf_size(data: &[T], leaf_size: usize) -> Self {
+        Self::par_from_leaves_of(data, leaf_size, None)
+    }
+
+    /// Like a `RopeBuilder::with_classifier` filled with `data`, in
+    /// parallel: each leaf is marked by `classifier` on the thread that
+    /// makes it, and the rope goes on using it.
+    pub fn par_from_slice_with_classifier(data: &[T], leaf_size: usize, classifier: &Classifier<T, M, V>) -> Self {
+        Self::par_from_leaves_of(data, leaf_size, Some(classifier))
+    }
+
+    fn par_from_leaves_of(data: &[T], leaf_size: usize, classifier: Option<&Classifier<T, M, V>>) -> Self {
+        if leaf_size == 0 {
+            panic!("leaf size must be greater than zero");
+        }
+
+        let roots: Vec<Link<T, M, V>> = data.par_chunks(leaf_size).map(|values| {
+            let mut chunk = match classifier {
+                Some(classifier) => Chunk::with_classifier(values.len(), classifier),
+                None => Chunk::with_capacity(values.len()),
+            };
+
+            chunk.extend_from_slice(values);
+            Rope::from_chunk(chunk).root
+        }).collect();
+
+        let classifier = classifier.cloned();
+
+        if roots.is_empty() {
+            Rope { classifier, ..Self::new(&[]) }
+        } else {
+            Rope { root: par_balanced(&roots), classifier }
+        }
+    }
+}
+
+/// `Node::balanced`, with the two halves of each node built in parallel.
+fn par_balanced<T, M, V>(nodes: &[Link<T, M, V>]) -> Link<T, M, V>
+    where T: Clone + Send + Sync, M: Eq + Hash + Copy + Send + Sync, V: Clone + Send + Sync {
+
+    if nodes.len() == 1 {
+        nodes[0].clone()
+    } else {
+        let (left, right) = nodes.split_at(nodes.len().div_ceil(2));
+        let (left, right) = rayon::join(|| par_balanced(left), || par_balanced(right));
+        Node::concat(&left, &right)
+    }
+}
@@ -0,0 +1,96 @@
+//!
+//! Piece-table style editing on top of `Rope`.
+//!
+//! The original contents of a document are held in one immutable buffer and
+//! every inserted run of values becomes a segment of the "add" buffer. The
+//! leaves of the resulting `Rope` are just ranges ("pieces") of those
+//! buffers, so loading a document never copies it and edits only allocate
+//! what they insert.
+//!
+
+use std::hash::Hash;
+use std::collections::HashMap;
+
+use super::{Chunk, Markers, Shared, Rope};
+
+pub struct PieceTable<T, M = (), V = ()> {
+    original: Shared<Vec<T>>,
+
+    // The add buffer is append-only but its contents are shared with the
+    // leaves of `rope`, so rather than growing one vector (which would mean
+    // copying it while it's shared) each insertion makes a new segment. The
+    // leaves keep the segments they use alive, so we only count them.
+    add_len: usize,
+
+    rope: Rope<T, M, V>,
+}
+
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> PieceTable<T, M, V> {
+
+    /// Takes ownership of `original`; nothing is copied.
+    pub fn new(original: Vec<T>) -> Self {
+        Self::from_shared(Shared::new(original))
+    }
+
+    /// Use a chunk (and its markers) as the original buffer.
+    pub fn from_chunk(chunk: Chunk<T, M, V>) -> Self {
+        Self::with_markers(Shared::new(chunk.data), chunk.markers)
+    }
+
+    /// Use a buffer that may also be referenced elsewhere, e.g. by another
+    /// `PieceTable` over the same file.
+    pub fn from_shared(original: Shared<Vec<T>>) -> Self {
+        Self::with_markers(original, HashMap::new())
+    }
+
+    fn with_markers(original: Shared<Vec<T>>, markers: Markers<M, V>) -> Self {
+        let rope = Rope::from_shared(&original, 0, original.len(), markers);
+
+        PieceTable {
+            original,
+            add_len: 0,
+            rope,
+        }
+    }
+
+    pub fn original(&self) -> &[T] {
+        &self.original
+    }
+
+    /// The total number of values ever inserted, including those that have
+    /// since been removed from the document.
+    pub fn add_len(&self) -> usize {
+        self.add_len
+    }
+
+    /// The current contents of the document. `Rope`s are persistent, so the
+    /// returned rope is unaffected by later edits to the table.
+    pub fn rope(&self) -> &Rope<T, M, V> {
+        &self.rope
+    }
+
+    pub fn into_rope(self) -> Rope<T, M, V> {
+        self.rope
+    }
+
+    pub fn insert(&mut self, at: usize, values: &[T]) {
+        let mut chunk = Chunk::with_capacity(values.len());
+        chunk.extend_from_slice(values);
+        self.insert_chunk(at, chunk);
+    }
+
+    /// Insert the contents of `chunk`, with its markers, at index `at`. The
+    /// chunk's data is moved into the add buffer without copying.
+    pub fn insert_chunk(&mut self, at: usize, chunk: Chunk<T, M, V>) {
+        let segment = Shared::new(chunk.data);
+        let piece = Rope::from_shared(&segment, 0, segment.len(), chunk.markers);
+
+        self.add_len += segment.len();
+        self.rope = self.rope.splice(at, at, &piece);
+    }
+
+    /// `start` is inclusive, `end` is EXclusive.
+    pub fn remove(&mut self, start: usize, end: usize) {
+        self.rope = self.rope.remove(start, end);
+    }
+}
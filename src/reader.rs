@@ -0,0 +1,173 @@
+//!
+//! `std::io` support for ropes of bytes.
+//!
+//! `Rope::reader` gives a `Read`, `BufRead` and `Seek` over the bytes of a
+//! rope, which serves each `Flat` leaf straight from the rope rather than
+//! copying it into a buffer of its own. `Rope::write_to` writes a rope with
+//! vectored writes, one `IoSlice` per leaf.
+//!
+
+use std::convert::TryFrom;
+use std::hash::Hash;
+use std::io::{self, BufRead, IoSlice, Read, Seek, SeekFrom, Write};
+
+use super::{Link, Node, Rope};
+use super::Node::*;
+
+pub struct RopeReader<M = (), V = ()> {
+    rope: Rope<u8, M, V>,
+    pos: usize,
+
+    // the leaf holding `pos`, and where it starts
+    leaf: Option<(Link<u8, M, V>, usize)>,
+}
+
+impl<M: Eq + Hash + Copy, V: Clone> RopeReader<M, V> {
+
+    /// The rope being read.
+    pub fn rope(&self) -> &Rope<u8, M, V> {
+        &self.rope
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// The bytes of the current leaf from `pos` on, finding the leaf first
+    /// if need be.
+    fn leaf_bytes(&mut self) -> &[u8] {
+        if self.pos >= self.rope.len() {
+            return &[];
+        }
+
+        let current = match self.leaf {
+            Some((ref leaf, start)) => self.pos >= start && self.pos < start + leaf.len(),
+            None => false,
+        };
+
+        if !current {
+            self.leaf = Some(leaf_at(&self.rope.root, self.pos));
+        }
+
+        match self.leaf {
+            Some((ref leaf, start)) => match **leaf {
+                Flat { ref data, .. } => &data[self.pos - start..],
+                Concat { .. } => unreachable!(),
+            },
+            None => unreachable!(),
+        }
+    }
+}
+
+/// The leaf under `node` holding index `at`, and the index it starts at.
+fn leaf_at<T, M, V>(node: &Link<T, M, V>, at: usize) -> (Link<T, M, V>, usize)
+    where T: Clone, M: Eq + Hash + Copy, V: Clone {
+
+    let (mut node, mut start) = (node, 0);
+
+    while let Concat { left_len, ref left, ref right, .. } = **node {
+        if at - start < left_len {
+            node = left;
+        } else {
+            node = right;
+            start += left_len;
+        }
+    }
+
+    (node.clone(), start)
+}
+
+impl<M: Eq + Hash + Copy, V: Clone> Read for RopeReader<M, V> {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let bytes = self.leaf_bytes();
+            let n = bytes.len().min(buf.len());
+            buf[..n].copy_from_slice(&bytes[..n]);
+            n
+        };
+
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<M: Eq + Hash + Copy, V: Clone> BufRead for RopeReader<M, V> {
+
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Ok(self.leaf_bytes())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+impl<M: Eq + Hash + Copy, V: Clone> Seek for RopeReader<M, V> {
+
+    /// Seeking past the end is allowed, as with files, and reads there give
+    /// nothing.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let too_far = || io::Error::new(io::ErrorKind::InvalidInput, "seek past the largest usize");
+
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = usize::try_from(offset).map_err(|_| too_far())?;
+                return Ok(offset);
+            },
+            SeekFrom::End(offset) => (self.rope.len(), offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+
+        match (base as i64).checked_add(offset) {
+            Some(pos) if pos >= 0 => {
+                self.pos = usize::try_from(pos).map_err(|_| too_far())?;
+                Ok(pos as u64)
+            },
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")),
+        }
+    }
+}
+
+fn push_leaves<'a, T, M, V>(node: &'a Node<T, M, V>, leaves: &mut Vec<&'a [T]>) {
+    match *node {
+        Concat { ref left, ref right, .. } => {
+            push_leaves(left, leaves);
+            push_leaves(right, leaves);
+        },
+        Flat { ref data, .. } => {
+            if !data.is_empty() {
+                leaves.push(data);
+            }
+        },
+    }
+}
+
+impl<M: Eq + Hash + Copy, V: Clone> Rope<u8, M, V> {
+
+    /// A reader over the bytes of the rope, starting at the beginning.
+    pub fn reader(&self) -> RopeReader<M, V> {
+        RopeReader { rope: self.clone(), pos: 0, leaf: None }
+    }
+
+    /// Write the whole rope to `out`, with as few vectored writes as `out`
+    /// will take.
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut leaves = Vec::new();
+        push_leaves(&self.root, &mut leaves);
+
+        let mut slices: Vec<IoSlice> = leaves.into_iter().map(IoSlice::new).collect();
+        let mut slices = &mut slices[..];
+
+        while !slices.is_empty() {
+            match out.write_vectored(slices) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write the whole rope")),
+                Ok(n) => IoSlice::advance_slices(&mut slices, n),
+                Err(ref error) if error.kind() == io::ErrorKind::Interrupted => {},
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//!
+//! `Serialize` and `Deserialize` for `Rope` and `Chunk`, behind the `serde`
+//! feature.
+//!
+//! Both are written as a struct with two fields: `values`, the flat sequence
+//! of values, and `markers`, a sequence of `(marker, [(index, value), ...])`
+//! pairs, sorted by marker (so markers need to be `Ord` to serialize) so
+//! the same rope is always written the same way. A `Rope` is flattened on
+//! the way out and comes back as a single `Flat` leaf, like
+//! `Rope::flatten` gives. Spans, anchors and classifiers aren't written:
+//! ids and functions don't mean anything in another process.
+//!
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use super::{Chunk, Markers, Node, Rope};
+use super::Node::*;
+
+const FIELDS: &[&str] = &["values", "markers"];
+
+struct MarkerList<'a, M: 'a, V: 'a>(&'a Markers<M, V>);
+
+impl<'a, M: Serialize + Ord, V: Serialize> Serialize for MarkerList<'a, M, V> {
+
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut entries: Vec<_> = self.0.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        serializer.collect_seq(entries.into_iter().map(|(marker, indices)| {
+            (marker, Indices(indices))
+        }))
+    }
+}
+
+struct Indices<'a, V: 'a>(&'a BTreeMap<usize, V>);
+
+impl<'a, V: Serialize> Serialize for Indices<'a, V> {
+
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter())
+    }
+}
+
+impl<T, M, V> Serialize for Chunk<T, M, V>
+    where T: Serialize, M: Serialize + Eq + Hash + Ord, V: Serialize {
+
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Chunk", 2)?;
+        state.serialize_field("values", &self.data)?;
+        state.serialize_field("markers", &MarkerList(&self.markers))?;
+        state.end()
+    }
+}
+
+struct Values<'a, T: 'a, M: 'a + Eq + Hash, V: 'a>(&'a Rope<T, M, V>);
+
+impl<'a, T, M, V> Serialize for Values<'a, T, M, V>
+    where T: Serialize + Clone, M: Eq + Hash + Copy, V: Clone {
+
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter())
+    }
+}
+
+/// Gather the markers under `node`, offset by `offset`.
+fn collect_markers<T, M, V>(node: &Node<T, M, V>, offset: usize, all: &mut Markers<M, V>)
+    where T: Clone, M: Eq + Hash + Copy, V: Clone {
+
+    match *node {
+        Flat { ref markers, .. } => {
+            for (&marker, indices) in markers.iter() {
+                all.entry(marker)
+                   .or_default()
+                   .extend(indices.iter().map(|(&i, value)| (offset + i, value.clone())));
+            }
+        },
+
+        Concat { left_len, ref left, ref right, .. } => {
+            collect_markers(left, offset, all);
+            collect_markers(right, offset + left_len, all);
+        },
+    }
+}
+
+impl<T, M, V> Serialize for Rope<T, M, V>
+    where T: Serialize + Clone, M: Serialize + Eq + Hash + Copy + Ord, V: Serialize + Clone {
+
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut markers = HashMap::new();
+        collect_markers(&self.root, 0, &mut markers);
+
+        let mut state = serializer.serialize_struct("Rope", 2)?;
+        state.serialize_field("values", &Values(self))?;
+        state.serialize_field("markers", &MarkerList(&markers))?;
+        state.end()
+    }
+}
+
+enum Field {
+    Values,
+    Markers,
+}
+
+impl<'de> Deserialize<'de> for Field {
+
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FieldVisitor;
+
+        impl<'de> Visitor<'de> for FieldVisitor {
+            type Value = Field;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("`values` or `markers`")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Field, E> {
+                match value {
+                    "values" => Ok(Field::Values),
+                    "markers" => Ok(Field::Markers),
+                    _ => Err(de::Error::unknown_field(value, FIELDS)),
+                }
+            }
+        }
+
+        deserializer.deserialize_identifier(FieldVisitor)
+    }
+}
+
+type MarkerEntries<M, V> = Vec<(M, Vec<(usize, V)>)>;
+
+struct ChunkVisitor<T, M, V>(PhantomData<(T, M, V)>);
+
+impl<'de, T, M, V> Visitor<'de> for ChunkVisitor<T, M, V>
+    where T: Deserialize<'de>, M: Deserialize<'de> + Eq + Hash, V: Deserialize<'de> {
+
+    type Value = Chunk<T, M, V>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sequence of values and their markers")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let values = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let markers = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        chunk(values, markers)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut values = None;
+        let mut markers = None;
+
+        while let Some(key) = map.next_key()? {
+            match key {
+                Field::Values => values = Some(map.next_value()?),
+                Field::Markers => markers = Some(map.next_value()?),
+            }
+        }
+
+        let values = values.ok_or_else(|| de::Error::missing_field("values"))?;
+        let markers = markers.ok_or_else(|| de::Error::missing_field("markers"))?;
+        chunk(values, markers)
+    }
+}
+
+fn chunk<T, M, V, E>(values: Vec<T>, entries: MarkerEntries<M, V>) -> Result<Chunk<T, M, V>, E>
+    where M: Eq + Hash, E: de::Error {
+
+    let mut markers: Markers<M, V> = HashMap::new();
+
+    for (marker, indices) in entries.into_iter().filter(|entry| !entry.1.is_empty()) {
+        let marked = markers.entry(marker).or_default();
+
+        for (index, value) in indices {
+            if index >= values.len() {
+                return Err(E::custom(format!("marker index {} exceeds bounds (length {})",
+                                             index, values.len())));
+            }
+
+            marked.insert(index, value);
+        }
+    }
+
+    Ok(Chunk { data: values, markers, classifier: None })
+}
+
+impl<'de, T, M, V> Deserialize<'de> for Chunk<T, M, V>
+    where T: Deserialize<'de>, M: Deserialize<'de> + Eq + Hash, V: Deserialize<'de> {
+
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_struct("Chunk", FIELDS, ChunkVisitor(PhantomData))
+    }
+}
+
+impl<'de, T, M, V> Deserialize<'de> for Rope<T, M, V>
+    where T: Deserialize<'de> + Clone, M: Deserialize<'de> + Eq + Hash + Copy, V: Deserialize<'de> + Clone {
+
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let chunk = deserializer.deserialize_struct("Rope", FIELDS, ChunkVisitor(PhantomData))?;
+        Ok(Rope::from_chunk(chunk))
+    }
+}
@@ -0,0 +1,335 @@
+//!
+//! A compact binary format for a set of ropes, e.g. every version in an
+//! undo history, that keeps the nodes they share shared.
+//!
+//! The ropes are written as a DAG: each distinct node is written once, in
+//! an order where children come before their parents, and parents refer to
+//! children by index. Concat nodes store their depth, `left_len` and marker
+//! counts, so reading the snapshot rebuilds exactly the same tree shape
+//! without walking it again.
+//!
+//! A snapshot is:
+//!
+//! * the magic bytes `ROPESNAP` and a little-endian `u32` format version
+//! * the number of nodes, then each node:
+//!   - `0`, the number of values, the values, then for each marker the
+//!     marker, the number of marked indices and each index and value
+//!   - `1`, the left and right child indices, depth, `left_len`, length,
+//!     then for each marker the marker and its counts in the left subtree
+//!     and the whole node
+//! * the number of ropes, then the index of each one's root
+//! * a little-endian CRC-32 of everything before it
+//!
+//! Integers are varints (see the `codec` module), and values, markers and
+//! marker values are written with their `Codec` impls. Markers are written
+//! in the order of their encodings, so a node is always written the same
+//! way (which the node store relies on). Spans, anchors and classifiers
+//! aren't written, and views of a shared buffer are written as their own
+//! values.
+//!
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+use super::{Buffer, Link, Markers, Node, Shared, Rope};
+use super::anchors::AnchorSet;
+use super::Node::*;
+use super::codec::{self, Codec, DecodeError};
+use super::spans::SpanList;
+
+const MAGIC: &[u8] = b"ROPESNAP";
+const VERSION: u32 = 1;
+
+pub const FLAT: u8 = 0;
+pub const CONCAT: u8 = 1;
+
+struct Writer<'a, T: 'a, M: 'a, V: 'a> {
+    out: Vec<u8>,
+    nodes: Vec<&'a Node<T, M, V>>,
+    indices: HashMap<*const Node<T, M, V>, usize>,
+}
+
+impl<'a, T, M, V> Writer<'a, T, M, V> {
+
+    /// Number `node` and everything under it that hasn't been seen yet,
+    /// children first, returning its index.
+    fn visit(&mut self, node: &'a Link<T, M, V>) -> usize {
+        if let Some(&index) = self.indices.get(&Shared::as_ptr(node)) {
+            return index;
+        }
+
+        if let Concat { ref left, ref right, .. } = **node {
+            self.visit(left);
+            self.visit(right);
+        }
+
+        let index = self.nodes.len();
+        self.nodes.push(node);
+        self.indices.insert(Shared::as_ptr(node), index);
+        index
+    }
+
+    fn index_of(&self, node: &Link<T, M, V>) -> usize {
+        self.indices[&Shared::as_ptr(node)]
+    }
+}
+
+impl<'a, T: Codec, M: Codec, V: Codec> Writer<'a, T, M, V> {
+
+    fn write_node(&mut self, node: &Node<T, M, V>) {
+        match *node {
+            Flat { ref data, ref markers, .. } => write_flat(&mut self.out, data, markers),
+
+            Concat { ref left, ref right, .. } => {
+                let (left, right) = (self.index_of(left), self.index_of(right));
+
+                self.out.push(CONCAT);
+                codec::write_varint(&mut self.out, left as u64);
+                codec::write_varint(&mut self.out, right as u64);
+                write_concat_fields(&mut self.out, node);
+            },
+        }
+    }
+}
+
+/// Write the entries of a map, each encoded by `write`, in the order of
+/// their encodings, so the same map is always written the same way.
+fn write_sorted<K, E, F>(out: &mut Vec<u8>, map: &HashMap<K, E>, write: F)
+    where F: Fn(&K, &E, &mut Vec<u8>) {
+
+    let mut entries: Vec<Vec<u8>> = map.iter().map(|(key, entry)| {
+        let mut bytes = Vec::new();
+        write(key, entry, &mut bytes);
+        bytes
+    }).collect();
+
+    entries.sort();
+    codec::write_varint(out, entries.len() as u64);
+    entries.iter().for_each(|bytes| out.extend_from_slice(bytes));
+}
+
+/// Write a `Flat` node's tag, values and markers.
+pub fn write_flat<T: Codec, M: Codec, V: Codec>(out: &mut Vec<u8>, data: &[T], markers: &Markers<M, V>) {
+    out.push(FLAT);
+    codec::write_varint(out, data.len() as u64);
+
+    for value in data {
+        value.encode(out);
+    }
+
+    write_sorted(out, markers, |marker, indices, out| {
+        marker.encode(out);
+        codec::write_varint(out, indices.len() as u64);
+
+        for (&index, value) in indices {
+            codec::write_varint(out, index as u64);
+            value.encode(out);
+        }
+    });
+}
+
+/// Write what a `Concat` node stores about itself, after its children.
+pub fn write_concat_fields<T, M: Codec, V>(out: &mut Vec<u8>, node: &Node<T, M, V>) {
+    if let Concat { depth, left_len, ref markers, len, .. } = *node {
+        for &n in &[depth, left_len, len] {
+            codec::write_varint(out, n as u64);
+        }
+
+        write_sorted(out, markers, |marker, &(in_left, total), out| {
+            marker.encode(out);
+            codec::write_varint(out, in_left as u64);
+            codec::write_varint(out, total as u64);
+        });
+    }
+}
+
+/// Read the rest of a `Flat` node, after its tag.
+pub fn read_flat<T, M, V>(input: &mut &[u8]) -> Result<Node<T, M, V>, DecodeError>
+    where T: Clone + Codec, M: Eq + Hash + Copy + Codec, V: Clone + Codec {
+
+    let len = codec::read_usize(input)?;
+    let mut data = Vec::with_capacity(len.min(input.len()));
+
+    for _ in 0..len {
+        data.push(T::decode(input)?);
+    }
+
+    let mut markers: Markers<M, V> = HashMap::new();
+
+    for _ in 0..codec::read_usize(input)? {
+        let marker = M::decode(input)?;
+        let mut indices = BTreeMap::new();
+
+        for _ in 0..codec::read_usize(input)? {
+            let index = codec::read_usize(input)?;
+
+            if index >= len {
+                return Err(DecodeError::Corrupt("marker index out of bounds"));
+            }
+
+            indices.insert(index, V::decode(input)?);
+        }
+
+        if !indices.is_empty() {
+            markers.insert(marker, indices);
+        }
+    }
+
+    Ok(Flat {
+        data: Buffer::Owned(Shared::new(data)),
+        markers: Shared::new(markers),
+        spans: SpanList::new(Vec::new()),
+        anchors: Shared::new(Vec::new()),
+    })
+}
+
+/// Read the rest of a `Concat` node over `left` and `right`, checking that
+/// it agrees with them.
+pub fn read_concat<T, M, V>(input: &mut &[u8],
+                            left: Link<T, M, V>,
+                            right: Link<T, M, V>) -> Result<Node<T, M, V>, DecodeError>
+    where T: Clone + Codec, M: Eq + Hash + Copy + Codec, V: Clone + Codec {
+
+    let depth = codec::read_usize(input)?;
+    let left_len = codec::read_usize(input)?;
+    let len = codec::read_usize(input)?;
+
+    if depth != left.depth().max(right.depth()) + 1 ||
+       left_len != left.len() ||
+       Some(len) != left_len.checked_add(right.len()) {
+        return Err(DecodeError::Corrupt("node doesn't match its children"));
+    }
+
+    let mut markers = HashMap::new();
+
+    for _ in 0..codec::read_usize(input)? {
+        let marker = M::decode(input)?;
+        let in_left = codec::read_usize(input)?;
+        let total = codec::read_usize(input)?;
+
+        markers.insert(marker, (in_left, total));
+    }
+
+    if markers != Node::concat_marker_counts(&left, &right) {
+        return Err(DecodeError::Corrupt("marker counts don't match the children"));
+    }
+
+    Ok(Concat {
+        depth,
+        left_len,
+        markers,
+        len,
+        left,
+        right,
+        spans: SpanList::new(Vec::new()),
+        span_count: 0,
+        spans_below: None,
+        anchors: AnchorSet::default(),
+    })
+}
+
+fn read_node<T, M, V>(input: &mut &[u8], nodes: &[Link<T, M, V>]) -> Result<Node<T, M, V>, DecodeError>
+    where T: Clone + Codec, M: Eq + Hash + Copy + Codec, V: Clone + Codec {
+
+    match codec::read_byte(input)? {
+        FLAT => read_flat(input),
+
+        CONCAT => {
+            let child = |input: &mut &[u8]| -> Result<Link<T, M, V>, DecodeError> {
+                // children always come first, so this also rules out cycles
+                nodes.get(codec::read_usize(input)?)
+                     .cloned()
+                     .ok_or(DecodeError::Corrupt("node refers to a later node"))
+            };
+
+            let left = child(input)?;
+            let right = child(input)?;
+            read_concat(input, left, right)
+        },
+
+        _ => Err(DecodeError::Corrupt("unknown node type")),
+    }
+}
+
+impl<T, M, V> Rope<T, M, V>
+    where T: Clone + Codec, M: Eq + Hash + Copy + Codec, V: Clone + Codec {
+
+    /// Write `ropes` as a snapshot, each node they share written once.
+    pub fn write_snapshot<'a, I>(ropes: I) -> Vec<u8>
+        where I: IntoIterator<Item = &'a Self>, Self: 'a {
+
+        let mut writer = Writer { out: Vec::new(), nodes: Vec::new(), indices: HashMap::new() };
+        let roots: Vec<usize> = ropes.into_iter().map(|rope| writer.visit(&rope.root)).collect();
+
+        writer.out.extend_from_slice(MAGIC);
+        writer.out.extend_from_slice(&VERSION.to_le_bytes());
+        codec::write_varint(&mut writer.out, writer.nodes.len() as u64);
+
+        for i in 0..writer.nodes.len() {
+            let node = writer.nodes[i];
+            writer.write_node(node);
+        }
+
+        codec::write_varint(&mut writer.out, roots.len() as u64);
+
+        for root in roots {
+            codec::write_varint(&mut writer.out, root as u64);
+        }
+
+        let checksum = codec::crc32(&writer.out);
+        writer.out.extend_from_slice(&checksum.to_le_bytes());
+        writer.out
+    }
+
+    /// Read back the ropes written by `write_snapshot`, in the same order
+    /// and sharing the same nodes.
+    pub fn read_snapshot(bytes: &[u8]) -> Result<Vec<Self>, DecodeError> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+
+        if bytes.len() < MAGIC.len() + 8 {
+            return Err(DecodeError::Truncated);
+        }
+
+        let mut version = [0; 4];
+        version.copy_from_slice(&bytes[MAGIC.len()..MAGIC.len() + 4]);
+        let version = u32::from_le_bytes(version);
+
+        if version != VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        let mut expected = [0; 4];
+        expected.copy_from_slice(checksum);
+
+        if codec::crc32(body) != u32::from_le_bytes(expected) {
+            return Err(DecodeError::BadChecksum);
+        }
+
+        let mut input = &body[MAGIC.len() + 4..];
+        let count = codec::read_usize(&mut input)?;
+        let mut nodes = Vec::with_capacity(count.min(input.len()));
+
+        for _ in 0..count {
+            let node = read_node(&mut input, &nodes)?;
+            nodes.push(Shared::new(node));
+        }
+
+        let mut ropes = Vec::new();
+
+        for _ in 0..codec::read_usize(&mut input)? {
+            let root = nodes.get(codec::read_usize(&mut input)?)
+                            .ok_or(DecodeError::Corrupt("root refers to a missing node"))?;
+
+            ropes.push(Rope { root: root.clone(), classifier: None });
+        }
+
+        if !input.is_empty() {
+            return Err(DecodeError::Corrupt("trailing data"));
+        }
+
+        Ok(ropes)
+    }
+}
@@ -0,0 +1,420 @@
+//!
+//! Range annotations ("spans") over a `Rope`.
+//!
+//! Like markers, each span has a marker of type `M` and a value of type `V`,
+//! but it covers a range `start..end` of the rope rather than one index.
+//!
+//! A span is stored in the lowest node that wholly contains it: a `Flat`
+//! leaf, or the `Concat` node whose boundary it crosses. Positions are kept
+//! relative to the node, so spans move along with their contents for free,
+//! and a `Concat` node's spans all contain its `left_len`, which lets us find
+//! those overlapping a range without looking at the rest (as in a centered
+//! interval tree).
+//!
+//! How spans behave under the rope's other operations:
+//!
+//! * `slice` clips spans to the sliced range, dropping those outside it.
+//! * `concat` leaves the spans of both sides as they are. Spans are never
+//!   merged, even if they touch at the boundary.
+//! * `splice` (and so `insert`, `remove` and `replace`) clips spans that
+//!   overlap the replaced range, drops those inside it, and stretches or
+//!   shrinks those that strictly enclose it rather than cutting them in two.
+//!   Inserting exactly at the start or end of a span doesn't grow it.
+//!
+
+use std::cmp::min;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Node, Shared, Rope};
+use super::Node::*;
+
+static NEXT_SPAN_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Identifies a span added by `Rope::add_span`. A span keeps its id in every
+/// rope derived from the one it was added to.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct SpanId(usize);
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Span<M, V> {
+    pub id: SpanId,
+
+    /// `start` is inclusive, `end` is EXclusive.
+    pub start: usize,
+    pub end: usize,
+
+    pub marker: M,
+    pub value: V,
+}
+
+impl<M, V> Span<M, V> {
+
+    fn moved(self, start: usize, end: usize) -> Self {
+        Span { start, end, ..self }
+    }
+}
+
+/// The spans stored in one node, relative to the start of the node.
+pub struct SpanList<M, V> {
+    by_start: Vec<Span<M, V>>,
+
+    // indices into `by_start`, ordered by decreasing end
+    by_end: Vec<usize>,
+}
+
+impl<M, V> SpanList<M, V> {
+
+    pub fn new(mut spans: Vec<Span<M, V>>) -> Self {
+        spans.sort_by_key(|span| (span.start, span.id));
+
+        let mut by_end: Vec<usize> = (0..spans.len()).collect();
+        by_end.sort_by(|&a, &b| spans[b].end.cmp(&spans[a].end));
+
+        SpanList { by_start: spans, by_end }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_start.len()
+    }
+
+    fn contains(&self, id: SpanId) -> bool {
+        self.by_start.iter().any(|span| span.id == id)
+    }
+}
+
+impl<M: Copy, V: Clone> SpanList<M, V> {
+
+    /// The spans overlapping `start..end`, clipped to it and made relative
+    /// to `start`.
+    pub fn clipped(&self, start: usize, end: usize) -> Vec<Span<M, V>> {
+        self.by_start
+            .iter()
+            .filter(|span| span.start < end && span.end > start)
+            .map(|span| {
+                span.clone().moved(span.start.saturating_sub(start),
+                                   min(span.end, end) - start)
+            })
+            .collect()
+    }
+
+    fn with(&self, spans: Vec<Span<M, V>>) -> Self {
+        let mut all = self.by_start.clone();
+        all.extend(spans);
+        Self::new(all)
+    }
+
+    fn without(&self, id: SpanId) -> Self {
+        Self::new(self.by_start.iter().filter(|span| span.id != id).cloned().collect())
+    }
+
+    /// Push the spans overlapping `start..end` onto `found`, offset by
+    /// `offset`. Every span in the list must contain `mid`, so we only have
+    /// to look at the ones we're going to return.
+    fn crossing(&self,
+                start: usize,
+                end: usize,
+                mid: usize,
+                offset: usize,
+                found: &mut Vec<Span<M, V>>) {
+
+        let moved = |span: &Span<M, V>| {
+            span.clone().moved(offset + span.start, offset + span.end)
+        };
+
+        if end <= mid {
+            found.extend(self.by_start.iter().take_while(|span| span.start < end).map(moved));
+        } else if start >= mid {
+            found.extend(self.by_end.iter()
+                                    .map(|&i| &self.by_start[i])
+                                    .take_while(|span| span.end > start)
+                                    .map(moved));
+        } else {
+            found.extend(self.by_start.iter().map(moved));
+        }
+    }
+}
+
+impl<M: Copy, V: Clone> Clone for SpanList<M, V> {
+
+    fn clone(&self) -> Self {
+        SpanList {
+            by_start: self.by_start.clone(),
+            by_end: self.by_end.clone(),
+        }
+    }
+}
+
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> Node<T, M, V> {
+
+    pub fn span_count(&self) -> usize {
+        match *self {
+            Concat { span_count, .. } => span_count,
+            Flat { ref spans, .. } => spans.len(),
+        }
+    }
+
+    /// Where looking for spans under `node` can start, and its offset from
+    /// `node`.
+    fn spans_start(node: &Shared<Self>) -> (usize, Shared<Self>) {
+        match **node {
+            Concat { spans_below: Some((at, ref below)), .. } => (at, below.clone()),
+            _ => (0, node.clone()),
+        }
+    }
+
+    /// The `spans_below` of a `Concat` of `left` and `right` with `spans`
+    /// crossing between them.
+    pub fn spans_below(left: &Shared<Self>,
+                       right: &Shared<Self>,
+                       spans: &SpanList<M, V>) -> Option<(usize, Shared<Self>)> {
+
+        if spans.len() > 0 {
+            return None;
+        }
+
+        match (left.span_count() > 0, right.span_count() > 0) {
+            (true, false) => Some(Self::spans_start(left)),
+            (false, true) => {
+                let (at, below) = Self::spans_start(right);
+                Some((left.len() + at, below))
+            },
+            _ => None,
+        }
+    }
+
+    /// Add spans, relative to this node, each going to the lowest node that
+    /// contains it.
+    pub fn add_spans(node: &Shared<Self>, spans: Vec<Span<M, V>>) -> Shared<Self> {
+        if spans.is_empty() {
+            return node.clone();
+        }
+
+        match **node {
+            Flat { ref data, ref markers, spans: ref existing, ref anchors } => {
+                Shared::new(Flat {
+                    data: data.clone(),
+                    markers: markers.clone(),
+                    spans: existing.with(spans),
+                    anchors: anchors.clone(),
+                })
+            },
+
+            Concat { left_len, ref left, ref right, spans: ref existing, .. } => {
+                let mut to_left = Vec::new();
+                let mut to_right = Vec::new();
+                let mut here = Vec::new();
+
+                for span in spans {
+                    if span.end <= left_len {
+                        to_left.push(span);
+                    } else if span.start >= left_len {
+                        let (start, end) = (span.start - left_len, span.end - left_len);
+                        to_right.push(span.moved(start, end));
+                    } else {
+                        here.push(span);
+                    }
+                }
+
+                Self::concat_spanning(&Self::add_spans(left, to_left),
+                                      &Self::add_spans(right, to_right),
+                                      existing.with(here))
+            },
+        }
+    }
+
+    /// The spans stored in this node itself (none, for a `Flat` node),
+    /// offset by `offset`. Used when a `Concat` node is taken apart.
+    pub fn own_spans(&self, offset: usize) -> Vec<Span<M, V>> {
+        match *self {
+            Concat { ref spans, .. } => {
+                spans.by_start
+                     .iter()
+                     .map(|span| span.clone().moved(offset + span.start, offset + span.end))
+                     .collect()
+            },
+            Flat { .. } => Vec::new(),
+        }
+    }
+
+    /// Remove the span `span`, which must be relative to this node.
+    fn remove_span(node: &Shared<Self>, span: &Span<M, V>) -> Shared<Self> {
+        match **node {
+            Flat { ref data, ref markers, ref spans, ref anchors } => {
+                Shared::new(Flat {
+                    data: data.clone(),
+                    markers: markers.clone(),
+                    spans: spans.without(span.id),
+                    anchors: anchors.clone(),
+                })
+            },
+
+            Concat { left_len, ref left, ref right, ref spans, .. } => {
+                if span.end <= left_len {
+                    Self::concat_spanning(&Self::remove_span(left, span), right, spans.clone())
+                } else if span.start >= left_len {
+                    let moved = span.clone().moved(span.start - left_len, span.end - left_len);
+                    Self::concat_spanning(left, &Self::remove_span(right, &moved), spans.clone())
+                } else {
+                    Self::concat_spanning(left, right, spans.without(span.id))
+                }
+            },
+        }
+    }
+
+    fn find_span(&self, id: SpanId, offset: usize) -> Option<Span<M, V>> {
+        if self.span_count() == 0 {
+            return None;
+        }
+
+        match *self {
+            Flat { ref spans, .. } | Concat { ref spans, .. } if spans.contains(id) => {
+                spans.by_start
+                     .iter()
+                     .find(|span| span.id == id)
+                     .map(|span| span.clone().moved(offset + span.start, offset + span.end))
+            },
+
+            Flat { .. } => None,
+
+            Concat { left_len, ref left, ref right, .. } => {
+                left.find_span(id, offset)
+                    .or_else(|| right.find_span(id, offset + left_len))
+            },
+        }
+    }
+
+    /// Push every span overlapping `start..end` onto `found`, offset by
+    /// `offset`. Subtrees without spans are skipped, and so are nodes with
+    /// none of their own whose spans are all on one side: every node we go
+    /// into either overlaps an end of the range without being inside it, or
+    /// has spans to return, or has two children that do.
+    pub fn spans_overlapping(&self,
+                             start: usize,
+                             end: usize,
+                             offset: usize,
+                             found: &mut Vec<Span<M, V>>) {
+
+        if self.span_count() == 0 {
+            return;
+        }
+
+        match *self {
+            Flat { ref spans, .. } => {
+                found.extend(spans.by_start
+                                  .iter()
+                                  .filter(|span| span.start < end && span.end > start)
+                                  .map(|span| {
+                                      span.clone().moved(offset + span.start, offset + span.end)
+                                  }));
+            },
+
+            Concat { spans_below: Some((at, ref below)), .. } => {
+                if start < at + below.len() && end > at {
+                    below.spans_overlapping(start.saturating_sub(at),
+                                            min(end - at, below.len()),
+                                            offset + at,
+                                            found);
+                }
+            },
+
+            Concat { left_len, ref left, ref right, ref spans, .. } => {
+                spans.crossing(start, end, left_len, offset, found);
+
+                if start < left_len {
+                    left.spans_overlapping(start, min(end, left_len), offset, found);
+                }
+
+                if end > left_len {
+                    right.spans_overlapping(start.saturating_sub(left_len),
+                                            end - left_len,
+                                            offset + left_len,
+                                            found);
+                }
+            },
+        }
+    }
+}
+
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> Rope<T, M, V> {
+
+    /// Annotate the range `start..end` (`end` EXclusive), returning the new
+    /// rope and the id of the span within it.
+    pub fn add_span(&self, start: usize, end: usize, marker: M, value: V) -> (Self, SpanId) {
+        if start >= end || end > self.len() {
+            panic!("bad span indices: {}, {}", start, end);
+        }
+
+        let id = SpanId(NEXT_SPAN_ID.fetch_add(1, Ordering::Relaxed));
+        let span = Span { id, start, end, marker, value };
+
+        (self.with_root(Node::add_spans(&self.root, vec![span])), id)
+    }
+
+    /// A copy of this rope without the span `id`, if it has one.
+    pub fn remove_span(&self, id: SpanId) -> Self {
+        match self.span(id) {
+            None => self.clone(),
+            Some(span) => self.without_span(&span),
+        }
+    }
+
+    fn without_span(&self, span: &Span<M, V>) -> Self {
+        self.with_root(Node::remove_span(&self.root, span))
+    }
+
+    /// The span `id` with its current extent, if this rope has it.
+    pub fn span(&self, id: SpanId) -> Option<Span<M, V>> {
+        self.root.find_span(id, 0)
+    }
+
+    pub fn span_count(&self) -> usize {
+        self.root.span_count()
+    }
+
+    /// Every span overlapping `start..end` (`end` EXclusive), ordered by
+    /// start. Finding k results takes O(log n + k) time, and sorting them
+    /// O(k log k): besides the nodes along the ends of the range, we only go into nodes
+    /// with spans to return or that branch towards them. Leaves at the ends
+    /// of the range also check every span they hold.
+    pub fn spans_overlapping(&self, start: usize, end: usize) -> Vec<Span<M, V>> {
+        let mut found = Vec::new();
+
+        if start < end {
+            self.root.spans_overlapping(start, min(end, self.len()), 0, &mut found);
+        }
+
+        found.sort_by_key(|span| (span.start, span.id));
+        found
+    }
+
+    /// Replace `start..end` with `replacement` as `splice` does, keeping the
+    /// spans that strictly enclose the range whole.
+    pub(crate) fn splice_spans<F>(&self,
+                                  start: usize,
+                                  end: usize,
+                                  replacement_len: usize,
+                                  splice: F) -> Self
+        where F: FnOnce(&Self) -> Self {
+
+        let enclosing: Vec<Span<M, V>> =
+            if start == 0 || end >= self.len() {
+                Vec::new()
+            } else {
+                self.spans_overlapping(start - 1, end + 1)
+                    .into_iter()
+                    .filter(|span| span.start < start && span.end > end)
+                    .collect()
+            };
+
+        let without = enclosing.iter().fold(self.clone(), |rope, span| rope.without_span(span));
+        let spliced = splice(&without);
+
+        let stretched = enclosing.into_iter().map(|span| {
+            let (span_start, span_end) = (span.start, span.end - (end - start) + replacement_len);
+            span.moved(span_start, span_end)
+        }).collect();
+
+        spliced.with_root(Node::add_spans(&spliced.root, stretched))
+    }
+}
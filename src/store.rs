@@ -0,0 +1,470 @@
+//!
+//! A content-addressed store of rope nodes on disk, for history that
+//! survives restarts.
+//!
+//! Every node is saved in its own file, named by the SHA-256 hash of its
+//! encoding, and a `Concat` node's encoding includes the hashes of its
+//! children, so a rope is named by the hash of its root. Versions made from
+//! one another by persistent edits share most of their nodes, and saving a
+//! new version only writes the nodes the store doesn't have yet.
+//!
+//! Loading a version with `load` only reads the nodes that aren't already
+//! in memory: any subtree loaded or saved through the same store and still
+//! alive is reused, so loading the next version of a document reads about
+//! as much as saving it wrote. The nodes that are missing are all read up
+//! front, though, one file each.
+//!
+//! `load_lazy` reads just the root instead, and gives a `LazyRope` that
+//! rebuilds the tree a node at a time as it's used: `get` reads one path
+//! down the tree, `slice` the nodes over a range, and `rope` whatever's
+//! left. Any of them can find a node missing or corrupt, so they all return
+//! an `io::Result`. A `Rope` itself is always wholly in memory, since a rope
+//! operation has nowhere to report a failed read from halfway through.
+//!
+//! Nodes are written as in the snapshot format, and likewise spans, anchors
+//! and classifiers aren't saved.
+//!
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use std::cell::OnceCell;
+
+use super::{Link, Node, Shared, Rope, Weak};
+use super::Node::*;
+use super::anchors::Cuts;
+use super::codec::{self, Codec, DecodeError};
+use super::snapshot::{self, CONCAT, FLAT};
+
+/// The hash naming a node in a `NodeStore`, written out in hex.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeHash([u8; 32]);
+
+impl fmt::Display for NodeHash {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl fmt::Debug for NodeHash {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NodeHash({})", self)
+    }
+}
+
+impl FromStr for NodeHash {
+    type Err = DecodeError;
+
+    fn from_str(hex: &str) -> Result<Self, DecodeError> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(DecodeError::Corrupt("a node hash is 64 hex digits"));
+        }
+
+        let mut hash = [0; 32];
+
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                .map_err(|_| DecodeError::Corrupt("a node hash is 64 hex digits"))?;
+        }
+
+        Ok(NodeHash(hash))
+    }
+}
+
+// node addresses to the node and its hash
+type Hashes<T, M, V> = HashMap<*const Node<T, M, V>, (Weak<Node<T, M, V>>, NodeHash)>;
+
+fn invalid(error: DecodeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn node_path(dir: &Path, hash: &NodeHash) -> PathBuf {
+    let hex = hash.to_string();
+    dir.join(&hex[..2]).join(&hex[2..])
+}
+
+/// A node as read from its file: a whole `Flat` node, or a `Concat` node's
+/// children and the rest of its encoding, for `snapshot::read_concat`.
+enum Encoded<T, M, V> {
+    Flat(Node<T, M, V>),
+    Concat([NodeHash; 2], Vec<u8>),
+}
+
+fn read_encoded<T, M, V>(dir: &Path, hash: &NodeHash) -> io::Result<Encoded<T, M, V>>
+    where T: Clone + Codec, M: Eq + Hash + Copy + Codec, V: Clone + Codec {
+
+    let bytes = fs::read(node_path(dir, hash))?;
+
+    if codec::sha256(&bytes) != hash.0 {
+        return Err(invalid(DecodeError::BadChecksum));
+    }
+
+    let mut input = &bytes[..];
+
+    match codec::read_byte(&mut input).map_err(invalid)? {
+        FLAT => {
+            let node = snapshot::read_flat(&mut input).map_err(invalid)?;
+            finished(input)?;
+            Ok(Encoded::Flat(node))
+        },
+
+        CONCAT => {
+            let mut children = [NodeHash([0; 32]); 2];
+
+            for hash in &mut children {
+                hash.0.copy_from_slice(codec::read_bytes(&mut input, 32).map_err(invalid)?);
+            }
+
+            Ok(Encoded::Concat(children, input.to_vec()))
+        },
+
+        _ => Err(invalid(DecodeError::Corrupt("unknown node type"))),
+    }
+}
+
+/// Finish a `Concat` node read by `read_encoded`, given its children.
+fn read_concat<T, M, V>(fields: &[u8],
+                        left: Link<T, M, V>,
+                        right: Link<T, M, V>) -> io::Result<Node<T, M, V>>
+    where T: Clone + Codec, M: Eq + Hash + Copy + Codec, V: Clone + Codec {
+
+    let mut input = fields;
+    let node = snapshot::read_concat(&mut input, left, right).map_err(invalid)?;
+    finished(input)?;
+    Ok(node)
+}
+
+fn finished(input: &[u8]) -> io::Result<()> {
+    if input.is_empty() {
+        Ok(())
+    } else {
+        Err(invalid(DecodeError::Corrupt("trailing data")))
+    }
+}
+
+/// Make new and renamed entries in `dir` durable. Only Unix lets us open a
+/// directory to sync it; elsewhere that's left to the filesystem.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+pub struct NodeStore<T, M = (), V = ()> {
+    dir: PathBuf,
+
+    // the nodes in memory we know the hashes of, which are all in the store.
+    // The weak references keep the addresses from being reused.
+    hashes: Hashes<T, M, V>,
+    loaded: HashMap<NodeHash, Weak<Node<T, M, V>>>,
+}
+
+impl<T, M, V> NodeStore<T, M, V>
+    where T: Clone + Codec, M: Eq + Hash + Copy + Codec, V: Clone + Codec {
+
+    /// Use the store in the directory `dir`, creating it if need be.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+
+        Ok(NodeStore {
+            dir: dir.as_ref().to_path_buf(),
+            hashes: HashMap::new(),
+            loaded: HashMap::new(),
+        })
+    }
+
+    fn path(&self, hash: &NodeHash) -> PathBuf {
+        node_path(&self.dir, hash)
+    }
+
+    pub fn contains(&self, hash: &NodeHash) -> bool {
+        self.path(hash).is_file()
+    }
+
+    /// Save `rope`, writing any of its nodes the store doesn't have yet, and
+    /// return the hash to load it by.
+    pub fn save(&mut self, rope: &Rope<T, M, V>) -> io::Result<NodeHash> {
+        self.hashes.retain(|_, entry| entry.0.strong_count() > 0);
+        self.loaded.retain(|_, node| node.strong_count() > 0);
+
+        self.save_node(&rope.root)
+    }
+
+    fn save_node(&mut self, node: &Link<T, M, V>) -> io::Result<NodeHash> {
+        if let Some(&(_, hash)) = self.hashes.get(&Shared::as_ptr(node)) {
+            return Ok(hash);
+        }
+
+        let mut bytes = Vec::new();
+
+        match **node {
+            Flat { ref data, ref markers, .. } => snapshot::write_flat(&mut bytes, data, markers),
+
+            Concat { ref left, ref right, .. } => {
+                let left = self.save_node(left)?;
+                let right = self.save_node(right)?;
+
+                bytes.push(CONCAT);
+                bytes.extend_from_slice(&left.0);
+                bytes.extend_from_slice(&right.0);
+                snapshot::write_concat_fields(&mut bytes, node);
+            },
+        }
+
+        let hash = NodeHash(codec::sha256(&bytes));
+        let path = self.path(&hash);
+
+        if !path.is_file() {
+            // write it under another name and get it onto the disk first, so
+            // a crash or power cut can't leave a partial node behind under
+            // the real name, which we'd never rewrite
+            let dir = path.parent().unwrap();
+            let temp = dir.join(format!("{}.tmp", hash));
+
+            if !dir.is_dir() {
+                fs::create_dir_all(dir)?;
+                sync_dir(&self.dir)?;
+            }
+
+            let mut file = File::create(&temp)?;
+            file.write_all(&bytes)?;
+            file.sync_all()?;
+
+            fs::rename(&temp, &path)?;
+            sync_dir(dir)?;
+        }
+
+        self.remember(node, hash);
+        Ok(hash)
+    }
+
+    fn remember(&mut self, node: &Link<T, M, V>, hash: NodeHash) {
+        self.hashes.insert(Shared::as_ptr(node), (Shared::downgrade(node), hash));
+        self.loaded.insert(hash, Shared::downgrade(node));
+    }
+
+    /// Load the rope saved as `hash`, sharing any nodes already in memory
+    /// and reading all the others.
+    pub fn load(&mut self, hash: &NodeHash) -> io::Result<Rope<T, M, V>> {
+        Ok(Rope { root: self.load_node(hash)?, classifier: None })
+    }
+
+    fn load_node(&mut self, hash: &NodeHash) -> io::Result<Link<T, M, V>> {
+        if let Some(node) = self.loaded.get(hash).and_then(Weak::upgrade) {
+            return Ok(node);
+        }
+
+        let node = match read_encoded(&self.dir, hash)? {
+            Encoded::Flat(node) => node,
+
+            Encoded::Concat(children, fields) => {
+                let left = self.load_node(&children[0])?;
+                let right = self.load_node(&children[1])?;
+                read_concat(&fields, left, right)?
+            },
+        };
+
+        let node = Shared::new(node);
+        self.remember(&node, *hash);
+        Ok(node)
+    }
+
+    /// Read just the root of the rope saved as `hash`, leaving the rest to
+    /// be read as it's needed. Unlike `load`, nothing is shared with the
+    /// nodes already in memory.
+    pub fn load_lazy(&self, hash: &NodeHash) -> io::Result<LazyRope<T, M, V>> {
+        let root = LazyNode::new(*hash);
+        let len = root.contents(&self.dir)?.len();
+
+        Ok(LazyRope { dir: self.dir.clone(), root, len })
+    }
+}
+
+/// A rope in a `NodeStore`, rebuilt a node at a time as it's used. See
+/// `NodeStore::load_lazy`.
+pub struct LazyRope<T, M = (), V = ()> {
+    dir: PathBuf,
+    root: LazyNode<T, M, V>,
+    len: usize,
+}
+
+struct LazyNode<T, M, V> {
+    hash: NodeHash,
+    contents: OnceCell<Contents<T, M, V>>,
+
+    // this node as a rope node, once everything under it has been read
+    built: OnceCell<Link<T, M, V>>,
+}
+
+enum Contents<T, M, V> {
+    Flat(Link<T, M, V>),
+
+    Concat {
+        left_len: usize,
+        len: usize,
+        left: Box<LazyNode<T, M, V>>,
+        right: Box<LazyNode<T, M, V>>,
+        fields: Vec<u8>,
+    },
+}
+
+impl<T, M, V> Contents<T, M, V>
+    where T: Clone + Codec, M: Eq + Hash + Copy + Codec, V: Clone + Codec {
+
+    fn len(&self) -> usize {
+        match *self {
+            Contents::Flat(ref node) => node.len(),
+            Contents::Concat { len, .. } => len,
+        }
+    }
+}
+
+impl<T, M, V> LazyNode<T, M, V>
+    where T: Clone + Codec, M: Eq + Hash + Copy + Codec, V: Clone + Codec {
+
+    fn new(hash: NodeHash) -> Self {
+        LazyNode { hash, contents: OnceCell::new(), built: OnceCell::new() }
+    }
+
+    /// This node's own file, read the first time it's asked for.
+    fn contents(&self, dir: &Path) -> io::Result<&Contents<T, M, V>> {
+        if let Some(contents) = self.contents.get() {
+            return Ok(contents);
+        }
+
+        let contents = match read_encoded(dir, &self.hash)? {
+            Encoded::Flat(node) => Contents::Flat(Shared::new(node)),
+
+            Encoded::Concat(children, fields) => {
+                let mut input = &fields[..];
+                let _depth = codec::read_usize(&mut input).map_err(invalid)?;
+                let left_len = codec::read_usize(&mut input).map_err(invalid)?;
+                let len = codec::read_usize(&mut input).map_err(invalid)?;
+
+                Contents::Concat {
+                    left_len,
+                    len,
+                    left: Box::new(LazyNode::new(children[0])),
+                    right: Box::new(LazyNode::new(children[1])),
+                    fields,
+                }
+            },
+        };
+
+        Ok(self.contents.get_or_init(|| contents))
+    }
+
+    /// The whole subtree, reading whatever hasn't been read yet.
+    fn build(&self, dir: &Path) -> io::Result<Link<T, M, V>> {
+        if let Some(node) = self.built.get() {
+            return Ok(node.clone());
+        }
+
+        let node = match *self.contents(dir)? {
+            Contents::Flat(ref node) => node.clone(),
+
+            Contents::Concat { ref left, ref right, ref fields, .. } => {
+                Shared::new(read_concat(fields, left.build(dir)?, right.build(dir)?)?)
+            },
+        };
+
+        Ok(self.built.get_or_init(|| node).clone())
+    }
+
+    /// The values `start..end` of this subtree, reading only the nodes
+    /// they're in.
+    fn build_range(&self, dir: &Path, start: usize, end: usize) -> io::Result<Link<T, M, V>> {
+        match *self.contents(dir)? {
+            ref contents if start == 0 && end == contents.len() => self.build(dir),
+
+            Contents::Flat(ref node) => {
+                Ok(Node::slice(node, start, end, Cuts { start: false, end: false }))
+            },
+
+            Contents::Concat { left_len, ref left, ref right, .. } => {
+                if end <= left_len {
+                    left.build_range(dir, start, end)
+                } else if start >= left_len {
+                    right.build_range(dir, start - left_len, end - left_len)
+                } else {
+                    Ok(Node::concat(&left.build_range(dir, start, left_len)?,
+                                    &right.build_range(dir, 0, end - left_len)?))
+                }
+            },
+        }
+    }
+}
+
+impl<T, M, V> LazyRope<T, M, V>
+    where T: Clone + Codec, M: Eq + Hash + Copy + Codec, V: Clone + Codec {
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The value at `index`, reading the nodes on the way down to it. `None`
+    /// if `index` is out of bounds.
+    pub fn get(&self, index: usize) -> io::Result<Option<&T>> {
+        if index >= self.len {
+            return Ok(None);
+        }
+
+        let (mut node, mut index) = (&self.root, index);
+
+        loop {
+            match *node.contents(&self.dir)? {
+                Contents::Flat(ref leaf) => {
+                    return match **leaf {
+                        Flat { ref data, .. } if index < data.len() => Ok(Some(&data[index])),
+                        _ => Err(invalid(DecodeError::Corrupt("node doesn't match its children"))),
+                    };
+                },
+
+                Contents::Concat { left_len, ref left, ref right, .. } => {
+                    if index < left_len {
+                        node = left;
+                    } else {
+                        node = right;
+                        index -= left_len;
+                    }
+                },
+            }
+        }
+    }
+
+    /// The values `start..end` (`end` EXclusive) as a `Rope`, reading only
+    /// the nodes they're in.
+    pub fn slice(&self, start: usize, end: usize) -> io::Result<Rope<T, M, V>> {
+        if start > end || end > self.len {
+            panic!("bad slice indices: {}, {}", start, end);
+        }
+
+        let root = if start == end {
+            Rope::new(&[]).root
+        } else {
+            self.root.build_range(&self.dir, start, end)?
+        };
+
+        Ok(Rope { root, classifier: None })
+    }
+
+    /// The whole rope, reading every node that hasn't been read yet.
+    pub fn rope(&self) -> io::Result<Rope<T, M, V>> {
+        Ok(Rope { root: self.root.build(&self.dir)?, classifier: None })
+    }
+}
@@ -1,4 +1,9 @@
 use super::*;
+use std::hash::Hash;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
 
 pub fn sample_flat_rope() -> Rope<usize> {
     Rope::new(&vec![0, 1, 2])
@@ -12,6 +17,54 @@ pub fn sample_deep_rope() -> Rope<usize> {
     Rope::concat(&Rope::new(v1), &Rope::concat(&Rope::new(v2), &Rope::new(v3)))
 }
 
+pub fn as_vec<T: Clone, M: Eq + Hash + Copy, V: Clone>(rope: &Rope<T, M, V>) -> Vec<T> {
+    rope.iter().cloned().collect()
+}
+
+/// A path in the temp directory for the test called `name`, with nothing
+/// left there from an earlier run.
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("rope-{}-{}", name, std::process::id()));
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_dir_all(&path);
+    path
+}
+
+/// A tiny deterministic random number generator, for shuffling and
+/// generating edits.
+pub struct Lcg(u64);
+
+impl Lcg {
+
+    pub fn below(&mut self, n: usize) -> usize {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((self.0 >> 33) as usize) % n.max(1)
+    }
+
+    pub fn shuffle<T>(&mut self, values: &mut [T]) {
+        for i in (1..values.len()).rev() {
+            let j = self.below(i + 1);
+            values.swap(i, j);
+        }
+    }
+}
+
+/// The number of distinct nodes in `ropes`, counting shared ones once.
+pub fn node_count<T, M, V>(ropes: &[Rope<T, M, V>]) -> usize {
+    fn visit<T, M, V>(node: &Link<T, M, V>, seen: &mut HashSet<*const Node<T, M, V>>) {
+        if seen.insert(Shared::as_ptr(node)) {
+            if let Node::Concat { ref left, ref right, .. } = **node {
+                visit(left, seen);
+                visit(right, seen);
+            }
+        }
+    }
+
+    let mut seen = HashSet::new();
+    ropes.iter().for_each(|rope| visit(&rope.root, &mut seen));
+    seen.len()
+}
+
 #[test]
 fn length() {
     let empty_rope: Rope<usize> = Rope::new(&(Vec::new()));
@@ -97,6 +150,132 @@ mod slice {
         let sub = base.slice(1, 5);
         assert_eq!(vec![1, 2, 3, 4], sub.iter().cloned().collect::<Vec<usize>>());
     }
+
+    #[test]
+    fn right_side() {
+        let base = sample_deep_rope();
+        let sub = base.slice(4, 8);
+        assert_eq!(vec![4, 5, 6, 7], sub.iter().cloned().collect::<Vec<usize>>());
+    }
+}
+
+mod construction {
+
+    use super::*;
+
+    fn chunk_of(values: &[usize]) -> Chunk<usize, ()> {
+        let mut chunk = Chunk::with_capacity(values.len());
+        chunk.extend_from_slice(values);
+        chunk
+    }
+
+    #[test]
+    fn leaf_size() {
+        let data: Vec<usize> = (0..100).collect();
+        let rope: Rope<usize> = Rope::from_slice_with_leaf_size(&data, 10);
+
+        // 10 leaves need 4 levels of concats
+        assert_eq!(4, rope.depth());
+        assert_eq!(data, rope.iter().cloned().collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn from_iter() {
+        let rope: Rope<usize> = (0..DEFAULT_LEAF_SIZE * 3 + 1).collect();
+
+        assert_eq!(2, rope.depth());
+        assert_eq!(DEFAULT_LEAF_SIZE * 3 + 1, rope.len());
+        assert_eq!(DEFAULT_LEAF_SIZE * 2, rope[DEFAULT_LEAF_SIZE * 2]);
+    }
+
+    #[test]
+    fn chunk_iter() {
+        let chunks = vec![chunk_of(&[0, 1]), chunk_of(&[]), chunk_of(&[2]), chunk_of(&[3, 4])];
+        let rope = Rope::from_chunk_iter(chunks);
+
+        assert_eq!(2, rope.depth());
+        assert_eq!(vec![0, 1, 2, 3, 4], rope.iter().cloned().collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn empty() {
+        let no_chunks: Result<Rope<usize>, ()> = Rope::from_chunks(|| Ok(None));
+        assert!(no_chunks.unwrap().is_empty());
+
+        let no_values: Rope<usize> = Rope::from_iter_balanced(Vec::new());
+        assert!(no_values.is_empty());
+        assert!(Rope::<usize>::from_slice_with_leaf_size(&[], 4).is_empty());
+    }
+
+    #[test]
+    fn loader_error() {
+        let mut calls = 0;
+        let result: Result<Rope<usize>, &str> = Rope::from_chunks(|| {
+            calls += 1;
+            if calls < 3 { Ok(Some(chunk_of(&[calls]))) } else { Err("oops") }
+        });
+
+        assert_eq!(Some("oops"), result.err());
+    }
+}
+
+mod edit {
+
+    use super::*;
+
+    #[test]
+    fn insert() {
+        let rope = sample_deep_rope().insert(4, &[10, 11]);
+        assert_eq!(vec![0, 1, 2, 3, 10, 11, 4, 5, 6, 7, 8],
+                   rope.iter().cloned().collect::<Vec<usize>>());
+
+        let at_end = sample_flat_rope().insert(3, &[3]);
+        assert_eq!(vec![0, 1, 2, 3], at_end.iter().cloned().collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn remove() {
+        let rope = sample_deep_rope().remove(2, 7);
+        assert_eq!(vec![0, 1, 7, 8], rope.iter().cloned().collect::<Vec<usize>>());
+        assert!(sample_deep_rope().remove(0, 9).is_empty());
+    }
+
+    #[test]
+    fn replace() {
+        let rope = sample_deep_rope().replace(1, 8, &[10]);
+        assert_eq!(vec![0, 10, 8], rope.iter().cloned().collect::<Vec<usize>>());
+    }
+
+    #[test]
+    #[should_panic]
+    fn remove_out_of_bounds() {
+        sample_flat_rope().remove(2, 4);
+    }
+
+    #[test]
+    fn many_single_inserts() {
+        let count = 100_000;
+        let mut random = Lcg(3);
+        let mut rope: Rope<usize> = Rope::new(&[]);
+        let mut expected: Vec<usize> = Vec::new();
+
+        // typing at the end, in the middle and all over the place
+        for i in 0..count {
+            let at = match i % 3 {
+                0 => i,
+                1 => i / 2,
+                _ => random.below(i + 1),
+            };
+
+            rope = rope.insert(at, &[i]);
+            expected.insert(at, i);
+        }
+
+        // 100k leaves would take 17 levels if perfectly balanced
+        assert!(rope.depth() <= 2 * 17);
+        assert_eq!(expected, as_vec(&rope));
+        drop(rope);
+    }
 }
 
 mod markers {
@@ -159,6 +338,22 @@ mod markers {
                    deep_marked_rope().slice(1, 6).marker_counts().get(&Marker{}));
     }
 
+    #[test]
+    fn slice_indices() {
+        let sliced = deep_marked_rope().slice(2, 9);
+        assert_eq!(Some(2), sliced.index_for_nth_marker(Marker {}, 0));
+        assert_eq!(Some(4), sliced.index_for_nth_marker(Marker {}, 1));
+        assert_eq!(Some(6), sliced.index_for_nth_marker(Marker {}, 2));
+    }
+
+    #[test]
+    fn flatten() {
+        let flat = deep_marked_rope().flatten();
+        assert_eq!(0, flat.depth());
+        assert_eq!(Some(&4), flat.marker_counts().get(&Marker{}));
+        assert_eq!(Some(6), flat.index_for_nth_marker(Marker {}, 2));
+    }
+
     #[test]
     fn flat_count() {
         assert_eq!(Some(&1), flat_marked_rope().marker_counts().get(&Marker{}));
@@ -169,3 +364,2202 @@ mod markers {
         assert_eq!(Some(&4), deep_marked_rope().marker_counts().get(&Marker{}));
     }
 }
+
+mod classifier {
+
+    use super::*;
+
+    #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+    enum Kind {
+        Newline,
+        Space,
+        Whitespace,
+    }
+
+    fn newlines() -> Classifier<char, Kind> {
+        Classifier::new(|&c: &char| if c == '\n' { Some(Kind::Newline) } else { None })
+    }
+
+    fn chars(text: &str) -> Vec<char> {
+        text.chars().collect()
+    }
+
+    fn classified_rope(text: &str, classifier: &Classifier<char, Kind>) -> Rope<char, Kind> {
+        let mut chunk = Chunk::with_classifier(text.len(), classifier);
+        chunk.extend_from_slice(&chars(text));
+        Rope::from_chunk(chunk)
+    }
+
+    #[test]
+    fn chunk() {
+        let rope = classified_rope("a\nbc\n\n", &newlines());
+        assert_eq!(3, rope.marker_count(Kind::Newline));
+        assert_eq!(Some(4), rope.index_for_nth_marker(Kind::Newline, 1));
+    }
+
+    #[test]
+    fn multi() {
+        let whitespace = Classifier::multi(|&c: &char, mark: &mut dyn FnMut(Kind)| {
+            if c.is_whitespace() {
+                mark(Kind::Whitespace);
+            }
+            if c == ' ' {
+                mark(Kind::Space);
+            }
+        });
+
+        let rope = classified_rope("a b\nc", &whitespace);
+        assert_eq!(2, rope.marker_count(Kind::Whitespace));
+        assert_eq!(1, rope.marker_count(Kind::Space));
+    }
+
+    #[test]
+    fn from_chunks() {
+        let classifier = newlines();
+        let mut texts = vec!["c\n", "\nb", "a\n"];
+
+        let rope: Rope<char, Kind> = Rope::from_chunks(|| {
+            Ok::<_, ()>(texts.pop().map(|text| {
+                let mut chunk = Chunk::with_classifier(2, &classifier);
+                chunk.extend_from_slice(&chars(text));
+                chunk
+            }))
+        }).unwrap();
+
+        assert_eq!(3, rope.marker_count(Kind::Newline));
+        assert_eq!(Some(5), rope.index_for_nth_marker(Kind::Newline, 2));
+        assert!(rope.classifier().is_some());
+    }
+
+    #[test]
+    fn edits() {
+        let rope = classified_rope("ab\ncd", &newlines());
+
+        let inserted = rope.insert(1, &chars("\n\n"));
+        assert_eq!(3, inserted.marker_count(Kind::Newline));
+        assert_eq!(Some(2), inserted.index_for_nth_marker(Kind::Newline, 1));
+
+        let replaced = inserted.replace(0, 3, &chars("xyz\n"));
+        assert_eq!(Some(3), replaced.index_for_nth_marker(Kind::Newline, 0));
+        assert_eq!(Some(5), replaced.index_for_nth_marker(Kind::Newline, 1));
+        assert_eq!(2, replaced.marker_count(Kind::Newline));
+
+        let removed = replaced.remove(2, 5);
+        assert_eq!(1, removed.marker_count(Kind::Newline));
+    }
+
+    #[test]
+    fn concat() {
+        let classified = classified_rope("a\n", &newlines());
+        let plain: Rope<char, Kind> = Rope::new(&chars("\nb\n"));
+
+        assert_eq!(0, plain.marker_count(Kind::Newline));
+        assert_eq!(3, Rope::concat(&classified, &plain).marker_count(Kind::Newline));
+        assert_eq!(3, Rope::concat(&plain, &classified).marker_count(Kind::Newline));
+        assert!(Rope::concat(&plain, &classified).classifier().is_some());
+    }
+
+    #[test]
+    fn concat_marks_plain_side() {
+        let mut chunk = Chunk::with_capacity(4);
+        chunk.extend_from_slice(&chars("b\nc\n"));
+        chunk.mark_at(Kind::Space, 0);
+
+        let joined = Rope::concat(&classified_rope("a\n", &newlines()), &Rope::from_chunk(chunk));
+        assert_eq!(3, joined.marker_count(Kind::Newline));
+        assert_eq!(0, joined.marker_count(Kind::Space));
+
+        let grown = joined.insert(6, &chars("\n"));
+        assert_eq!(Some(6), grown.index_for_nth_marker(Kind::Newline, 3));
+    }
+
+    #[test]
+    fn reclassify() {
+        let mut chunk = Chunk::with_capacity(3);
+        chunk.extend_from_slice(&chars("a b"));
+        chunk.mark_at(Kind::Space, 0);
+
+        let rope = Rope::from_chunk(chunk).classified(&newlines());
+        assert_eq!(0, rope.marker_count(Kind::Space));
+
+        let grown = rope.insert(3, &chars("\n"));
+        assert_eq!(Some(3), grown.index_for_nth_marker(Kind::Newline, 0));
+    }
+}
+
+mod annotations {
+
+    use super::*;
+
+    #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+    enum Key {
+        Error,
+        Warning,
+    }
+
+    fn annotated_rope() -> Rope<usize, Key, String> {
+        let mut left = Chunk::with_capacity(4);
+        left.extend_from_slice(&[0, 1, 2, 3]);
+        left.annotate(1, Key::Error, "bad".to_string());
+        left.annotate(3, Key::Warning, "iffy".to_string());
+
+        let mut right = Chunk::with_capacity(4);
+        right.extend_from_slice(&[4, 5, 6, 7]);
+        right.annotate(0, Key::Error, "worse".to_string());
+        right.annotate(2, Key::Error, "worst".to_string());
+
+        Rope::concat(&Rope::from_chunk(left), &Rope::from_chunk(right))
+    }
+
+    #[test]
+    fn nth() {
+        let rope = annotated_rope();
+        assert_eq!(3, rope.marker_count(Key::Error));
+        assert_eq!(Some((4, &"worse".to_string())), rope.nth_annotation(Key::Error, 1));
+        assert_eq!(Some((3, &"iffy".to_string())), rope.nth_annotation(Key::Warning, 0));
+        assert_eq!(None, rope.nth_annotation(Key::Warning, 1));
+    }
+
+    #[test]
+    fn at() {
+        let rope = annotated_rope();
+        assert_eq!(Some(&"worst".to_string()), rope.annotation_at(Key::Error, 6));
+        assert_eq!(None, rope.annotation_at(Key::Warning, 6));
+        assert_eq!(None, rope.annotation_at(Key::Error, 5));
+    }
+
+    #[test]
+    fn range() {
+        let rope = annotated_rope();
+        let found: Vec<usize> = rope.annotations(Key::Error, 1, 6).iter().map(|&(i, _)| i).collect();
+        assert_eq!(vec![1, 4], found);
+        assert!(rope.annotations(Key::Warning, 4, 100).is_empty());
+
+        // starting past the end finds nothing
+        let len = rope.len();
+        assert!(rope.annotations(Key::Error, len + 2, len + 12).is_empty());
+    }
+
+    #[test]
+    fn persistence() {
+        let rope = annotated_rope();
+        let edited = rope.slice(2, 8).insert(0, &[9]);
+
+        assert_eq!(Some((3, &"worse".to_string())), edited.nth_annotation(Key::Error, 0));
+        assert_eq!(Some(&"iffy".to_string()), edited.annotation_at(Key::Warning, 2));
+        assert_eq!(Some((1, &"bad".to_string())), rope.nth_annotation(Key::Error, 0));
+    }
+
+    #[test]
+    fn replace_value() {
+        let mut chunk: Chunk<usize, Key, u32> = Chunk::with_capacity(1);
+        chunk.push(0);
+        chunk.annotate(0, Key::Error, 1);
+        chunk.annotate(0, Key::Error, 2);
+
+        let rope = Rope::from_chunk(chunk);
+        assert_eq!(1, rope.marker_count(Key::Error));
+        assert_eq!(Some(&2), rope.annotation_at(Key::Error, 0));
+    }
+}
+
+mod spans {
+
+    use super::*;
+
+    fn extents(spans: &[Span<char, u32>]) -> Vec<(usize, usize, u32)> {
+        spans.iter().map(|span| (span.start, span.end, span.value)).collect()
+    }
+
+    fn spanned_rope() -> (Rope<usize, char, u32>, Vec<SpanId>) {
+        let data: Vec<usize> = (0..20).collect();
+        let rope = Rope::from_slice_with_leaf_size(&data, 4);
+
+        let (rope, a) = rope.add_span(1, 3, 'a', 0);
+        let (rope, b) = rope.add_span(2, 13, 'b', 1);
+        let (rope, c) = rope.add_span(8, 12, 'c', 2);
+        let (rope, d) = rope.add_span(15, 20, 'd', 3);
+
+        (rope, vec![a, b, c, d])
+    }
+
+    #[test]
+    fn overlapping() {
+        let (rope, _) = spanned_rope();
+        assert_eq!(4, rope.span_count());
+        assert_eq!(vec![(2, 13, 1), (8, 12, 2)], extents(&rope.spans_overlapping(5, 10)));
+        assert_eq!(vec![(1, 3, 0), (2, 13, 1)], extents(&rope.spans_overlapping(0, 3)));
+        assert_eq!(vec![(15, 20, 3)], extents(&rope.spans_overlapping(13, 100)));
+        assert!(rope.spans_overlapping(13, 15).is_empty());
+        assert!(rope.spans_overlapping(5, 5).is_empty());
+    }
+
+    #[test]
+    fn persistent() {
+        let (rope, ids) = spanned_rope();
+        let removed = rope.remove_span(ids[1]);
+
+        assert_eq!(3, removed.span_count());
+        assert_eq!(None, removed.span(ids[1]));
+        assert_eq!(Some((2, 13)), rope.span(ids[1]).map(|span| (span.start, span.end)));
+    }
+
+    #[test]
+    fn slice_and_concat() {
+        let (rope, ids) = spanned_rope();
+        let sliced = rope.slice(10, 18);
+
+        assert_eq!(vec![(0, 3, 1), (0, 2, 2), (5, 8, 3)],
+                   extents(&sliced.spans_overlapping(0, 8)));
+
+        let joined = Rope::concat(&sliced, &sliced);
+        assert_eq!(6, joined.span_count());
+        assert_eq!(vec![(8, 11, 1), (8, 10, 2), (13, 16, 3)],
+                   extents(&joined.spans_overlapping(8, 16)));
+        assert_eq!(ids[0], rope.spans_overlapping(0, 2)[0].id);
+    }
+
+    #[test]
+    fn insert() {
+        let (rope, ids) = spanned_rope();
+        let inserted = rope.insert(8, &[100, 101]);
+
+        let span = |id| inserted.span(id).map(|span| (span.start, span.end));
+        assert_eq!(Some((1, 3)), span(ids[0]));
+        assert_eq!(Some((2, 15)), span(ids[1]));
+        assert_eq!(Some((10, 14)), span(ids[2]));
+        assert_eq!(Some((17, 22)), span(ids[3]));
+        assert_eq!(4, inserted.span_count());
+    }
+
+    #[test]
+    fn remove() {
+        let (rope, ids) = spanned_rope();
+        let removed = rope.remove(2, 9);
+
+        let span = |id| removed.span(id).map(|span| (span.start, span.end));
+        assert_eq!(Some((1, 2)), span(ids[0]));
+        assert_eq!(Some((2, 6)), span(ids[1]));
+        assert_eq!(Some((2, 5)), span(ids[2]));
+        assert_eq!(Some((8, 13)), span(ids[3]));
+
+        // removing the whole of a span drops it
+        assert_eq!(None, rope.remove(14, 20).span(ids[3]));
+    }
+
+    #[test]
+    fn flatten() {
+        let (rope, _) = spanned_rope();
+        let flat = rope.flatten();
+
+        assert_eq!(0, flat.depth());
+        assert_eq!(extents(&rope.spans_overlapping(0, 20)),
+                   extents(&flat.spans_overlapping(0, 20)));
+    }
+
+    #[test]
+    fn sparse() {
+        let data: Vec<usize> = (0..2000).collect();
+        let mut rope: Rope<usize, char, u32> = Rope::from_slice_with_leaf_size(&data, 4);
+        let mut random = Lcg(7);
+        let mut ids = vec![];
+
+        // a few short spans far apart, so most nodes have none of their own
+        for value in 0..20 {
+            let start = random.below(1990);
+            let (spanned, id) = rope.add_span(start, start + 1 + random.below(8), 'a', value);
+            rope = spanned;
+            ids.push(id);
+        }
+
+        for _ in 0..100 {
+            let at = random.below(rope.len());
+            rope = if random.below(2) == 0 { rope.insert(at, &[0, 0]) } else { rope.remove(at, at + 1) };
+
+            let start = random.below(rope.len());
+            let end = start + random.below(300);
+            let mut expected: Vec<Span<char, u32>> =
+                ids.iter()
+                   .filter_map(|&id| rope.span(id))
+                   .filter(|span| span.start < end && span.end > start)
+                   .collect();
+            expected.sort_by_key(|span| (span.start, span.id));
+
+            assert_eq!(extents(&expected), extents(&rope.spans_overlapping(start, end)));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn empty_span() {
+        sample_flat_rope().add_span(1, 1, (), ());
+    }
+}
+
+mod anchors {
+
+    use super::*;
+
+    fn anchored_rope() -> (Rope<usize>, AnchorId, AnchorId) {
+        let data: Vec<usize> = (0..12).collect();
+        let rope = Rope::from_slice_with_leaf_size(&data, 4);
+
+        let (rope, left) = rope.add_anchor(4, Bias::Left);
+        let (rope, right) = rope.add_anchor(4, Bias::Right);
+        (rope, left, right)
+    }
+
+    #[test]
+    fn resolve() {
+        let (rope, left, right) = anchored_rope();
+        assert_eq!(Some(4), rope.resolve(left));
+        assert_eq!(Some(4), rope.resolve(right));
+
+        let (rope, end) = rope.add_anchor(12, Bias::Right);
+        assert_eq!(Some(12), rope.resolve(end));
+        assert_eq!(None, rope.remove_anchor(end).resolve(end));
+    }
+
+    #[test]
+    fn insert() {
+        let (rope, left, right) = anchored_rope();
+
+        let at_anchor = rope.insert(4, &[100, 101]);
+        assert_eq!(Some(4), at_anchor.resolve(left));
+        assert_eq!(Some(6), at_anchor.resolve(right));
+
+        let before = rope.insert(1, &[100]);
+        assert_eq!(Some(5), before.resolve(left));
+        assert_eq!(Some(5), before.resolve(right));
+
+        let after = rope.insert(9, &[100]);
+        assert_eq!(Some(4), after.resolve(left));
+    }
+
+    #[test]
+    fn remove() {
+        let (rope, left, right) = anchored_rope();
+
+        let around = rope.remove(2, 7);
+        assert_eq!(Some(2), around.resolve(left));
+        assert_eq!(Some(2), around.resolve(right));
+
+        let replaced = rope.replace(2, 7, &[100, 101, 102]);
+        assert_eq!(Some(2), replaced.resolve(left));
+        assert_eq!(Some(5), replaced.resolve(right));
+
+        let before = rope.remove(0, 4);
+        assert_eq!(Some(0), before.resolve(left));
+        assert_eq!(Some(0), before.resolve(right));
+
+        let everything = rope.remove(0, 12);
+        assert!(everything.is_empty());
+        assert_eq!(Some(0), everything.resolve(right));
+        assert_eq!(Some(1), everything.insert(0, &[5]).resolve(right));
+    }
+
+    #[test]
+    fn slice() {
+        let (rope, left, right) = anchored_rope();
+
+        let after = rope.slice(4, 12);
+        assert_eq!(None, after.resolve(left));
+        assert_eq!(Some(0), after.resolve(right));
+
+        let before = rope.slice(1, 4);
+        assert_eq!(Some(3), before.resolve(left));
+        assert_eq!(None, before.resolve(right));
+
+        let joined = Rope::concat(&before, &after);
+        assert_eq!(Some(3), joined.resolve(left));
+        assert_eq!(Some(3), joined.resolve(right));
+    }
+
+    #[test]
+    fn many_versions() {
+        let (mut rope, _, right) = anchored_rope();
+        let original = rope.clone();
+
+        for i in 0..20 {
+            rope = rope.insert(i % 3, &[i]);
+        }
+
+        assert_eq!(Some(24), rope.resolve(right));
+        assert_eq!(Some(4), original.resolve(right));
+        assert_eq!(Some(24), rope.flatten().resolve(right));
+    }
+
+    #[test]
+    fn many_anchors() {
+        let data: Vec<usize> = (0..3000).collect();
+        let mut rope: Rope<usize> = Rope::from_slice_with_leaf_size(&data, 8);
+        let mut ids = vec![];
+
+        for at in (0..3000).step_by(3) {
+            let (anchored, id) = rope.add_anchor(at, Bias::Left);
+            rope = anchored;
+            ids.push((at, id));
+        }
+
+        let mut rope = rope.remove(0, 10);
+        let (_, gone) = Rope::<usize>::new(&[0]).add_anchor(0, Bias::Left);
+        assert_eq!(None, rope.resolve(gone));
+
+        for &(at, id) in &ids {
+            assert_eq!(Some(at.saturating_sub(10)), rope.resolve(id));
+        }
+
+        // removing each anchor leaves the rest where they were
+        for (i, &(_, id)) in ids.iter().enumerate() {
+            rope = rope.remove_anchor(id);
+            assert_eq!(None, rope.resolve(id));
+
+            if let Some(&(at, next)) = ids.get(i + 1) {
+                assert_eq!(Some(at.saturating_sub(10)), rope.resolve(next));
+            }
+        }
+
+        assert!(!rope.root.has_anchors());
+    }
+}
+
+mod piece_table {
+
+    use super::*;
+
+    #[test]
+    fn edits() {
+        let mut table: PieceTable<usize> = PieceTable::new(vec![0, 1, 2, 3, 4]);
+        table.insert(2, &[10, 11]);
+        table.insert(0, &[12]);
+        table.remove(4, 6);
+
+        assert_eq!(vec![12, 0, 1, 10, 3, 4], as_vec(table.rope()));
+        assert_eq!(&[0, 1, 2, 3, 4], table.original());
+        assert_eq!(3, table.add_len());
+    }
+
+    #[test]
+    fn persistence() {
+        let mut table: PieceTable<usize> = PieceTable::new(vec![0, 1, 2]);
+        let before = table.rope().clone();
+        table.insert(1, &[5]);
+
+        assert_eq!(vec![0, 1, 2], as_vec(&before));
+        assert_eq!(vec![0, 5, 1, 2], as_vec(table.rope()));
+    }
+
+    #[test]
+    fn markers() {
+        let mut original: Chunk<usize, char> = Chunk::with_capacity(4);
+        original.extend_from_slice(&[0, 1, 2, 3]);
+        original.mark_at('a', 1);
+        original.mark_at('a', 3);
+
+        let mut inserted = Chunk::with_capacity(2);
+        inserted.extend_from_slice(&[4, 5]);
+        inserted.mark_at('a', 0);
+
+        let mut table = PieceTable::from_chunk(original);
+        table.insert_chunk(2, inserted);
+        table.remove(0, 1);
+
+        let rope = table.rope();
+        assert_eq!(vec![1, 4, 5, 2, 3], as_vec(rope));
+        assert_eq!(3, rope.marker_count('a'));
+        assert_eq!(Some(0), rope.index_for_nth_marker('a', 0));
+        assert_eq!(Some(1), rope.index_for_nth_marker('a', 1));
+        assert_eq!(Some(4), rope.index_for_nth_marker('a', 2));
+    }
+
+    #[test]
+    fn flatten() {
+        let mut table: PieceTable<usize> = PieceTable::new(vec![0, 1, 2]);
+        table.insert(3, &[3]);
+        table.insert(1, &[4]);
+
+        let flat = table.rope().flatten();
+        assert_eq!(0, flat.depth());
+        assert_eq!(vec![0, 4, 1, 2, 3], as_vec(&flat));
+    }
+}
+
+mod reader {
+
+    use super::*;
+    use std::io::{self, BufRead, IoSlice, Read, Seek, SeekFrom, Write};
+
+    fn bytes_rope() -> Rope<u8> {
+        let mut rope: Rope<u8> = Rope::new(b"hello");
+        rope = Rope::concat(&rope, &Rope::new(b", "));
+        rope = Rope::concat(&rope, &Rope::concat(&Rope::new(b"rope"), &Rope::new(b" world")));
+        rope
+    }
+
+    #[test]
+    fn read_leaf_by_leaf() {
+        let rope = bytes_rope();
+        let mut reader = rope.reader();
+
+        assert_eq!(b"hello", reader.fill_buf().unwrap());
+        reader.consume(3);
+        assert_eq!(b"lo", reader.fill_buf().unwrap());
+        reader.consume(2);
+        assert_eq!(b", ", reader.fill_buf().unwrap());
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(", rope world", rest);
+        assert!(reader.fill_buf().unwrap().is_empty());
+    }
+
+    #[test]
+    fn seek() {
+        let rope = bytes_rope();
+        let mut reader = rope.reader();
+
+        assert_eq!(9, reader.seek(SeekFrom::Start(9)).unwrap());
+        assert_eq!(b"pe", reader.fill_buf().unwrap());
+
+        assert_eq!(12, reader.seek(SeekFrom::End(-5)).unwrap());
+        let mut word = [0; 5];
+        reader.read_exact(&mut word).unwrap();
+        assert_eq!(b"world", &word);
+
+        assert_eq!(0, reader.seek(SeekFrom::Current(-17)).unwrap());
+        assert!(reader.seek(SeekFrom::Current(-1)).is_err());
+
+        reader.seek(SeekFrom::End(3)).unwrap();
+        assert_eq!(0, reader.read(&mut word).unwrap());
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "32")]
+    fn seek_past_usize() {
+        let rope = bytes_rope();
+        let mut reader = rope.reader();
+        reader.seek(SeekFrom::Start(4)).unwrap();
+
+        let error = reader.seek(SeekFrom::Start(1 << 40)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        assert_eq!(4, reader.position());
+    }
+
+    /// A writer taking at most three bytes at a time, to check that partial
+    /// vectored writes are carried on from.
+    struct Trickle(Vec<u8>, usize);
+
+    impl Write for Trickle {
+
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+            self.1 += 1;
+            let first = bufs.iter().find(|buf| !buf.is_empty()).map_or(&[][..], |buf| &buf[..]);
+            self.write(first)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_to() {
+        let rope = bytes_rope();
+
+        let mut out = Vec::new();
+        rope.write_to(&mut out).unwrap();
+        assert_eq!(b"hello, rope world".to_vec(), out);
+
+        let mut trickle = Trickle(Vec::new(), 0);
+        rope.write_to(&mut trickle).unwrap();
+        assert_eq!(b"hello, rope world".to_vec(), trickle.0);
+        assert_eq!(7, trickle.1);
+    }
+}
+
+mod builder {
+
+    use super::*;
+    use std::fmt::Write as FmtWrite;
+    use std::io::Write;
+
+    #[test]
+    fn formatted_chars() {
+        let newlines = Classifier::new(|&c: &char| if c == '\n' { Some(()) } else { None });
+        let mut builder = RopeBuilder::with_classifier(4, &newlines);
+
+        for i in 0..3 {
+            writeln!(builder, "line {}", i).unwrap();
+        }
+
+        assert_eq!(21, builder.len());
+        let rope: Rope<char> = builder.finish();
+
+        assert_eq!("line 0\nline 1\nline 2\n", rope.iter().collect::<String>());
+        assert_eq!(3, rope.marker_count(()));
+        assert_eq!(Some(13), rope.index_for_nth_marker((), 1));
+        assert_eq!(3, rope.depth());
+        assert!(rope.classifier().is_some());
+    }
+
+    #[test]
+    fn bytes() {
+        let mut builder: RopeBuilder<u8> = RopeBuilder::with_capacity(5);
+        builder.write_all(b"hello, ").unwrap();
+        Write::write_fmt(&mut builder, format_args!("w{}rld", 0)).unwrap();
+        FmtWrite::write_fmt(&mut builder, format_args!(" {}", 'é')).unwrap();
+
+        let rope = builder.finish();
+        assert_eq!("hello, w0rld é".as_bytes().to_vec(), rope.iter().cloned().collect::<Vec<u8>>());
+        assert_eq!(2, rope.depth());
+
+        let empty: Rope<u8> = RopeBuilder::new().finish();
+        assert!(empty.is_empty());
+    }
+}
+
+mod load {
+
+    use super::*;
+    use std::fs;
+
+    fn text(rope: &Rope<char>) -> String {
+        rope.iter().collect()
+    }
+
+    fn load(bytes: &[u8], chunk_size: usize) -> Rope<char> {
+        Rope::load(bytes, None, LoadOptions::new().chunk_size(chunk_size)).unwrap()
+    }
+
+    #[test]
+    fn utf8_across_chunks() {
+        let original = "héllo wörld — 😀 done";
+
+        for chunk_size in 1..8 {
+            let rope = load(original.as_bytes(), chunk_size);
+            assert_eq!(original, text(&rope));
+        }
+
+        let mut with_bom = vec![0xef, 0xbb, 0xbf];
+        with_bom.extend_from_slice(original.as_bytes());
+        assert_eq!(original, text(&load(&with_bom, 5)));
+
+        let invalid: Rope<char> =
+            Rope::load(&b"a\xffb\xe2\x82"[..], None, LoadOptions::new().encoding(Encoding::Utf8)).unwrap();
+        assert_eq!("a\u{fffd}b\u{fffd}", text(&invalid));
+    }
+
+    /// Hands over one byte per read, like a slow pipe.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> std::io::Read for Trickle<'a> {
+
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.0.len().min(buf.len()).min(1);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn bom_split_between_reads() {
+        let original = "héllo";
+
+        let mut utf8 = vec![0xef, 0xbb, 0xbf];
+        utf8.extend_from_slice(original.as_bytes());
+
+        let mut utf16 = vec![0xff, 0xfe];
+        original.encode_utf16().for_each(|unit| utf16.extend_from_slice(&unit.to_le_bytes()));
+
+        for bytes in &[utf8, utf16] {
+            let rope: Rope<char> = Rope::load(Trickle(bytes), None, LoadOptions::new()).unwrap();
+            assert_eq!(original, text(&rope));
+        }
+
+        // fewer bytes than a byte order mark could be
+        let rope: Rope<char> = Rope::load(Trickle(b"ab"), None, LoadOptions::new()).unwrap();
+        assert_eq!("ab", text(&rope));
+    }
+
+    #[test]
+    fn utf16() {
+        let original = "line one\nline 😀 two\n";
+        let units: Vec<u16> = original.encode_utf16().collect();
+
+        let mut le = vec![0xff, 0xfe];
+        units.iter().for_each(|unit| le.extend_from_slice(&unit.to_le_bytes()));
+        assert_eq!((Encoding::Utf16Le, 2), Encoding::detect(&le));
+        assert_eq!(original, text(&load(&le, 3)));
+
+        // no byte order mark, but mostly ASCII
+        let be: Vec<u8> = units.iter().flat_map(|unit| unit.to_be_bytes().to_vec()).collect();
+        assert_eq!((Encoding::Utf16Be, 0), Encoding::detect(&be));
+        assert_eq!(original, text(&load(&be, 7)));
+    }
+
+    #[test]
+    fn latin1() {
+        let bytes = b"caf\xe9 cr\xe8me";
+        assert_eq!((Encoding::Latin1, 0), Encoding::detect(bytes));
+        assert_eq!("café crème", text(&load(bytes, 64)));
+
+        let forced: Rope<char> =
+            Rope::load(&b"\xef\xbb\xbfabc"[..], None, LoadOptions::new().encoding(Encoding::Latin1)).unwrap();
+        assert_eq!("\u{ef}\u{bb}\u{bf}abc", text(&forced));
+    }
+
+    #[test]
+    fn file_with_newlines_and_progress() {
+        let path = temp_path("load");
+        let contents = "first\nsecond\nthird\n".repeat(10);
+        fs::write(&path, &contents).unwrap();
+
+        let mut reports = Vec::new();
+        let rope: Rope<char> = {
+            let options = LoadOptions::new()
+                .chunk_size(16)
+                .newlines(())
+                .progress(|read, total| { reports.push((read, total)); true });
+
+            Rope::load_file(&path, options).unwrap()
+        };
+
+        assert_eq!(contents, text(&rope));
+        assert_eq!(30, rope.marker_count(()));
+        assert_eq!(Some(12), rope.index_for_nth_marker((), 1));
+
+        let total = contents.len() as u64;
+        assert_eq!(Some(&(total, Some(total))), reports.last());
+        assert!(reports.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+
+        let options = LoadOptions::new().chunk_size(16).progress(|read, _| read < 40);
+
+        match Rope::load_file(&path, options) {
+            Err(LoadError::Cancelled(partial)) => assert_eq!(contents[..48], text(&partial)[..]),
+            _ => panic!("expected the load to be cancelled"),
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+#[cfg(feature = "sync")]
+mod background {
+
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn growing_prefix() {
+        let path = temp_path("background");
+        let contents = "some line of text\n".repeat(500);
+        fs::write(&path, &contents).unwrap();
+
+        let options = LoadOptions::new().chunk_size(100).newlines(());
+        let load: BackgroundLoad = Rope::load_file_in_background(&path, options).unwrap();
+
+        let mut len = 0;
+        while len < contents.len() {
+            let prefix = load.wait_for_more(len);
+            let text: String = prefix.iter().collect();
+
+            assert!(text.len() > len);
+            assert!(contents.starts_with(&text));
+            assert!(prefix.depth() <= 2 * (contents.len() / 100 + 1).next_power_of_two().trailing_zeros() as usize + 1);
+            len = text.len();
+        }
+
+        let rope = load.wait().unwrap();
+        assert_eq!(contents, rope.iter().collect::<String>());
+        assert_eq!(500, rope.marker_count(()));
+        assert_eq!(7, rope.depth());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn panicking_callback() {
+        let path = temp_path("background-panic");
+        fs::write(&path, "some line of text\n".repeat(50)).unwrap();
+
+        let options = LoadOptions::new().chunk_size(100).progress(|read, _| {
+            if read > 300 {
+                panic!("too far");
+            }
+            true
+        });
+
+        let load: BackgroundLoad = Rope::load_file_in_background(&path, options).unwrap();
+
+        // waiting for more than there'll ever be still returns
+        assert!(load.wait_for_more(10000).len() <= 300);
+
+        match load.wait() {
+            Err(LoadError::Panicked(message)) => assert_eq!("too far", message),
+            result => panic!("expected a panic, got {:?}", result.map(|rope| rope.len())),
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_file() {
+        let missing = temp_path("background-missing");
+        let result: std::io::Result<BackgroundLoad> = Rope::load_file_in_background(&missing, LoadOptions::new());
+        assert!(result.is_err());
+    }
+}
+
+#[cfg(feature = "sync")]
+mod document {
+
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn snapshots_and_versions() {
+        let document: Document<usize> = Document::new(Rope::new(&[1, 2, 3]));
+        let before = document.snapshot();
+        assert_eq!(0, before.version());
+
+        let edited = before.rope().insert(3, &[4]);
+        assert_eq!(1, document.publish(edited));
+
+        assert!(!document.is_current(&before));
+        assert_eq!(3, before.rope().len());
+
+        let after = document.snapshot();
+        assert!(document.is_current(&after));
+        assert_eq!(vec![1, 2, 3, 4], after.rope().iter().cloned().collect::<Vec<_>>());
+
+        // a writer working from a stale version is turned away
+        let stale = document.publish_over(0, Rope::new(&[]));
+        assert_eq!(1, stale.err().unwrap().version());
+        assert_eq!(Some(2), document.publish_over(1, after.rope().remove(0, 1)).ok());
+    }
+
+    #[test]
+    fn concurrent_readers() {
+        let document: Arc<Document<usize>> = Arc::new(Document::new(Rope::new(&[0])));
+
+        let readers: Vec<_> = (0..4).map(|_| {
+            let document = document.clone();
+
+            thread::spawn(move || {
+                let mut last = 0;
+
+                while last < 100 {
+                    let snapshot = document.snapshot();
+                    let rope = snapshot.rope();
+
+                    // version n has the values 0..=n
+                    assert_eq!(snapshot.version() as usize + 1, rope.len());
+                    assert_eq!(snapshot.version() as usize, rope[rope.len() - 1]);
+                    assert!(snapshot.version() >= last);
+                    last = snapshot.version();
+                }
+            })
+        }).collect();
+
+        for i in 1..=100 {
+            let rope = document.snapshot().rope().insert(i, &[i]);
+            document.publish(rope);
+        }
+
+        readers.into_iter().for_each(|reader| reader.join().unwrap());
+        assert_eq!(100, document.version());
+    }
+
+    #[test]
+    fn old_versions_freed() {
+        let first = Arc::new(0);
+        let document: Document<Arc<usize>> = Document::new(Rope::new(std::slice::from_ref(&first)));
+        assert_eq!(2, Arc::strong_count(&first));
+
+        // the stale copy holds on to version 0 until version 2 replaces it
+        document.publish(Rope::new(&[Arc::new(1)]));
+        assert_eq!(2, Arc::strong_count(&first));
+        document.publish(Rope::new(&[Arc::new(2)]));
+        assert_eq!(1, Arc::strong_count(&first));
+
+        let last = document.snapshot().rope()[0].clone();
+        drop(document);
+        assert_eq!(1, Arc::strong_count(&last));
+    }
+
+    #[test]
+    fn observers() {
+        let document: Document<char> = Document::new(Rope::new(&['a', 'b', 'c']));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let seen_by_callback = seen.clone();
+        let callback = document.subscribe(move |change| {
+            seen_by_callback.lock().unwrap().push((change.version(), change.hunks().to_vec()));
+        });
+
+        let (_, changes) = document.subscribe_channel();
+
+        assert_eq!(Some(1), document.apply_over(0, &ChangeSet::splice(3, 1, 2, &['x', 'y'])).ok());
+
+        let rope = document.snapshot().rope().insert(0, &['z']);
+        document.publish(rope);
+
+        let hunk = |old_start, old_end, new_start, new_end| Hunk { old_start, old_end, new_start, new_end };
+
+        assert_eq!(vec![
+            (1, vec![hunk(1, 2, 1, 3)]),
+            (2, vec![hunk(0, 0, 0, 1)]),
+        ], *seen.lock().unwrap());
+
+        let first = changes.recv().unwrap();
+        assert_eq!(vec!['a', 'b', 'c'], first.old_rope().iter().cloned().collect::<Vec<_>>());
+        assert_eq!(vec!['a', 'x', 'y', 'c'], first.new_rope().iter().cloned().collect::<Vec<_>>());
+        assert_eq!(&[hunk(1, 2, 1, 3)], first.hunks());
+
+        // published without a changeset, so worked out with a diff
+        let second = changes.recv().unwrap();
+        assert_eq!(2, second.version());
+        assert_eq!(&[hunk(0, 0, 0, 1)], second.hunks());
+
+        assert!(document.unsubscribe(callback));
+        assert!(!document.unsubscribe(callback));
+        document.publish(Rope::new(&[]));
+
+        assert_eq!(2, seen.lock().unwrap().len());
+        assert_eq!(3, changes.recv().unwrap().version());
+    }
+
+    #[test]
+    fn observers_publishing() {
+        let document: Arc<Document<usize>> = Arc::new(Document::new(Rope::new(&[])));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        // an observer that snapshots and publishes itself, which is only
+        // delivered once it returns
+        let inner = document.clone();
+        let seen_by_callback = seen.clone();
+
+        document.subscribe(move |change| {
+            seen_by_callback.lock().unwrap().push(change.version());
+
+            let snapshot = inner.snapshot();
+            if snapshot.version() < 4 {
+                inner.publish(snapshot.rope().insert(0, &[snapshot.version() as usize]));
+            }
+        });
+
+        // a stale changeset is turned away
+        assert_eq!(1, document.publish(Rope::new(&[9])));
+        assert_eq!(4, document.apply_over(0, &ChangeSet::splice(0, 0, 0, &[1])).err().unwrap().version());
+
+        assert_eq!(vec![1, 2, 3, 4], *seen.lock().unwrap());
+        assert_eq!(vec![3, 2, 1, 9], document.snapshot().rope().iter().cloned().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn observers_and_two_writers() {
+        let document: Arc<Document<usize>> = Arc::new(Document::new(Rope::new(&[])));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let inner = document.clone();
+        let seen_by_callback = seen.clone();
+
+        document.subscribe(move |change| {
+            // taking a snapshot while another writer publishes
+            assert!(inner.snapshot().version() >= change.version());
+            seen_by_callback.lock().unwrap().push(change.version());
+        });
+
+        let writers: Vec<_> = (0..2).map(|_| {
+            let document = document.clone();
+
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    loop {
+                        let snapshot = document.snapshot();
+                        let rope = snapshot.rope().insert(0, &[0]);
+
+                        if document.publish_over(snapshot.version(), rope).is_ok() {
+                            break;
+                        }
+                    }
+                }
+            })
+        }).collect();
+
+        writers.into_iter().for_each(|writer| writer.join().unwrap());
+        assert_eq!((1..=100).collect::<Vec<_>>(), *seen.lock().unwrap());
+    }
+
+    #[test]
+    fn channel_on_another_thread() {
+        let document: Document<usize> = Document::new(Rope::new(&[]));
+        let (_, changes) = document.subscribe_channel();
+
+        let observer = thread::spawn(move || {
+            // stops when the document (and so the sender) is dropped
+            changes.iter().map(|change| {
+                assert_eq!(change.old_rope().len() + 1, change.new_rope().len());
+                change.version()
+            }).collect::<Vec<_>>()
+        });
+
+        for i in 0..10 {
+            let rope = document.snapshot().rope().insert(i, &[i]);
+            document.publish(rope);
+        }
+
+        drop(document);
+        assert_eq!((1..=10).collect::<Vec<_>>(), observer.join().unwrap());
+    }
+}
+
+#[cfg(feature = "rayon")]
+mod parallel {
+
+    use super::*;
+    use rayon::prelude::*;
+
+    fn rope_of(n: usize) -> Rope<usize> {
+        Rope::from_slice_with_leaf_size(&(0..n).collect::<Vec<_>>(), 100)
+    }
+
+    #[test]
+    fn par_iter() {
+        let rope = rope_of(10000);
+
+        let collected: Vec<usize> = rope.par_iter().cloned().collect();
+        assert_eq!((0..10000).collect::<Vec<_>>(), collected);
+        assert_eq!(49995000, rope.par_iter().sum::<usize>());
+
+        // a single leaf is split up too
+        let flat: Rope<usize> = Rope::new(&(0..10000).collect::<Vec<_>>());
+        assert_eq!(collected, flat.par_iter().cloned().collect::<Vec<_>>());
+        assert_eq!(None, Rope::<usize>::new(&[]).par_iter().max());
+    }
+
+    #[test]
+    fn par_chunks() {
+        let rope = rope_of(1050);
+        let chunks: Vec<&[usize]> = rope.par_chunks().collect();
+
+        assert_eq!(11, chunks.len());
+        assert!(chunks[..10].iter().all(|chunk| chunk.len() == 100));
+        assert_eq!(&[1000, 1001], &chunks[10][..2]);
+
+        // an empty leaf gives no chunk at all
+        assert_eq!(0, Rope::<usize>::new(&[]).par_chunks().count());
+    }
+
+    #[test]
+    fn par_fold() {
+        let text: Vec<char> = "the quick brown fox jumps over the lazy dog ".chars().cycle().take(44 * 500).collect();
+        let rope: Rope<char> = Rope::from_slice_with_leaf_size(&text, 37);
+
+        // concatenation in order: associative but not commutative
+        let copied = rope.par_fold(String::new, |mut s, &c| { s.push(c); s }, |a, b| a + &b);
+        assert_eq!(text.iter().collect::<String>(), copied);
+
+        let spaces = rope.par_fold(|| 0, |n, &c| n + (c == ' ') as usize, |a, b| a + b);
+        assert_eq!(9 * 500, spaces);
+    }
+
+    #[test]
+    fn par_from_slice() {
+        let data: Vec<usize> = (0..5000).collect();
+
+        for &leaf_size in &[1, 7, 1024, 5000, 6000] {
+            let parallel: Rope<usize> = Rope::par_from_slice_with_leaf_size(&data, leaf_size);
+            let sequential: Rope<usize> = Rope::from_slice_with_leaf_size(&data, leaf_size);
+
+            // the same tree, node for node
+            assert_eq!(Rope::write_snapshot(&[sequential]), Rope::write_snapshot(&[parallel]));
+        }
+
+        let parallel: Rope<usize> = Rope::par_from_slice(&data);
+        let sequential: Rope<usize> = data.iter().cloned().collect();
+        assert_eq!(Rope::write_snapshot(&[sequential]), Rope::write_snapshot(&[parallel]));
+        assert!(Rope::<usize>::par_from_slice(&[]).is_empty());
+    }
+
+    #[test]
+    fn par_from_slice_with_classifier() {
+        let text: Vec<char> = "one\ntwo\nthree\n".chars().cycle().take(14 * 300).collect();
+        let newlines: Classifier<char, ()> = Classifier::new(|&c: &char| if c == '\n' { Some(()) } else { None });
+
+        let parallel = Rope::par_from_slice_with_classifier(&text, 50, &newlines);
+
+        let mut builder = RopeBuilder::with_classifier(50, &newlines);
+        builder.extend_from_slice(&text);
+        let sequential = builder.finish();
+
+        assert_eq!(Rope::write_snapshot(&[sequential]), Rope::write_snapshot(Some(&parallel)));
+        assert_eq!(900, parallel.marker_count(()));
+
+        // and the rope goes on marking new values
+        assert!(parallel.classifier().unwrap().is(&newlines));
+        assert_eq!(901, parallel.insert(0, &['\n']).marker_count(()));
+    }
+}
+
+mod changeset {
+
+    use super::*;
+
+    fn sample_changes() -> ChangeSet<usize> {
+        // [0, 1, 2, 3, 4, 5] -> [0, 10, 2, 3, 11, 12]
+        let mut changes = ChangeSet::new();
+        changes.retain(1);
+        changes.delete(1);
+        changes.insert(&[10]);
+        changes.retain(2);
+        changes.delete(2);
+        changes.insert(&[11, 12]);
+        changes
+    }
+
+    #[test]
+    fn build() {
+        let changes = sample_changes();
+        assert_eq!(6, changes.len_before());
+        assert_eq!(6, changes.len_after());
+        assert_eq!(&[Operation::Retain(1),
+                     Operation::Insert(vec![10]),
+                     Operation::Delete(1),
+                     Operation::Retain(2),
+                     Operation::Insert(vec![11, 12]),
+                     Operation::Delete(2)],
+                   changes.operations());
+
+        assert!(ChangeSet::<usize>::identity(4).is_identity());
+        assert!(!changes.is_identity());
+        assert_eq!(ChangeSet::splice(6, 1, 2, &[10]).operations()[..3], changes.operations()[..3]);
+    }
+
+    #[test]
+    fn apply_and_invert() {
+        let rope: Rope<usize> = Rope::from_slice_with_leaf_size(&[0, 1, 2, 3, 4, 5], 2);
+        let changes = sample_changes();
+
+        let edited = changes.apply(&rope);
+        assert_eq!(vec![0, 10, 2, 3, 11, 12], as_vec(&edited));
+
+        let inverse = changes.invert(&rope);
+        assert_eq!(vec![0, 1, 2, 3, 4, 5], as_vec(&inverse.apply(&edited)));
+        assert_eq!(as_vec(&rope), as_vec(&changes.compose(&inverse).apply(&rope)));
+    }
+
+    #[test]
+    fn compose() {
+        let rope: Rope<usize> = Rope::new(&[0, 1, 2, 3, 4, 5]);
+        let first = sample_changes();
+        let second = ChangeSet::splice(6, 1, 5, &[20]);
+
+        let composed = first.compose(&second);
+        assert_eq!(6, composed.len_before());
+        assert_eq!(3, composed.len_after());
+        assert_eq!(as_vec(&second.apply(&first.apply(&rope))), as_vec(&composed.apply(&rope)));
+
+        // inserting and then deleting the same values cancels out
+        let insert = ChangeSet::splice(6, 3, 3, &[30, 31]);
+        let delete = ChangeSet::splice(8, 3, 5, &[]);
+        assert_eq!(ChangeSet::identity(6), insert.compose(&delete));
+    }
+
+    #[test]
+    fn map_position() {
+        let changes = sample_changes();
+
+        assert_eq!(0, changes.map_position(0, Bias::Left));
+        assert_eq!(1, changes.map_position(1, Bias::Left));
+        assert_eq!(2, changes.map_position(1, Bias::Right));
+        assert_eq!(3, changes.map_position(3, Bias::Left));
+        assert_eq!(4, changes.map_position(5, Bias::Left));
+        assert_eq!(6, changes.map_position(5, Bias::Right));
+        assert_eq!(4, changes.map_position(6, Bias::Left));
+        assert_eq!(6, changes.map_position(6, Bias::Right));
+
+        // insertions at the ends of a range don't grow it
+        let insert = ChangeSet::splice(6, 2, 2, &[30]);
+        assert_eq!((3, 5), insert.map_range(2, 4));
+        assert_eq!((1, 2), insert.map_range(1, 2));
+    }
+
+    #[test]
+    fn apply_moves_anchors_like_splice() {
+        let rope: Rope<usize> = Rope::new(&[0, 1, 2, 3, 4, 5]);
+        let (rope, left) = rope.add_anchor(5, Bias::Left);
+        let (rope, right) = rope.add_anchor(5, Bias::Right);
+
+        let changes = sample_changes();
+        let edited = changes.apply(&rope);
+
+        assert_eq!(Some(changes.map_position(5, Bias::Left)), edited.resolve(left));
+        assert_eq!(Some(changes.map_position(5, Bias::Right)), edited.resolve(right));
+    }
+}
+
+mod history {
+
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn typed(history: &mut History<usize>, at: usize, value: usize, time: Instant) {
+        let len = history.rope().len();
+        history.edit_at(ChangeSet::splice(len, at, at, &[value]), Some(at), time);
+    }
+
+    #[test]
+    fn undo_redo() {
+        let mut history: History<usize> = History::new(Rope::new(&[0, 1, 2]));
+        history.set_coalesce_window(Duration::from_secs(0));
+
+        history.edit(ChangeSet::splice(3, 1, 2, &[10, 11]), Some(1));
+        history.edit(ChangeSet::splice(4, 0, 1, &[]), Some(0));
+        assert_eq!(vec![10, 11, 2], as_vec(history.rope()));
+        assert_eq!(2, history.undo_count());
+
+        assert!(history.undo());
+        assert_eq!(vec![0, 10, 11, 2], as_vec(history.rope()));
+        assert_eq!(Some(0), history.cursor_hint());
+
+        assert!(history.undo());
+        assert!(!history.undo());
+        assert_eq!(vec![0, 1, 2], as_vec(history.rope()));
+        assert_eq!(Some(1), history.cursor_hint());
+
+        assert!(history.redo());
+        assert_eq!(vec![0, 10, 11, 2], as_vec(history.rope()));
+        assert_eq!(Some(3), history.cursor_hint());
+
+        // a new edit discards what could have been redone
+        history.edit(ChangeSet::splice(4, 4, 4, &[12]), None);
+        assert!(!history.can_redo());
+        assert_eq!(vec![0, 10, 11, 2, 12], as_vec(history.rope()));
+    }
+
+    #[test]
+    fn coalescing() {
+        let mut history = History::new(Rope::new(&[0, 1, 2]));
+        let start = Instant::now();
+
+        typed(&mut history, 3, 3, start);
+        typed(&mut history, 4, 4, start + Duration::from_millis(100));
+        typed(&mut history, 5, 5, start + Duration::from_millis(200));
+        assert_eq!(1, history.undo_count());
+
+        // too late, somewhere else, or after breaking coalescing
+        typed(&mut history, 6, 6, start + Duration::from_secs(5));
+        typed(&mut history, 0, 7, start + Duration::from_millis(5100));
+        history.break_coalescing();
+        typed(&mut history, 1, 8, start + Duration::from_millis(5200));
+        assert_eq!(4, history.undo_count());
+
+        // backspacing
+        let len = history.rope().len();
+        let time = start + Duration::from_millis(5300);
+        history.edit_at(ChangeSet::splice(len, len - 1, len, &[]), None, time);
+        history.edit_at(ChangeSet::splice(len - 1, len - 2, len - 1, &[]), None, time);
+        assert_eq!(5, history.undo_count());
+
+        history.undo();
+        history.undo();
+        history.undo();
+        history.undo();
+        assert_eq!(vec![0, 1, 2, 3, 4, 5], as_vec(history.rope()));
+    }
+
+    #[test]
+    fn pruning() {
+        let mut history: History<usize> = History::new(Rope::new(&[0, 1, 2]));
+        history.set_coalesce_window(Duration::from_secs(0));
+
+        for i in 0..5 {
+            history.edit(ChangeSet::splice(3 + i, 0, 0, &[10 + i]), None);
+        }
+
+        history.set_max_steps(3);
+        assert_eq!(3, history.undo_count());
+        assert_eq!(3, history.size());
+
+        history.set_max_size(1);
+        assert_eq!(1, history.undo_count());
+
+        history.undo();
+        assert!(!history.can_undo());
+        assert_eq!(vec![13, 12, 11, 10, 0, 1, 2], as_vec(history.rope()));
+    }
+}
+
+mod undo_tree {
+
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn appended(tree: &UndoTree<usize>, value: usize) -> ChangeSet<usize> {
+        let len = tree.rope().len();
+        ChangeSet::splice(len, len, len, &[value])
+    }
+
+    #[test]
+    fn branching() {
+        let mut tree = UndoTree::new(Rope::new(&[0]));
+        let root = tree.current();
+
+        let one = tree.edit(&appended(&tree, 1));
+        let two = tree.edit(&appended(&tree, 2));
+        assert!(tree.undo());
+
+        // a new edit after undoing starts a branch rather than losing `two`
+        let three = tree.edit(&appended(&tree, 3));
+        assert_eq!(vec![0, 1, 3], as_vec(tree.rope()));
+        assert_eq!(Some(one), tree.parent(three));
+        assert_eq!(&[two, three], tree.children(one));
+
+        assert!(tree.undo());
+        assert!(tree.undo());
+        assert!(!tree.undo());
+        assert_eq!(root, tree.current());
+
+        // redo follows the branch we came from
+        assert!(tree.redo());
+        assert!(tree.redo());
+        assert_eq!(three, tree.current());
+        assert!(!tree.redo());
+
+        tree.goto(two);
+        assert_eq!(vec![0, 1, 2], as_vec(tree.rope()));
+        tree.goto(root);
+        tree.redo();
+        tree.redo();
+        assert_eq!(two, tree.current());
+    }
+
+    #[test]
+    fn time_travel() {
+        let start = Instant::now();
+        let mut tree = UndoTree::new_at(Rope::new(&[0]), start);
+        let root = tree.current();
+
+        let one = tree.edit_at(&appended(&tree, 1), start + Duration::from_secs(10));
+        let two = tree.edit_at(&appended(&tree, 2), start + Duration::from_secs(20));
+        tree.undo();
+        let three = tree.edit_at(&appended(&tree, 3), start + Duration::from_secs(30));
+
+        assert_eq!(two, tree.earlier(Duration::from_secs(10)));
+        assert_eq!(one, tree.earlier(Duration::from_secs(5)));
+        assert_eq!(root, tree.earlier(Duration::from_secs(60)));
+        assert_eq!(vec![0], as_vec(tree.rope()));
+
+        assert_eq!(one, tree.later(Duration::from_secs(15)));
+        assert_eq!(three, tree.later(Duration::from_secs(60)));
+        assert_eq!(three, tree.later(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn branches() {
+        let start = Instant::now();
+        let mut tree = UndoTree::new_at(Rope::new(&[0]), start);
+
+        tree.edit_at(&appended(&tree, 1), start);
+        let two = tree.edit_at(&appended(&tree, 2), start);
+        tree.undo();
+        tree.undo();
+        let three = tree.edit_at(&appended(&tree, 3), start);
+
+        let branches = tree.branches();
+        assert_eq!(vec![two, three], branches.iter().map(|branch| branch.leaf).collect::<Vec<_>>());
+        assert_eq!(vec![2, 1], branches.iter().map(|branch| branch.depth).collect::<Vec<_>>());
+        assert_eq!(4, tree.revision_count());
+    }
+
+    #[test]
+    fn long_session() {
+        let mut tree = UndoTree::new(Rope::new(&[0]));
+        let count = 20_000;
+
+        for i in 1..count {
+            tree.edit(&appended(&tree, i));
+        }
+
+        let last = tree.current();
+        while tree.undo() {}
+        assert_eq!(vec![0], as_vec(tree.rope()));
+
+        while tree.redo() {}
+        assert_eq!(last, tree.current());
+        assert_eq!((0..count).collect::<Vec<_>>(), as_vec(tree.rope()));
+    }
+}
+
+mod diff {
+
+    use super::*;
+
+    fn sample_rope() -> Rope<usize> {
+        let data: Vec<usize> = (0..100).collect();
+        Rope::from_slice_with_leaf_size(&data, 8)
+    }
+
+    #[test]
+    fn identical() {
+        let rope = sample_rope();
+        assert!(Rope::diff(&rope, &rope).is_identity());
+        assert!(Rope::diff(&rope, &rope.flatten()).is_identity());
+        let empty: Rope<usize> = Rope::new(&[]);
+        assert!(Rope::diff(&empty, &empty.clone()).is_identity());
+    }
+
+    #[test]
+    fn edits() {
+        let old = sample_rope();
+        let new = old.insert(50, &[200, 201]).remove(10, 13).replace(90, 91, &[300]);
+
+        let changes = Rope::diff(&old, &new);
+        assert_eq!(as_vec(&new), as_vec(&changes.apply(&old)));
+        assert_eq!(vec![Hunk { old_start: 10, old_end: 13, new_start: 10, new_end: 10 },
+                        Hunk { old_start: 50, old_end: 50, new_start: 47, new_end: 49 },
+                        Hunk { old_start: 91, old_end: 92, new_start: 90, new_end: 91 }],
+                   changes.hunks());
+    }
+
+    #[test]
+    fn unrelated() {
+        let old: Rope<usize> = Rope::new(&[1, 2, 3, 4, 5, 6]);
+        let new = Rope::from_slice_with_leaf_size(&[0, 2, 3, 9, 5, 6, 7], 2);
+
+        let changes = Rope::diff(&old, &new);
+        assert_eq!(as_vec(&new), as_vec(&changes.apply(&old)));
+        assert_eq!(3, changes.hunks().len());
+
+        let emptied = Rope::diff(&old, &old.remove(0, 6));
+        assert_eq!(0, emptied.apply(&old).len());
+    }
+
+    #[test]
+    fn moved() {
+        let old = sample_rope();
+        let new = Rope::concat(&old.slice(50, 100), &old.slice(0, 50));
+
+        let changes = Rope::diff(&old, &new);
+        assert_eq!(as_vec(&new), as_vec(&changes.apply(&old)));
+        assert_eq!(old.len(), changes.len_before());
+    }
+
+    fn lcs_len(a: &[usize], b: &[usize]) -> usize {
+        let mut row = vec![0; b.len() + 1];
+
+        for x in a {
+            let mut diagonal = 0;
+            for (j, y) in b.iter().enumerate() {
+                let above = row[j + 1];
+                row[j + 1] = if x == y { diagonal + 1 } else { max(above, row[j]) };
+                diagonal = above;
+            }
+        }
+
+        row[b.len()]
+    }
+
+    #[test]
+    fn shortest() {
+        let mut seed = 7usize;
+        let mut next = || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) % 4
+        };
+
+        for _ in 0..50 {
+            let old: Vec<usize> = (0..40).map(|_| next()).collect();
+            let new: Vec<usize> = (0..35).map(|_| next()).collect();
+            let old_rope: Rope<usize> = Rope::new(&old);
+            let changes = Rope::diff(&old_rope, &Rope::new(&new));
+            assert_eq!(new, as_vec(&changes.apply(&old_rope)));
+
+            let deleted: usize = changes.hunks().iter().map(|hunk| hunk.old_end - hunk.old_start).sum();
+            assert_eq!(old.len() - lcs_len(&old, &new), deleted);
+        }
+    }
+}
+
+mod journal {
+
+    use super::*;
+    use std::fs::{self, OpenOptions};
+    use std::io;
+
+    fn edit(journal: &mut Journal<char>, base: &Rope<char>) -> Rope<char> {
+        let rope = journal.insert(base, 5, &[' ', 'b', 'i', 'g']).unwrap();
+        let rope = journal.remove(&rope, 0, 1).unwrap();
+        journal.replace(&rope, 0, 4, &['W', 'o', 'r', 'l', 'd']).unwrap()
+    }
+
+    #[test]
+    fn replay() {
+        let path = temp_path("journal-replay");
+        let base = Rope::new(&"hello there".chars().collect::<Vec<_>>());
+
+        let mut journal = Journal::create(&path, SyncPolicy::Always).unwrap();
+        let rope = edit(&mut journal, &base);
+        assert_eq!("World big there", as_vec(&rope).into_iter().collect::<String>());
+
+        let recovered = Journal::replay(&path, &base).unwrap();
+        assert_eq!(as_vec(&rope), as_vec(&recovered));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_final_record() {
+        let path = temp_path("journal-torn");
+        let base = Rope::new(&"hello there".chars().collect::<Vec<_>>());
+
+        let mut journal = Journal::create(&path, SyncPolicy::Every(2)).unwrap();
+        let rope = edit(&mut journal, &base);
+        let rope = journal.insert(&rope, 0, &['!', '!']).unwrap();
+        drop(journal);
+
+        // lose the end of the last record, as a crash mid-write would
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 2).unwrap();
+
+        let recovered = Journal::replay(&path, &base).unwrap();
+        assert_eq!(as_vec(&rope)[2..], as_vec(&recovered)[..]);
+
+        // appending drops the torn record and carries on
+        let mut journal = Journal::append(&path, SyncPolicy::Never).unwrap();
+        let rope = journal.insert(&recovered, 0, &['?']).unwrap();
+        journal.sync().unwrap();
+
+        let recovered = Journal::replay(&path, &base).unwrap();
+        assert_eq!(as_vec(&rope), as_vec(&recovered));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn damaged_records() {
+        let path = temp_path("journal-damaged");
+        let base = Rope::new(&"hello there".chars().collect::<Vec<_>>());
+
+        let mut journal = Journal::create(&path, SyncPolicy::Never).unwrap();
+        edit(&mut journal, &base);
+        drop(journal);
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[21] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let error = Journal::replay(&path, &base).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+
+        // the edits don't fit a different base
+        let mut journal = Journal::create(&path, SyncPolicy::Never).unwrap();
+        edit(&mut journal, &base);
+
+        let other: Rope<char> = Rope::new(&['x']);
+        let error = Journal::replay(&path, &other).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn damaged_length() {
+        let path = temp_path("journal-length");
+        let base = Rope::new(&"hello there".chars().collect::<Vec<_>>());
+
+        let mut journal = Journal::create(&path, SyncPolicy::Never).unwrap();
+        edit(&mut journal, &base);
+        drop(journal);
+
+        // make the first record's length run past the end of the file
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[15] = 0x7f;
+        fs::write(&path, &bytes).unwrap();
+
+        let error = Journal::replay(&path, &base).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+
+        // and appending mustn't throw away the good records after it
+        let error = Journal::<char>::append(&path, SyncPolicy::Never).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert_eq!(bytes, fs::read(&path).unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+mod merge {
+
+    use super::*;
+
+    fn text(s: &str) -> Rope<char> {
+        Rope::from_iter(s.chars())
+    }
+
+    fn as_string(rope: &Rope<char>) -> String {
+        rope.iter().cloned().collect()
+    }
+
+    #[test]
+    fn clean() {
+        let data: Vec<usize> = (0..20).collect();
+        let base: Rope<usize> = Rope::from_slice_with_leaf_size(&data, 4);
+        let ours = base.insert(2, &[100]).remove(15, 17);
+        let theirs = base.replace(8, 10, &[200, 201, 202]);
+
+        let merge = Rope::merge3(&base, &ours, &theirs);
+        assert!(merge.is_clean());
+
+        let expected = ours.replace(9, 11, &[200, 201, 202]);
+        assert_eq!(as_vec(&expected), as_vec(merge.rope()));
+
+        // the same edit on both sides is applied once
+        let both = Rope::merge3(&base, &ours, &ours);
+        assert!(both.is_clean());
+        assert_eq!(as_vec(&ours), as_vec(both.rope()));
+    }
+
+    #[test]
+    fn conflicts() {
+        let data: Vec<usize> = (0..10).collect();
+        let base: Rope<usize> = Rope::new(&data);
+        let ours = base.replace(3, 5, &[100]).insert(0, &[50]);
+        let theirs = base.replace(4, 6, &[200, 201]);
+
+        let merge = Rope::merge3(&base, &ours, &theirs);
+        assert_eq!(vec![Conflict { base: 3..6, ours: 4..6, theirs: 3..6, merged: 4..6 }],
+                   merge.conflicts());
+        assert_eq!(vec![50, 0, 1, 2, 100, 5, 6, 7, 8, 9], as_vec(merge.rope()));
+
+        // two insertions in the same place conflict too
+        let merge = Rope::merge3(&base, &base.insert(5, &[1]), &base.insert(5, &[2]));
+        assert_eq!(1, merge.conflicts().len());
+    }
+
+    #[test]
+    fn conflict_markers() {
+        let base = text("a\nb\nc\n");
+        let ours = text("a\nB\nc\n");
+        let theirs = text("a\nbee\nc\n");
+
+        let merge = Rope::merge3(&base, &ours, &theirs);
+        assert_eq!("a\n<<<<<<< ours\nB\n=======\nbee\n>>>>>>> theirs\nc\n",
+                   as_string(&merge.with_conflict_markers()));
+    }
+}
+
+mod crdt {
+
+    use super::*;
+
+    fn as_string(replica: &Replica<char>) -> String {
+        replica.rope().iter().cloned().collect()
+    }
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn local_edits() {
+        let mut replica: Replica<char> = Replica::new(1);
+        replica.insert(0, &chars("hello world"));
+        replica.remove(5, 11);
+        replica.insert(5, &chars("!"));
+
+        assert_eq!("hello!", as_string(&replica));
+    }
+
+    #[test]
+    fn concurrent_edits_converge() {
+        let mut a: Replica<char> = Replica::new(1);
+        let mut b: Replica<char> = Replica::new(2);
+
+        let base = a.insert(0, &chars("abc"));
+        b.apply_all(base);
+
+        let from_a = a.insert(1, &chars("XY"));
+        let mut from_b = b.insert(1, &chars("Z"));
+        from_b.extend(b.remove(2, 3));
+
+        a.apply_all(from_b);
+        b.apply_all(from_a);
+
+        assert_eq!(as_string(&a), as_string(&b));
+        assert_eq!(5, a.rope().len());
+    }
+
+    #[test]
+    fn any_order() {
+        let mut rng = Lcg(37);
+        let mut replicas: Vec<Replica<char>> = (0..3).map(Replica::new).collect();
+        let mut ops = Vec::new();
+
+        // each replica edits its own copy without hearing from the others
+        for round in 0..20 {
+            for replica in replicas.iter_mut() {
+                let len = replica.rope().len();
+
+                if len > 0 && rng.below(3) == 0 {
+                    let start = rng.below(len);
+                    ops.extend(replica.remove(start, start + 1));
+                } else {
+                    let value = (b'a' + (round % 26) as u8) as char;
+                    ops.extend(replica.insert(rng.below(len + 1), &[value]));
+                }
+            }
+        }
+
+        // then everyone hears everything, in a different order each
+        for replica in replicas.iter_mut() {
+            let mut shuffled = ops.clone();
+            rng.shuffle(&mut shuffled);
+            replica.apply_all(shuffled);
+            assert_eq!(0, replica.pending_count());
+        }
+
+        assert_eq!(as_string(&replicas[0]), as_string(&replicas[1]));
+        assert_eq!(as_string(&replicas[0]), as_string(&replicas[2]));
+    }
+
+    #[test]
+    fn state() {
+        let mut a: Replica<char> = Replica::new(1);
+        a.insert(0, &chars("abcdef"));
+        a.remove(0, 6);
+        a.insert(0, &chars("xy"));
+
+        let mut late: Replica<char> = Replica::new(2);
+        late.apply_all(a.state());
+        assert_eq!("xy", as_string(&late));
+
+        // the late replica can still place edits relative to tombstones
+        let mut b: Replica<char> = Replica::new(3);
+        let mut ops = a.state();
+        ops.reverse();
+        b.apply_all(ops);
+        late.apply_all(b.insert(1, &chars("-")));
+        assert_eq!(as_string(&b), as_string(&late));
+    }
+
+    #[test]
+    fn encoded_exchange() {
+        // everything between replicas goes through bytes
+        fn send(ops: Vec<Op<char>>) -> Vec<Op<char>> {
+            let mut bytes = Vec::new();
+            ops.encode(&mut bytes);
+
+            let mut input = &bytes[..];
+            let decoded = Vec::decode(&mut input).unwrap();
+            assert!(input.is_empty());
+            decoded
+        }
+
+        let mut a: Replica<char> = Replica::new(1);
+        a.insert(0, &chars("hello world"));
+        a.remove(0, 6);
+
+        let mut b: Replica<char> = Replica::new(2);
+        b.apply_all(send(a.state()));
+        assert_eq!(a.state(), b.state());
+
+        let from_a = a.insert(0, &chars("big "));
+        let from_b = b.remove(0, 1);
+        a.apply_all(send(from_b));
+        b.apply_all(send(from_a));
+
+        assert_eq!("big orld", as_string(&a));
+        assert_eq!(as_string(&a), as_string(&b));
+
+        let mut input = &[7u8][..];
+        assert_eq!(Err(DecodeError::Corrupt("unknown operation")), Op::<char>::decode(&mut input));
+    }
+
+    #[test]
+    fn markers() {
+        let newlines: Classifier<char, ()> =
+            Classifier::new(|&c: &char| if c == '\n' { Some(()) } else { None });
+
+        let mut a = Replica::with_classifier(1, &newlines);
+        let mut b = Replica::with_classifier(2, &newlines);
+
+        b.apply_all(a.insert(0, &chars("a\nb")));
+        a.apply_all(b.insert(3, &chars("\nc")));
+
+        assert_eq!(2, a.rope().marker_count(()));
+        assert_eq!(Some(3), a.rope().index_for_nth_marker((), 1));
+    }
+}
+
+mod transform {
+
+    use super::*;
+
+    /// A random changeset over a document of length `len`, inserting values
+    /// starting from `next`.
+    fn random_changes(rng: &mut Lcg, len: usize, next: &mut usize) -> ChangeSet<usize> {
+        let mut changes = ChangeSet::new();
+        let mut remaining = len;
+
+        while remaining > 0 || rng.below(4) == 0 {
+            match rng.below(3) {
+                0 => {
+                    let values: Vec<usize> = (0..rng.below(3) + 1).map(|i| *next + i).collect();
+                    *next += values.len();
+                    changes.insert(&values);
+
+                    if remaining == 0 {
+                        break;
+                    }
+                },
+                1 if remaining > 0 => {
+                    let n = rng.below(remaining) + 1;
+                    changes.delete(n);
+                    remaining -= n;
+                },
+                _ => {
+                    let n = rng.below(remaining + 1);
+                    changes.retain(n);
+                    remaining -= n;
+                },
+            }
+        }
+
+        changes
+    }
+
+    #[test]
+    fn concurrent_inserts() {
+        let rope: Rope<usize> = Rope::new(&[0, 1, 2]);
+        let a = ChangeSet::splice(3, 1, 1, &[10]);
+        let b = ChangeSet::splice(3, 1, 2, &[20]);
+
+        let (a_prime, b_prime) = a.transform(&b);
+        assert_eq!(vec![0, 10, 20, 2], as_vec(&b_prime.apply(&a.apply(&rope))));
+        assert_eq!(vec![0, 10, 20, 2], as_vec(&a_prime.apply(&b.apply(&rope))));
+    }
+
+    #[test]
+    fn randomized_convergence() {
+        let mut rng = Lcg(38);
+        let mut next = 100;
+
+        for _ in 0..200 {
+            let len = rng.below(20);
+            let data: Vec<usize> = (0..len).collect();
+            let rope: Rope<usize> = Rope::from_slice_with_leaf_size(&data, 4);
+
+            let a = random_changes(&mut rng, len, &mut next);
+            let b = random_changes(&mut rng, len, &mut next);
+            let (a_prime, b_prime) = a.transform(&b);
+
+            let a_then_b = b_prime.apply(&a.apply(&rope));
+            let b_then_a = a_prime.apply(&b.apply(&rope));
+            assert_eq!(as_vec(&a_then_b), as_vec(&b_then_a));
+
+            // composing either way gives the same changeset
+            assert_eq!(as_vec(&a.compose(&b_prime).apply(&rope)), as_vec(&a_then_b));
+            assert_eq!(as_vec(&b.compose(&a_prime).apply(&rope)), as_vec(&a_then_b));
+        }
+    }
+}
+
+mod snapshot {
+
+    use super::*;
+    use snapshot::{read_concat, write_concat_fields};
+
+    fn versions() -> Vec<Rope<char, char, usize>> {
+        let mut chunk = Chunk::with_capacity(8);
+        chunk.extend_from_slice(&['a', '(', 'b', ')']);
+        chunk.annotate(1, '(', 10);
+        chunk.annotate(3, ')', 11);
+
+        let leaf = Rope::from_chunk(chunk);
+        let mut rope = Rope::concat(&leaf, &leaf);
+        let mut versions = vec![rope.clone()];
+
+        for i in 0..20 {
+            rope = rope.insert(i % rope.len(), &['x', 'y']);
+            versions.push(rope.clone());
+        }
+
+        versions
+    }
+
+    #[test]
+    fn round_trip_keeps_sharing() {
+        let versions = versions();
+        let bytes = Rope::write_snapshot(&versions);
+        let back: Vec<Rope<char, char, usize>> = Rope::read_snapshot(&bytes).unwrap();
+
+        assert_eq!(versions.len(), back.len());
+        assert_eq!(node_count(&versions), node_count(&back));
+
+        for (rope, loaded) in versions.iter().zip(&back) {
+            assert_eq!(as_vec(rope), as_vec(loaded));
+            assert_eq!(rope.depth(), loaded.depth());
+            assert_eq!(rope.marker_counts(), loaded.marker_counts());
+            assert_eq!(rope.annotations('(', 0, rope.len()), loaded.annotations('(', 0, loaded.len()));
+        }
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        let mut bytes = Rope::write_snapshot(&versions());
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 1;
+
+        let result: Result<Vec<Rope<char, char, usize>>, _> = Rope::read_snapshot(&bytes);
+        assert_eq!(Some(DecodeError::BadChecksum), result.err());
+
+        bytes.truncate(middle);
+        let result: Result<Vec<Rope<char, char, usize>>, _> = Rope::read_snapshot(&bytes);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn marker_counts_are_checked() {
+        let marked = &versions()[0];
+        let (left, right) = match *marked.root {
+            Node::Concat { ref left, ref right, .. } => (left.clone(), right.clone()),
+            Node::Flat { .. } => unreachable!(),
+        };
+
+        let mut bytes = Vec::new();
+        write_concat_fields(&mut bytes, &marked.root);
+        assert!(read_concat(&mut &bytes[..], left.clone(), right).is_ok());
+
+        // same shape, but without the markers the counts claim
+        let plain: Rope<char, char, usize> = Rope::new(&['a', '(', 'b', ')']);
+        let result = read_concat(&mut &bytes[..], left, plain.root);
+        assert_eq!(Some(DecodeError::Corrupt("marker counts don't match the children")), result.err());
+    }
+
+    #[test]
+    fn headers_are_checked() {
+        let rope: Rope<usize> = Rope::new(&[1, 2, 3]);
+        let mut bytes = Rope::write_snapshot(&[rope]);
+        bytes[8] = 2;
+
+        let result: Result<Vec<Rope<usize>>, _> = Rope::read_snapshot(&bytes);
+        assert_eq!(Some(DecodeError::UnsupportedVersion(2)), result.err());
+
+        let result: Result<Vec<Rope<usize>>, _> = Rope::read_snapshot(b"not a snapshot");
+        assert_eq!(Some(DecodeError::BadMagic), result.err());
+    }
+}
+
+mod store {
+
+    use super::*;
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+
+    fn file_count(dir: &PathBuf) -> usize {
+        fs::read_dir(dir).unwrap().map(|entry| fs::read_dir(entry.unwrap().path()).unwrap().count()).sum()
+    }
+
+    #[test]
+    fn sha256() {
+        let hex = |bytes: &[u8]| codec::sha256(bytes).iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        let a = |n: usize| vec![b'a'; n];
+
+        assert_eq!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad", hex(b"abc"));
+        assert_eq!("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855", hex(b""));
+
+        // the most that fits in one block with the padding, and either side
+        // of a whole block
+        assert_eq!("9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318", hex(&a(55)));
+        assert_eq!("b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a", hex(&a(56)));
+        assert_eq!("ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb", hex(&a(64)));
+
+        assert_eq!("cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1",
+                   hex(b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu"));
+        assert_eq!("41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3", hex(&a(1000)));
+    }
+
+    #[test]
+    fn saves_only_new_nodes() {
+        let dir = temp_path("store-new-nodes");
+        let mut store = NodeStore::open(&dir).unwrap();
+
+        let mut chunk = Chunk::with_capacity(4);
+        chunk.extend_from_slice(&['a', '(', 'b', ')']);
+        chunk.annotate(1, '(', 10);
+
+        let leaf = Rope::from_chunk(chunk);
+        let mut rope: Rope<char, char, usize> = Rope::concat(&leaf, &leaf);
+        for _ in 0..6 {
+            rope = Rope::concat(&rope, &rope);
+        }
+
+        store.save(&rope).unwrap();
+        let before = file_count(&dir);
+
+        let edited = rope.insert(100, &['x']);
+        let hash = store.save(&edited).unwrap();
+        assert!(file_count(&dir) - before <= 2 * edited.depth() + 2);
+
+        let mut reopened: NodeStore<char, char, usize> = NodeStore::open(&dir).unwrap();
+        let loaded = reopened.load(&hash).unwrap();
+        assert_eq!(as_vec(&edited), as_vec(&loaded));
+        assert_eq!(edited.depth(), loaded.depth());
+        assert_eq!(edited.marker_counts(), loaded.marker_counts());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loads_share_nodes() {
+        let dir = temp_path("store-share");
+        let mut store = NodeStore::open(&dir).unwrap();
+
+        let mut versions: Vec<Rope<usize>> = vec![Rope::new(&[0])];
+        let mut hashes = Vec::new();
+        for i in 1..50 {
+            let next = versions[i - 1].insert(i / 2, &[i]);
+            hashes.push(store.save(&next).unwrap());
+            versions.push(next);
+        }
+
+        let hash: NodeHash = hashes[48].to_string().parse().unwrap();
+        assert_eq!(hashes[48], hash);
+
+        let mut reopened: NodeStore<usize> = NodeStore::open(&dir).unwrap();
+        let loaded = vec![reopened.load(&hashes[47]).unwrap(), reopened.load(&hashes[48]).unwrap()];
+
+        assert_eq!(versions[49].iter().collect::<Vec<_>>(), loaded[1].iter().collect::<Vec<_>>());
+        assert_eq!(node_count(&versions[48..]), node_count(&loaded));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lazy_loading() {
+        let dir = temp_path("store-lazy");
+        let mut store = NodeStore::open(&dir).unwrap();
+
+        let data: Vec<usize> = (0..1000).collect();
+        let rope: Rope<usize> = Rope::from_slice_with_leaf_size(&data, 8);
+        let hash = store.save(&rope).unwrap();
+
+        let lazy = store.load_lazy(&hash).unwrap();
+        assert_eq!(1000, lazy.len());
+        assert_eq!(Some(&10), lazy.get(10).unwrap());
+        assert_eq!(None, lazy.get(1000).unwrap());
+        assert_eq!((20..40).collect::<Vec<_>>(), as_vec(&lazy.slice(20, 40).unwrap()));
+
+        // with the files gone, only what's been read already can be used
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(Some(&11), lazy.get(11).unwrap());
+        assert_eq!((25..35).collect::<Vec<_>>(), as_vec(&lazy.slice(25, 35).unwrap()));
+        assert!(lazy.get(900).is_err());
+        assert!(lazy.rope().is_err());
+    }
+
+    #[test]
+    fn lazy_rope() {
+        let dir = temp_path("store-lazy-rope");
+        let mut store = NodeStore::open(&dir).unwrap();
+
+        let mut chunk = Chunk::with_capacity(4);
+        chunk.extend_from_slice(&['a', '(', 'b', ')']);
+        chunk.annotate(1, '(', 10);
+
+        let leaf = Rope::from_chunk(chunk);
+        let rope: Rope<char, char, usize> = Rope::concat(&leaf, &leaf.insert(2, &['x']));
+        let hash = store.save(&rope).unwrap();
+
+        let lazy = store.load_lazy(&hash).unwrap();
+        let loaded = lazy.rope().unwrap();
+        assert_eq!(as_vec(&rope), as_vec(&loaded));
+        assert_eq!(rope.depth(), loaded.depth());
+        assert_eq!(Some((4, &10)), lazy.slice(1, 9).unwrap().nth_annotation('(', 1));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_nodes_are_rejected() {
+        let dir = temp_path("store-corrupt");
+        let mut store = NodeStore::open(&dir).unwrap();
+
+        let rope: Rope<usize> = Rope::new(&[1, 2, 3]);
+        let hash = store.save(&rope).unwrap();
+        let hex = hash.to_string();
+        fs::write(dir.join(&hex[..2]).join(&hex[2..]), b"\x00\x01\x07").unwrap();
+
+        let mut reopened: NodeStore<usize> = NodeStore::open(&dir).unwrap();
+        let error = reopened.load(&hash).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde {
+
+    use super::*;
+    use serde_json;
+
+    fn marked_rope() -> Rope<char, char, usize> {
+        let mut chunk = Chunk::with_capacity(8);
+        chunk.extend_from_slice(&['a', '(', 'b', ')']);
+        chunk.annotate(1, '(', 10);
+        chunk.annotate(3, ')', 11);
+
+        let rope = Rope::from_chunk(chunk);
+        Rope::concat(&rope, &rope)
+    }
+
+    #[test]
+    fn rope_round_trip() {
+        let rope = marked_rope();
+        let json = serde_json::to_string(&rope).unwrap();
+        let back: Rope<char, char, usize> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(as_vec(&rope), as_vec(&back));
+        assert_eq!(Some((5, &10)), back.nth_annotation('(', 1));
+        assert_eq!(vec![(3, &11), (7, &11)], back.annotations(')', 0, 8));
+    }
+
+    #[test]
+    fn chunk_round_trip() {
+        let mut chunk: Chunk<usize, (), ()> = Chunk::with_capacity(4);
+        chunk.extend_from_slice(&[1, 2, 3]);
+        chunk.mark_at((), 2);
+
+        let json = serde_json::to_string(&chunk).unwrap();
+        assert_eq!("{\"values\":[1,2,3],\"markers\":[[null,[[2,null]]]]}", json);
+
+        let back: Chunk<usize, (), ()> = serde_json::from_str(&json).unwrap();
+        let rope = Rope::from_chunk(back);
+        assert_eq!(Some(2), rope.index_for_nth_marker((), 0));
+    }
+
+    #[test]
+    fn sorted_markers() {
+        let json = serde_json::to_string(&marked_rope()).unwrap();
+        assert_eq!("{\"values\":[\"a\",\"(\",\"b\",\")\",\"a\",\"(\",\"b\",\")\"],\
+                    \"markers\":[[\"(\",[[1,10],[5,10]]],[\")\",[[3,11],[7,11]]]]}", json);
+    }
+
+    #[test]
+    fn bad_marker_index() {
+        let json = "{\"values\":[1],\"markers\":[[null,[[5,null]]]]}";
+        assert!(serde_json::from_str::<Rope<usize>>(json).is_err());
+    }
+}
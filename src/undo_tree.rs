@@ -0,0 +1,232 @@
+//!
+//! Undo trees over `Rope` versions.
+//!
+//! Unlike `History`, undoing and then making a new edit doesn't throw away
+//! the undone steps: the edit just starts a new branch. Every revision keeps
+//! its `Rope`, so moving around the tree (or through time, as with Vim's
+//! `:earlier` and `:later`) only switches between versions that share most of
+//! their structure.
+//!
+
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use super::{ChangeSet, Rope};
+
+/// Identifies a revision in an `UndoTree`. Revisions are numbered in the
+/// order they were made, starting with the original document at 0.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct RevisionId(usize);
+
+/// A leaf of an `UndoTree`, as listed by `UndoTree::branches`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Branch {
+    pub leaf: RevisionId,
+
+    /// The number of edits between the original document and `leaf`.
+    pub depth: usize,
+    pub time: Instant,
+}
+
+struct Revision<T, M, V> {
+    rope: Rope<T, M, V>,
+    parent: Option<RevisionId>,
+    children: Vec<RevisionId>,
+
+    // the child `redo` goes to: the one we last came back from
+    last_child: Option<RevisionId>,
+
+    depth: usize,
+    time: Instant,
+}
+
+pub struct UndoTree<T, M = (), V = ()> {
+    revisions: Vec<Revision<T, M, V>>,
+
+    // every revision from the root down to `current` has its `last_child`
+    // on the way to `current`, so stepping one revision away only has to
+    // fix up one `last_child`
+    current: RevisionId,
+}
+
+impl<T: Clone, M: Eq + Hash + Copy, V: Clone> UndoTree<T, M, V> {
+
+    pub fn new(rope: Rope<T, M, V>) -> Self {
+        Self::new_at(rope, Instant::now())
+    }
+
+    /// Like `new`, but as if the original document was loaded at `time`.
+    pub fn new_at(rope: Rope<T, M, V>, time: Instant) -> Self {
+        let root = Revision {
+            rope,
+            parent: None,
+            children: Vec::new(),
+            last_child: None,
+            depth: 0,
+            time,
+        };
+
+        UndoTree {
+            revisions: vec![root],
+            current: RevisionId(0),
+        }
+    }
+
+    fn revision(&self, id: RevisionId) -> &Revision<T, M, V> {
+        &self.revisions[id.0]
+    }
+
+    /// The current version of the document.
+    pub fn rope(&self) -> &Rope<T, M, V> {
+        &self.revision(self.current).rope
+    }
+
+    pub fn current(&self) -> RevisionId {
+        self.current
+    }
+
+    pub fn revision_count(&self) -> usize {
+        self.revisions.len()
+    }
+
+    pub fn rope_at(&self, id: RevisionId) -> &Rope<T, M, V> {
+        &self.revision(id).rope
+    }
+
+    pub fn time(&self, id: RevisionId) -> Instant {
+        self.revision(id).time
+    }
+
+    pub fn parent(&self, id: RevisionId) -> Option<RevisionId> {
+        self.revision(id).parent
+    }
+
+    /// The revisions made from `id`, oldest first.
+    pub fn children(&self, id: RevisionId) -> &[RevisionId] {
+        &self.revision(id).children
+    }
+
+    /// Apply `changes` to the current version as a new child of the current
+    /// revision, and make it current.
+    pub fn edit(&mut self, changes: &ChangeSet<T>) -> RevisionId {
+        self.edit_at(changes, Instant::now())
+    }
+
+    /// Like `edit`, but as if the edit happened at `time`.
+    pub fn edit_at(&mut self, changes: &ChangeSet<T>, time: Instant) -> RevisionId {
+        let id = RevisionId(self.revisions.len());
+        let parent = self.current;
+
+        let revision = Revision {
+            rope: changes.apply(self.rope()),
+            parent: Some(parent),
+            children: Vec::new(),
+            last_child: None,
+            depth: self.revision(parent).depth + 1,
+            time,
+        };
+
+        self.revisions.push(revision);
+        self.revisions[parent.0].children.push(id);
+        self.step_down(id);
+
+        id
+    }
+
+    /// Go to the parent of the current revision, returning false if we're
+    /// already at the original document.
+    pub fn undo(&mut self) -> bool {
+        match self.parent(self.current) {
+            Some(parent) => {
+                self.current = parent;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Go to the child of the current revision we were last at (or else the
+    /// newest one), returning false if it has no children.
+    pub fn redo(&mut self) -> bool {
+        let revision = self.revision(self.current);
+
+        match revision.last_child.or_else(|| revision.children.last().cloned()) {
+            Some(child) => {
+                self.step_down(child);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Make `child`, a child of the current revision, current.
+    fn step_down(&mut self, child: RevisionId) {
+        self.revisions[self.current.0].last_child = Some(child);
+        self.current = child;
+    }
+
+    /// Make `id` the current revision. `redo` from any revision between it
+    /// and the original document will then lead back towards `id`.
+    pub fn goto(&mut self, id: RevisionId) {
+        let mut child = id;
+
+        while let Some(parent) = self.parent(child) {
+            self.revisions[parent.0].last_child = Some(child);
+            child = parent;
+        }
+
+        self.current = id;
+    }
+
+    /// Go to the last revision made at least `by` before the current one, as
+    /// Vim's `:earlier` does, or to the original document if there's none.
+    pub fn earlier(&mut self, by: Duration) -> RevisionId {
+        let now = self.time(self.current);
+
+        let target = match now.checked_sub(by) {
+            Some(time) => self.last_made_by(time).unwrap_or(RevisionId(0)),
+            None => RevisionId(0),
+        };
+
+        self.goto(target);
+        target
+    }
+
+    /// Go to the last revision made at most `by` after the current one, as
+    /// Vim's `:later` does.
+    pub fn later(&mut self, by: Duration) -> RevisionId {
+        let now = self.time(self.current);
+
+        let target = match now.checked_add(by) {
+            Some(time) => self.last_made_by(time).unwrap_or(self.current),
+            None => RevisionId(self.revisions.len() - 1),
+        };
+
+        // revisions made at the same time as the current one are "later"
+        let target = if target < self.current { self.current } else { target };
+
+        self.goto(target);
+        target
+    }
+
+    fn last_made_by(&self, time: Instant) -> Option<RevisionId> {
+        self.revisions.iter()
+                      .rposition(|revision| revision.time <= time)
+                      .map(RevisionId)
+    }
+
+    /// Every leaf of the tree (the tips of its branches), oldest first.
+    pub fn branches(&self) -> Vec<Branch> {
+        self.revisions.iter()
+                      .enumerate()
+                      .filter(|&(_, revision)| revision.children.is_empty())
+                      .map(|(i, revision)| {
+                          Branch {
+                              leaf: RevisionId(i),
+                              depth: revision.depth,
+                              time: revision.time,
+                          }
+                      })
+                      .collect()
+    }
+}